@@ -49,8 +49,10 @@ impl ToTokens for Receiver {
 
         match stype.as_str() {
           "Option < String >" => quote!{ .as_str().map(|s| s.to_string() ) },
+          "String" => quote!{ .as_str().unwrap().to_string() },
           "u8"|"u16"|"u32" => quote!{ .as_integer().unwrap() as #ftype },
           "u64" => quote!{ .as_integer().unwrap() },
+          "f64" => quote!{ .as_float().unwrap() },
           "bool" => quote!{ .as_bool().unwrap() },
           _ => { quote!{ } }
         }
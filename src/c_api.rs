@@ -7,17 +7,22 @@ use self::libc::{size_t, calloc};
 use scale;
 use scale::PileUp;
 use seqtable;
-use seqtable::SeqTableParams;
+use seqtable::{SeqTableParams, SeqTable, KmerCodec};
 use tallyrun;
 use fasta;
 use counts;
 use super::file_exists;
 use std::slice;
+use std::fs::File;
 use std::ffi::CStr;
 use std::ptr;
 use std::mem;
 use std::ops::Deref;
-use filter::PairPosition;
+use filter::{FlagFilter, DEFAULT_EXCLUDE_FLAGS, TN5_SHIFT};
+use split::ReadSplit;
+use dedup::UmiSource;
+use source::ReadOptions;
+use chroms::ChromAliases;
 
 #[repr(C)]
 pub struct SeqTblParams(SeqTableParams);
@@ -35,8 +40,11 @@ impl Deref for SeqTblParams {
 /// Valid mask characters:
 /// - 'N' - unmasked position
 /// - 'X' - masked position
+/// - 'R'/'Y', 'S'/'W', 'K'/'M' - position reduced to a base class (purine/pyrimidine, strong/weak, keto/amino)
 /// - 'C' - cut position
+/// - 'c' - minus strand cut position, when the mask also has a plus strand cut position 'C'
 /// plus_offset and minus_offset params are ignored if kmer_mask contains a cut mark 'C'.
+/// Returns NULL for separate plus and minus cut positions when strand_specific is set.
 /// Free memory using seqoutbias_free_params().
 /// Returns NULL if the mask is invalid.
 #[no_mangle]
@@ -47,7 +55,8 @@ pub extern fn seqoutbias_params_with_mask(kmer_mask: *const libc::c_char, plus_o
   };
 
   // validate mask
-  if seqtable::SeqTableParams::validate_mask(&kmer_mask).is_err() {
+  if seqtable::SeqTableParams::validate_mask(&kmer_mask).is_err() ||
+     (strand_specific && seqtable::SeqTableParams::has_staggered_cut(&kmer_mask)) {
     return ptr::null_mut();
   }
 
@@ -105,7 +114,7 @@ pub struct PileUpPoint {
 
 #[repr(C)]
 pub struct Config {
-  // add here the flags
+  // add here the flags, new fields go at the end to keep the layout of existing fields
   /// Minimum read quality
   min_qual: u8,
   // regions
@@ -125,14 +134,30 @@ pub struct Config {
   shift_counts: bool,
   /// Apply a custom shift to each strand
   custom_shift: bool,
-  /// Custom shift amount for plus strand
+  /// Custom shift amount for plus strand (in fragment mode: shift applied before k-mer lookup, defaults to Tn5 +4/-5)
   custom_shift_plus: i32,
   /// Custom shift amount for minus strand
   custom_shift_minus: i32,
   /// If false, pileUp represents unscaled counts
   scale_pileup: bool,
   /// 0 - both, -1 - select first, 1 - select last in pair; only affects pile-ups
-  select_pair: i8
+  select_pair: i8,
+  /// Only accept reads with all of these SAM flags set
+  require_flags: u16,
+  /// Only accept reads with none of these SAM flags set
+  exclude_flags: u16,
+  /// Skip reads whose counted edge is soft-clipped
+  skip_soft_clipped: bool,
+  /// Count both (shifted) insertion sites of properly paired fragments, eg. ATAC-seq
+  fragment_mode: bool,
+  /// Count reads sharing the same 5' position, strand and UMI only once
+  dedup_umi: bool,
+  /// SAM tag holding the UMI
+  umi_tag: [u8; 2],
+  /// Take the UMI from the read name (after the last '_' or ':') instead of the SAM tag
+  umi_from_name: bool,
+  /// UMIs differing at a single base are considered the same
+  umi_mismatch: bool
 }
 
 /// Create a Config structure filled with the same default values as used in the seqOutBias program.
@@ -152,7 +177,15 @@ pub extern fn seqoutbias_default_config() -> Config {
     custom_shift_plus: 0,
     custom_shift_minus: 0,
     scale_pileup: true,
-    select_pair: 0
+    select_pair: 0,
+    require_flags: 0,
+    exclude_flags: DEFAULT_EXCLUDE_FLAGS,
+    skip_soft_clipped: false,
+    fragment_mode: false,
+    dedup_umi: false,
+    umi_tag: *b"RX",
+    umi_from_name: false,
+    umi_mismatch: false
   }
 }
 
@@ -189,7 +222,7 @@ pub extern fn seqoutbias_generate_seqtbl(fasta_filename: *const libc::c_char, pa
   let path = tallyrun::tallymer_createfile(&fasta_filename, params.read_length, parts, None);
 
   // generate data
-  fasta::process_fasta(&fasta_filename, &path, &params, &outfile);
+  fasta::process_fasta(&fasta_filename, &path, &params, fasta::SoftMask::Ignore, &outfile);
 
   return 0;
 }
@@ -217,18 +250,7 @@ pub extern fn seqoutbias_create_pileup(seqtable_filename: *const libc::c_char, b
   } else {
     None
   };
-
-  // collect counts
-  let counts = counts::tabulate(
-    &seqtable_filename, 
-    Some(&bams), 
-    config.min_qual, 
-    regions, 
-    dist_range, 
-    config.only_paired,
-    config.exact_length,
-    config.tail_edge
-  );
+  let flags = FlagFilter { require: config.require_flags, exclude: config.exclude_flags };
 
   // Custom shift amount
   let custom_shift = if config.custom_shift {
@@ -236,26 +258,52 @@ pub extern fn seqoutbias_create_pileup(seqtable_filename: *const libc::c_char, b
   } else {
     None
   };
+  let fragment_shift = if config.fragment_mode {
+    Some(custom_shift.unwrap_or(TN5_SHIFT))
+  } else {
+    None
+  };
+
+  // pile-up for a single pair end is selected from the split by pair end
+  let mut split = if config.select_pair != 0 { ReadSplit::PairEnd } else { ReadSplit::None };
+
+  let source = if config.umi_from_name { UmiSource::ReadName } else { UmiSource::Tag(config.umi_tag) };
+  let options = ReadOptions {
+    min_quality: config.min_qual,
+    flags: flags,
+    pair_range: dist_range,
+    only_paired: config.only_paired,
+    exact_length: config.exact_length,
+    tail_edge: config.tail_edge,
+    skip_soft_clipped: config.skip_soft_clipped,
+    fragment_shift: fragment_shift,
+    dedup_umi: if config.dedup_umi { Some((source, config.umi_mismatch)) } else { None },
+    reference: None,
+    chroms: ChromAliases::default(),
+    allow_length_mismatch: false,
+  };
+
+  // collect counts
+  let counts = counts::tabulate(
+    &seqtable_filename, 
+    Some(&bams), 
+    regions, 
+    &options,
+    &mut split
+  );
 
   // compute pileup
-  let pileup = scale::scale(
+  let mut pileups = scale::scale(
     &seqtable_filename, 
     &counts,
     &bams, 
-    config.min_qual,
+    &options,
     config.shift_counts,
     &custom_shift,
     !config.scale_pileup,
-    &dist_range,
-    config.only_paired,
-    config.exact_length,
-    config.tail_edge,
-    match config.select_pair {
-      -1 => { Some(PairPosition::First) },
-      1 => { Some(PairPosition::Last) },
-      _ => { None }
-    }
+    &mut split
   );
+  let pileup = if config.select_pair == 1 { pileups.swap_remove(1) } else { pileups.swap_remove(0) };
 
   Box::into_raw(Box::new(PileUpData(pileup)))
 }
@@ -365,3 +413,144 @@ pub extern fn seqoutbias_query_pileup(ptr: *mut PileUpData, chrom_index: size_t,
     None => 0,
   }
 }
+
+#[repr(C)]
+pub struct KmerCodecData(KmerCodec);
+
+impl Deref for KmerCodecData {
+    type Target = KmerCodec;
+    fn deref(&self) -> &KmerCodec {
+        let KmerCodecData(ref inner) = *self;
+        inner
+    }
+}
+
+/// Create a k-mer codec, converting between seqtbl k-mer indexes and k-mer strings for the given parameters.
+/// Free memory using seqoutbias_free_kmer_codec().
+#[no_mangle]
+pub extern fn seqoutbias_kmer_codec(params: *mut SeqTblParams) -> *mut KmerCodecData {
+  let params = unsafe {
+    assert!(!params.is_null());
+    &*params
+  };
+
+  Box::into_raw(Box::new(KmerCodecData(KmerCodec::new(params))))
+}
+
+/// Create a k-mer codec for the parameters of an existing seqtbl file.
+/// Free memory using seqoutbias_free_kmer_codec().
+/// Returns NULL if the file can't be read.
+#[no_mangle]
+pub extern fn seqoutbias_kmer_codec_from_seqtbl(seqtable_filename: *const libc::c_char) -> *mut KmerCodecData {
+  let seqtable_filename = unsafe {
+    assert!(!seqtable_filename.is_null());
+    CStr::from_ptr(seqtable_filename).to_string_lossy().into_owned()
+  };
+
+  let table = match File::open(&seqtable_filename).map(SeqTable::open) {
+    Ok(Ok(table)) => table,
+    _ => return ptr::null_mut(),
+  };
+
+  Box::into_raw(Box::new(KmerCodecData(KmerCodec::new(table.params()))))
+}
+
+/// Free memory used by k-mer codec
+#[no_mangle]
+pub extern fn seqoutbias_free_kmer_codec(ptr: *mut KmerCodecData) {
+  if ptr.is_null() { return }
+  unsafe { Box::from_raw(ptr); }
+}
+
+/// Number of k-mer indexes, including index 0 (unmappable positions). Valid k-mer indexes range from 1 to count - 1.
+#[no_mangle]
+pub extern fn seqoutbias_kmer_count(ptr: *mut KmerCodecData) -> u64 {
+  let codec = unsafe {
+    assert!(!ptr.is_null());
+    &*ptr
+  };
+
+  codec.nmer_count()
+}
+
+/// Translate k-mer index into a NUL terminated k-mer string. Masked (X) positions are returned as 'N' and
+/// base class positions as their IUPAC letter.
+/// Caller must free memory using 'free()'.
+/// Returns NULL if the index is out of range.
+#[no_mangle]
+pub extern fn seqoutbias_kmer_decode(ptr: *mut KmerCodecData, index: u64) -> *mut libc::c_char {
+  let codec = unsafe {
+    assert!(!ptr.is_null());
+    &*ptr
+  };
+
+  let kmer = match codec.decode(index) {
+    Ok(kmer) => kmer,
+    Err(_) => return ptr::null_mut(),
+  };
+
+  // allocate memory with C calloc, zero filled so the string is terminated
+  let result: *mut libc::c_char = unsafe {
+    calloc(kmer.len() + 1, mem::size_of::<libc::c_char>()) as *mut libc::c_char
+  };
+
+  let rslice = unsafe {
+    slice::from_raw_parts_mut(result as *mut u8, kmer.len())
+  };
+  rslice.copy_from_slice(kmer.as_bytes());
+
+  result
+}
+
+/// Obtain the k-mer index (returned via 'out_index') of a k-mer string. Any character is accepted at
+/// masked (X) positions, base class positions accept a base or one of the class letters.
+/// Returns 1 if the k-mer is valid, 0 otherwise.
+#[no_mangle]
+pub extern fn seqoutbias_kmer_encode(ptr: *mut KmerCodecData, kmer: *const libc::c_char, out_index: *mut u64) -> i32 {
+  let codec = unsafe {
+    assert!(!ptr.is_null());
+    &*ptr
+  };
+
+  let kmer = unsafe {
+    assert!(!kmer.is_null());
+    CStr::from_ptr(kmer).to_string_lossy()
+  };
+
+  match codec.encode(&kmer) {
+    Ok(value) => {
+      unsafe {
+        assert!(!out_index.is_null());
+        *out_index = value;
+      }
+      1
+    },
+    Err(_) => 0,
+  }
+}
+
+/// Obtain the index of the reverse complement k-mer (returned via 'out_index'), as used on the minus strand of
+/// strand specific seqtbl files.
+/// Returns 1 on success, 0 if the index is out of range or the reverse complement is unknown because the mask
+/// is not symmetric.
+#[no_mangle]
+pub extern fn seqoutbias_kmer_revcomp(ptr: *mut KmerCodecData, index: u64, out_index: *mut u64) -> i32 {
+  let codec = unsafe {
+    assert!(!ptr.is_null());
+    &*ptr
+  };
+
+  if index == 0 || index >= codec.nmer_count() {
+    return 0;
+  }
+  match codec.revcomp(index) {
+    Some(value) => {
+      unsafe {
+        assert!(!out_index.is_null());
+        *out_index = value;
+      }
+      1
+    },
+    None => 0,
+  }
+}
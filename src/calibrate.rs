@@ -0,0 +1,255 @@
+//!
+//!	Empirical calibration of the cut-site k-mer: position specific base frequencies around read
+//! 5' ends compared to the genome background.
+//!
+use std::ops::Range;
+use fasta;
+use fasta::{DNABases, GenomeSequences};
+use seqtable::{SeqTableParams, SequenceInfo};
+use source;
+use source::{ReadOptions, skip_sequence};
+use split::ReadSplit;
+use std::process::exit;
+
+/// Base counts around read 5' ends, in read orientation
+pub struct Calibration {
+    window: i32,
+    // counts per offset, from -window to window - 1 (offset 0 is the first read base)
+    counts: Vec<[u64; 4]>,
+    // genome base counts, both strands
+    background: [u64; 4],
+    reads: u64,
+}
+
+impl Calibration {
+    fn new(window: i32) -> Calibration {
+        Calibration { window: window, counts: vec![[0; 4]; 2 * window as usize], background: [0; 4], reads: 0 }
+    }
+
+    fn add_background(&mut self, bases: &Vec<u8>) {
+        for &base in bases {
+            if base < 4 {
+                self.background[base as usize] += 1;
+                self.background[3 - base as usize] += 1;
+            }
+        }
+    }
+
+    // add bases around the 5' end at `pos`, reverse reads are read on the minus strand
+    fn add_site(&mut self, bases: &Vec<u8>, pos: i32, reverse: bool) {
+        let len = bases.len() as i32;
+        for offset in self.offsets() {
+            let gpos = if reverse { pos - offset } else { pos + offset };
+            if gpos < 0 || gpos >= len {
+                continue;
+            }
+            let base = bases[gpos as usize];
+            if base < 4 {
+                let base = if reverse { 3 - base } else { base };
+                self.counts[(offset + self.window) as usize][base as usize] += 1;
+            }
+        }
+        self.reads += 1;
+    }
+
+    /// Offsets relative to the read 5' end, the cut-site is between -1 and 0
+    pub fn offsets(&self) -> Range<i32> {
+        -self.window..self.window
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.reads
+    }
+
+    /// Base (A, C, G, T) frequencies at `offset`
+    pub fn frequencies(&self, offset: i32) -> [f64; 4] {
+        frequencies(&self.counts[(offset + self.window) as usize])
+    }
+
+    /// Information content (bits) of the bases at `offset` relative to the genome background
+    pub fn information(&self, offset: i32) -> f64 {
+        let freqs = self.frequencies(offset);
+        let background = frequencies(&self.background);
+        (0..4).filter(|&b| freqs[b] > 0f64 && background[b] > 0f64)
+            .map(|b| freqs[b] * (freqs[b] / background[b]).log2())
+            .sum()
+    }
+
+    /// k-mer mask including the offsets with at least `min_info` bits, None if there are none
+    pub fn recommended_mask(&self, min_info: f64) -> Option<String> {
+        let informative: Vec<i32> = self.offsets().filter(|&offset| self.information(offset) >= min_info).collect();
+        if informative.is_empty() {
+            return None;
+        }
+        // the mask must span the cut-site
+        let first = *informative.first().unwrap().min(&0);
+        let last = *informative.last().unwrap().max(&-1);
+        let mut mask = String::new();
+        for offset in first..(last + 1) {
+            if offset == 0 {
+                mask.push('C');
+            }
+            mask.push(if informative.contains(&offset) { 'N' } else { 'X' });
+        }
+        if last == -1 {
+            mask.push('C');
+        }
+        Some(mask)
+    }
+
+    pub fn print(&self, min_info: f64, read_length: u16) {
+        println!("# reads: {}", self.reads);
+        println!("# background: {}", frequencies(&self.background).iter().map(|f| format!("{:.4}", f)).collect::<Vec<String>>().join("\t"));
+        println!("# offset\tA\tC\tG\tT\tinfo");
+        for offset in self.offsets() {
+            let freqs = self.frequencies(offset);
+            println!("{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}", offset, freqs[0], freqs[1], freqs[2], freqs[3], self.information(offset));
+        }
+        match self.recommended_mask(min_info) {
+            Some(mask) => {
+                let params = SeqTableParams::new(0, 0, 0, read_length, &Some(mask.clone()), false);
+                println!("# recommended kmer-mask: {}", mask);
+                println!("# kmer-size: {} plus-offset: {} minus-offset: {}", params.kmer_length, params.plus_offset, params.minus_offset);
+                if let Err(err) = SeqTableParams::validate_mask(&mask) {
+                    println!("WARN: {}", err);
+                }
+            },
+            None => println!("# no offset has at least {} bits of information, no recommended kmer-mask", min_info),
+        }
+    }
+}
+
+fn frequencies(counts: &[u64; 4]) -> [f64; 4] {
+    let total: u64 = counts.iter().sum();
+    let mut freqs = [0f64; 4];
+    if total > 0 {
+        for b in 0..4 {
+            freqs[b] = counts[b] as f64 / total as f64;
+        }
+    }
+    freqs
+}
+
+// bases of sequence `name`, encoded as `DNABases`
+fn fetch_bases(genome: &mut GenomeSequences, name: &str) -> Vec<u8> {
+    match genome.fetch(name) {
+        Ok(bases) => bases.into_iter().map(|byte| match byte {
+            b'a' | b'A' => DNABases::A as u8,
+            b'c' | b'C' => DNABases::C as u8,
+            b'g' | b'G' => DNABases::G as u8,
+            b't' | b'T' => DNABases::T as u8,
+            _ => DNABases::N as u8,
+        }).collect(),
+        Err(err) => {
+            println!("Error: {}", err);
+            exit(1);
+        },
+    }
+}
+
+/// Base frequencies within `window` bp of the read 5' ends in the input files
+///
+/// Sequences are read one at a time through the FASTA index (or from the .2bit file), as the reads
+/// on each sequence are reached. The genome background is counted in the same pass.
+pub fn calibrate(fasta_file: &str, inputs: &Vec<String>, read_length: u16, options: &ReadOptions, window: u32) -> Calibration {
+    let mut genome = fasta::open_genome_sequences(fasta_file);
+    let seqinfos: Vec<SequenceInfo> = genome.sequence_names().into_iter().map(|name| {
+        match genome.sequence_length(&name) {
+            Ok(length) => SequenceInfo { name: name, length: length },
+            Err(err) => {
+                println!("Error: {}", err);
+                exit(1);
+            },
+        }
+    }).collect();
+    let mut calibration = Calibration::new(window as i32);
+    let mut counted = vec![false; seqinfos.len()];
+
+    let mut chroms = options.chroms.resolver(&seqinfos);
+    let mut split = ReadSplit::None;
+    for filename in inputs {
+        println!("# calibrate {}", filename);
+        let mut source = source::open(filename, read_length as usize, options);
+        chroms.check_lengths(&source.declared_sequences(), options.allow_length_mismatch);
+
+        let mut pending = source.next_event(&mut split);
+        while let Some(first) = pending {
+            let chrom = first.chrom;
+            let idx = match chroms.sequence_index(source.chrom_name(chrom)) {
+                Some(idx) => idx,
+                None => {
+                    pending = skip_sequence(&mut *source, &mut split, &mut chroms, first);
+                    continue;
+                },
+            };
+            let bases = fetch_bases(&mut *genome, &seqinfos[idx].name);
+            if !counted[idx] {
+                calibration.add_background(&bases);
+                counted[idx] = true;
+            }
+            let mut event = first;
+            pending = loop {
+                for site in event.sites {
+                    calibration.add_site(&bases, site.pos, site.reverse);
+                }
+                event = match source.next_event(&mut split) {
+                    Some(next) => if next.chrom == chrom { next } else { break Some(next) },
+                    None => break None,
+                };
+            };
+        }
+    }
+    chroms.report();
+
+    // background of the sequences without reads
+    for (idx, sinfo) in seqinfos.iter().enumerate() {
+        if !counted[idx] {
+            calibration.add_background(&fetch_bases(&mut *genome, &sinfo.name));
+        }
+    }
+    calibration
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn information_at_read_ends() {
+        let mut calibration = Calibration::new(2);
+        // ACGTACGTAC, reads starting at G (forward) and ending at C (reverse, G on the minus strand)
+        let bases = vec![0, 1, 2, 3, 0, 1, 2, 3, 0, 1];
+        calibration.add_background(&bases);
+        calibration.add_site(&bases, 2, false);
+        calibration.add_site(&bases, 6, false);
+        calibration.add_site(&bases, 5, true);
+
+        assert_eq!(3, calibration.reads());
+        assert_eq!([0f64, 0f64, 1f64, 0f64], calibration.frequencies(0));
+        // minus strand read: T at offset -2 becomes A
+        assert_eq!([1f64, 0f64, 0f64, 0f64], calibration.frequencies(-2));
+        // uniform background, a fixed base has 2 bits of information
+        assert_eq!(2f64, calibration.information(0));
+        assert_eq!(Some("NNCNN".to_string()), calibration.recommended_mask(1.5));
+        assert_eq!(None, calibration.recommended_mask(2.5));
+    }
+
+    #[test]
+    fn recommended_mask_spans_cut_site() {
+        let mut calibration = Calibration::new(3);
+        calibration.background = [1, 1, 1, 1];
+        for counts in calibration.counts.iter_mut() {
+            *counts = [5, 5, 5, 5];
+        }
+        // informative positions at -3 and 1
+        calibration.counts[0] = [10, 0, 0, 10];
+        calibration.counts[4] = [20, 0, 0, 0];
+        assert_eq!(1f64, calibration.information(-3));
+        assert_eq!(0f64, calibration.information(-1));
+        assert_eq!(Some("NXXCXN".to_string()), calibration.recommended_mask(0.5));
+
+        // only downstream of the cut-site
+        calibration.counts[0] = [5, 5, 5, 5];
+        assert_eq!(Some("CXN".to_string()), calibration.recommended_mask(0.5));
+    }
+}
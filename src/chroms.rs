@@ -0,0 +1,198 @@
+//!
+//!	Matching of sequence names between read files, region files and the sequence table
+//! (e.g. `chr1` vs `1`).
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+use std::process::exit;
+use seqtable::SequenceInfo;
+
+/// Sequence name aliases and handling of names missing from the sequence table
+#[derive(Clone, Debug, Default)]
+pub struct ChromAliases {
+    groups: Vec<Vec<String>>,
+    /// Skip reads on unknown sequences instead of failing
+    pub skip_unknown: bool,
+}
+
+impl ChromAliases {
+    /// No aliases, only matching names and `chr` prefixes
+    pub fn new(skip_unknown: bool) -> ChromAliases {
+        ChromAliases { groups: Vec::new(), skip_unknown: skip_unknown }
+    }
+
+    /// Read alias file, each line lists alternative names of the same sequence (whitespace separated)
+    pub fn from_file(filename: &str, skip_unknown: bool) -> Result<ChromAliases> {
+        let file = try!(File::open(filename));
+        let reader = BufReader::new(file);
+        let mut groups = Vec::new();
+
+        for res in reader.lines() {
+            let line = try!(res);
+            if line.starts_with('#') {
+                continue;
+            }
+            let names: Vec<String> = line.split_whitespace().map(|name| name.to_string()).collect();
+            if names.len() > 1 {
+                groups.push(names);
+            }
+        }
+        Ok(ChromAliases { groups: groups, skip_unknown: skip_unknown })
+    }
+
+    /// Name lookup for the sequences of a sequence table
+    pub fn resolver(&self, seqinfos: &Vec<SequenceInfo>) -> ChromResolver {
+        let mut index = HashMap::new();
+        for (idx, sinfo) in seqinfos.iter().enumerate() {
+            index.insert(sinfo.name.clone(), idx);
+        }
+        for names in &self.groups {
+            let target = names.iter().filter_map(|name| seqinfos.iter().position(|sinfo| &sinfo.name == name)).next();
+            if let Some(idx) = target {
+                for name in names {
+                    index.entry(name.clone()).or_insert(idx);
+                }
+            }
+        }
+        ChromResolver {
+            index: index,
+            lengths: seqinfos.iter().map(|sinfo| sinfo.length).collect(),
+            skip_unknown: self.skip_unknown,
+            unknown: BTreeMap::new()
+        }
+    }
+}
+
+/// Maps sequence names to sequence table indexes, keeping count of reads on unknown sequences
+pub struct ChromResolver {
+    index: HashMap<String, usize>,
+    lengths: Vec<u32>,
+    skip_unknown: bool,
+    unknown: BTreeMap<String, u64>,
+}
+
+impl ChromResolver {
+    /// Sequence table index for `name`, either directly, through an alias or by adding/removing a `chr` prefix
+    pub fn resolve(&self, name: &str) -> Option<usize> {
+        if let Some(&idx) = self.index.get(name) {
+            return Some(idx);
+        }
+        if name.starts_with("chr") {
+            self.index.get(&name[3..]).map(|&idx| idx)
+        } else {
+            self.index.get(&format!("chr{}", name)).map(|&idx| idx)
+        }
+    }
+
+    /// Sequence table index for reads on `name`, None if they are to be skipped
+    ///
+    /// Unknown names are an error unless unknown sequences are skipped.
+    pub fn sequence_index(&self, name: &str) -> Option<usize> {
+        match self.resolve(name) {
+            Some(idx) => Some(idx),
+            None if self.skip_unknown => None,
+            None => {
+                println!("Error: Unknown sequence name in input: {} (see --chrom-aliases and --skip-unknown-chroms)", name);
+                exit(1);
+            },
+        }
+    }
+
+    /// Sequences in `declared` whose length differs from the matching sequence table entry, as
+    /// (name, declared length, table length)
+    ///
+    /// Sequences unknown to the table are not compared. The sequence table does not store sequence
+    /// digests, so M5 tags of SAM headers cannot be checked.
+    pub fn length_mismatches(&self, declared: &Vec<SequenceInfo>) -> Vec<(String, u32, u32)> {
+        declared.iter().filter_map(|sinfo| match self.resolve(&sinfo.name) {
+            Some(idx) if self.lengths[idx] != sinfo.length => Some((sinfo.name.clone(), sinfo.length, self.lengths[idx])),
+            _ => None,
+        }).collect()
+    }
+
+    /// Fail if the `declared` sequences come from a different assembly than the sequence table, only
+    /// warn with `allow_mismatch`
+    pub fn check_lengths(&self, declared: &Vec<SequenceInfo>, allow_mismatch: bool) {
+        let mismatches = self.length_mismatches(declared);
+        if mismatches.is_empty() {
+            return;
+        }
+        let prefix = if allow_mismatch { "WARN" } else { "Error" };
+        println!("{}: Assembly mismatch, {} sequences differ in length from the sequence table:", prefix, mismatches.len());
+        for (name, length, table_length) in mismatches {
+            println!("  {}: {} (sequence table: {})", name, length, table_length);
+        }
+        if !allow_mismatch {
+            println!("Use --allow-length-mismatch to proceed anyway.");
+            exit(1);
+        }
+    }
+
+    /// Record `count` reads skipped on unknown sequence `name`
+    pub fn skipped(&mut self, name: &str, count: u64) {
+        *self.unknown.entry(name.to_string()).or_insert(0) += count;
+    }
+
+    /// Number of reads skipped per unknown sequence
+    pub fn unknown(&self) -> &BTreeMap<String, u64> {
+        &self.unknown
+    }
+
+    /// Print a warning summarizing the reads skipped on unknown sequences
+    pub fn report(&self) {
+        if self.unknown.is_empty() {
+            return;
+        }
+        let total: u64 = self.unknown.values().sum();
+        let names: Vec<String> = self.unknown.iter().map(|(name, count)| format!("{} ({})", name, count)).collect();
+        println!("WARN: {} reads on {} unknown sequences skipped: {}", total, self.unknown.len(), names.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqinfos(names: &[&str]) -> Vec<SequenceInfo> {
+        names.iter().map(|name| SequenceInfo { name: name.to_string(), length: 100 }).collect()
+    }
+
+    #[test]
+    fn chr_prefix_is_added_or_removed() {
+        let resolver = ChromAliases::default().resolver(&seqinfos(&["chr1", "2"]));
+        assert_eq!(Some(0), resolver.resolve("chr1"));
+        assert_eq!(Some(0), resolver.resolve("1"));
+        assert_eq!(Some(1), resolver.resolve("chr2"));
+        assert_eq!(None, resolver.resolve("chrUn_KI270302v1"));
+    }
+
+    #[test]
+    fn aliases_map_to_table_names() {
+        let aliases = ChromAliases {
+            groups: vec![vec!["chrM".to_string(), "MT".to_string(), "NC_012920.1".to_string()]],
+            skip_unknown: true,
+        };
+        let mut resolver = aliases.resolver(&seqinfos(&["chr1", "MT"]));
+        assert_eq!(Some(1), resolver.resolve("chrM"));
+        assert_eq!(Some(1), resolver.resolve("NC_012920.1"));
+        assert_eq!(None, resolver.sequence_index("chrY"));
+
+        resolver.skipped("chrY", 2);
+        resolver.skipped("chrY", 1);
+        assert_eq!(Some(&3), resolver.unknown().get("chrY"));
+    }
+
+    #[test]
+    fn length_mismatches_of_known_sequences() {
+        let resolver = ChromAliases::default().resolver(&seqinfos(&["chr1", "chr2"]));
+        let declared = vec![
+            SequenceInfo { name: "1".to_string(), length: 100 },
+            SequenceInfo { name: "2".to_string(), length: 120 },
+            SequenceInfo { name: "3".to_string(), length: 50 },
+        ];
+        assert_eq!(vec![("2".to_string(), 120, 100)], resolver.length_mismatches(&declared));
+    }
+}
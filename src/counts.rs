@@ -2,252 +2,132 @@
 //!	This module contains the code to sum up n-mer aligned read counts based on a
 //! SeqTable instance. 
 //!
-use htslib::bam;
-use htslib::bam::Read;
-use htslib::bam::Reader;
-use htslib::bam::Records;
 use std::fs::File;
 use std::io::Read as ioRead;
 use std::io::Seek;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Result;
-use std::io::ErrorKind;
-use std::io::Error as ioError;
 use std::error::Error;
 use std::process::exit;
-use std::ops::Range;
-use std::iter::Peekable;
-use seqtable::{SeqTableParams,SeqTable,SequenceInfo};
-use std::cmp::Ordering;
+use seqtable::{SeqTableParams,SeqTable,SequenceInfo,NmerCount,NmerCounts,KmerCodec};
 use std::str;
-use filter::{RecordCheck, PairedChecker, SingleChecker};
+use std::collections::{BTreeMap, HashMap};
+use split::ReadSplit;
+use source;
+use source::{AlignmentSource, SiteEvent, ReadOptions, HtsReader, skip_sequence};
+use chroms::ChromResolver;
+use regions::BedRanges;
+use filter::{FlagFilter, fragment_length};
 
-struct KeyIter<'a> {
-    kmer: Vec<u8>,
-    alph: [char; 4],
-    mask: Option<&'a Vec<bool>>,
+/// Genome k-mer counts shared by all read groups, along with the cut-site k-mer counts of each group
+///
+/// Groups only store the k-mers observed at their cut-sites, so splitting by a tag with many values
+/// (e.g. cell barcodes) does not copy the genome table per value.
+pub struct GroupCounts {
+    genome: NmerCounts,
+    genome_totals: NmerCount,
+    groups: Vec<HashMap<u64, (u64, u64)>>,
 }
 
-impl<'a> KeyIter<'a> {
-    fn new(k: u8, mask: Option<&'a Vec<bool>>) -> KeyIter {
-        let mut kmer = vec![1; k as usize];
-        kmer[(k - 1) as usize] = 0;
-        KeyIter { kmer: kmer, alph: ['A','C','G','T'], mask: mask }
+impl GroupCounts {
+    pub fn new(genome: NmerCounts) -> GroupCounts {
+        let totals = genome.totals();
+        GroupCounts { genome: genome, genome_totals: totals, groups: vec![HashMap::new()] }
     }
-    
-    fn key(&self) -> String {
-        let mut key = String::new();
-        
-        match self.mask {
-            Some(flags) => {
-                let kiter = &mut self.kmer.iter();
-                for &flag in flags {
-                    if flag {
-                        let b = kiter.next().unwrap();
-                        key.push(self.alph[(b - 1) as usize]);
-                    } else {
-                        key.push('N');
-                    }
-                } 
-            },
-            None => {
-                for b in &self.kmer {
-                    key.push(self.alph[(b - 1) as usize]);
-                }
-            }
-        }
-        key
-    }
-}
 
-impl<'a> Iterator for KeyIter<'a> {
-    type Item = String;
-    
-    fn next(&mut self) -> Option<String> {
-        let mut idx = self.kmer.len() - 1;
-        
-        loop {
-            self.kmer[idx] += 1;
-            if self.kmer[idx] > 4 {
-                self.kmer[idx] = 1;
-                
-                if idx > 0 {
-                  idx -= 1;  
-                } else {
-                  return None;  
-                }
-            } else {
-                break;
-            }
-        }
-        
-        Some(self.key())
+    /// Number of read groups
+    pub fn len(&self) -> usize {
+        self.groups.len()
     }
-}
-
-
-// Data for iterator over BED regions
-struct BedRanges {
-  sets: Vec<Vec<(u32, u32)>>,
-  chrom_idx: usize,
-  row_idx: usize,
-}
 
-impl BedRanges {
-  
-  pub fn parse(filename: &str, chroms: Vec<String>) -> Result<BedRanges> {
-    let file = try!(File::open(filename));
-    let reader = BufReader::new(file);
-    let mut n_lines = 0;
-    let mut n_no_chrom = 0;
-    let mut sets = Vec::new();
-    
-    for _ in 0..chroms.len() {
-      sets.push(Vec::new());
+    pub fn genome(&self) -> &NmerCounts {
+        &self.genome
     }
-    
-    for res in reader.lines() {
-      let line = try!(res);
-      let parts: Vec<&str> = line.split('\t').collect();
-      
-      //
-      if parts.len() < 3 {
-        return Err(ioError::new(ErrorKind::Other, format!("not enough fields in line '{}'", line)));
-      }
-      n_lines += 1;
-      
-      // locate chromosome in set
-      let idx = match chroms.iter().position(|chr| chr == parts[0]) {
-        Some(idx) => idx,
-        None => {
-          n_no_chrom += 1;
-          continue;
+
+    fn ensure_group(&mut self, group: usize) {
+        while self.groups.len() <= group {
+            self.groups.push(HashMap::new());
         }
-      };
-      
-      // add region to set
-      let start: u32 = match parts[1].parse() {
-        Ok(value) => value,
-        Err(_) => return Err(ioError::new(ErrorKind::Other, format!("invalid field value in line '{}'", line))),
-      };
-      let end: u32 = match parts[2].parse() {
-        Ok(value) => value,
-        Err(_) => return Err(ioError::new(ErrorKind::Other, format!("invalid field value in line '{}'", line))),
-      };
-      sets[idx].push((start, end));
     }
-    
-    // sort sets by starting position
-    for i in 0..sets.len() {
-      sets[i].sort_by(|a, b| a.0.cmp(&b.0)); // TODO: consider converting this to sort_by_key - requires rust 1.7
-    }
-    
-    // check that no stored range overlaps
-    for i in 0..sets.len() {
-      for j in 1..sets[i].len() {
-        if sets[i][j].0 < sets[i][j - 1].1 {
-          return Err(ioError::new(ErrorKind::Other, format!("overlapping ranges in {}: {}-{} & {}-{}", chroms[i], sets[i][j - 1].0, sets[i][j - 1].1, sets[i][j].0, sets[i][j].1)));
-        }
-      }
+
+    /// Count a read cut-site with k-mer index `idx` in read group `group`
+    pub fn add_site(&mut self, group: usize, idx: u64, reverse: bool) {
+        self.ensure_group(group);
+        let entry = self.groups[group].entry(idx).or_insert((0, 0));
+        if reverse { entry.1 += 1 } else { entry.0 += 1 }
     }
-    
-    if n_no_chrom > 0 {
-      println!("WARN:{}: {} rows ({:.2} %) from unknown chromosomes.", filename, n_no_chrom, n_no_chrom as f32 / n_lines as f32 * 100.0);
+
+    /// Counts of k-mer `idx` in read group `group`: (plus genome, minus genome, plus reads, minus reads)
+    pub fn get(&self, group: usize, idx: u64) -> NmerCount {
+        let (sp, sm, _, _) = self.genome[idx];
+        let (bp, bm) = self.groups[group].get(&idx).cloned().unwrap_or((0, 0));
+        (sp, sm, bp, bm)
     }
-    
-    Ok(BedRanges{ sets: sets, chrom_idx: 0, row_idx: 0})
-  }
-  
-  fn contains(&self, chrom_idx: usize, position: i32) -> bool {
-      if position < 0 { return false; }
-      let position = position as u32;
-      self.sets[chrom_idx].binary_search_by(|probe| {
-          if probe.0 <= position && probe.1 > position {
-              Ordering::Equal
-          } else if probe.0 > position {
-              Ordering::Greater
-          } else {
-              Ordering::Less
-          }
-      }).is_ok()
-  }
-}
 
-// Iterator over BED regions returns chromosome index (as per supplied map) and the region coordinates in the form of a iteratable Range
-impl Iterator for BedRanges {
-  type Item = (usize, Range<u32>);
-  
-  fn next(&mut self) -> Option<(usize, Range<u32>)> {
-    if self.row_idx >= self.sets[self.chrom_idx].len() {
-      self.row_idx = 0;
-      self.chrom_idx += 1;
+    /// Sum of the counts of all mappable k-mers (index 0 excluded) in read group `group`
+    pub fn totals(&self, group: usize) -> NmerCount {
+        let (bp, bm) = self.groups[group].iter().filter(|&(&idx, _)| idx > 0)
+            .fold((0, 0), |acc, (_, &(bp, bm))| (acc.0 + bp, acc.1 + bm));
+        (self.genome_totals.0, self.genome_totals.1, bp, bm)
     }
-    if self.chrom_idx >= self.sets.len() {
-      return None;
+
+    /// Counts of read group `group`, in index order
+    ///
+    /// Sparse genome tables only list the k-mers observed in the genome.
+    pub fn iter<'a>(&'a self, group: usize) -> Box<Iterator<Item=(u64, NmerCount)> + 'a> {
+        Box::new(self.genome.iter().map(move |(idx, _)| (idx, self.get(group, idx))))
     }
-    
-    let result = Some((self.chrom_idx, Range { start: self.sets[self.chrom_idx][self.row_idx].0, end: self.sets[self.chrom_idx][self.row_idx].1}));
-    self.row_idx += 1;
-    result
-  }
 }
 
-fn process_bam_seq<R: ioRead+Seek, C: RecordCheck>(counts: &mut Vec<(u64, u64, u64, u64)>, table: &mut SeqTable<R>, bamrecs: &mut Peekable<Records<Reader>>, tid: &mut i32, map: &Vec<usize>, checker: &C, regions: Option<&BedRanges>) -> bool {
-    // skip unmapped sequences (tid = -1)
-    if *tid < 0 {
-        match bamrecs.next() {
-            Some(Ok(ref rec)) => { *tid = rec.tid(); return true; },
-            Some(Err(_)) => return false,
-            None => return false,
-        }
-    }
-    
-    let chrom_idx = map[*tid as usize];
+// count cut-sites of the reads on one sequence, returns the first event on the next sequence
+fn tabulate_seq<R: ioRead+Seek>(counts: &mut GroupCounts, split: &mut ReadSplit, table: &mut SeqTable<R>, seqinfos: &Vec<SequenceInfo>, chroms: &mut ChromResolver, source: &mut AlignmentSource, regions: Option<&BedRanges>, first: SiteEvent) -> Option<SiteEvent> {
+    let chrom = first.chrom;
+    let chrom_idx = match chroms.sequence_index(source.chrom_name(chrom)) {
+        Some(idx) => idx,
+        None => return skip_sequence(source, split, chroms, first),
+    };
     let len_idx = table.len_by_idx(chrom_idx).ok().expect("read sequence length") as i32;
+    let rlen = table.params.read_length as usize;
     // added this kmersize for bit sliding 
     let mut rdr = table.get_sequence_by_idx(chrom_idx).ok().expect("read sequence");
+    let mut event = first;
     
     loop {
-        // check if we changed sequence
-        match bamrecs.peek() {
-            Some(&Ok(ref rec)) => if rec.tid() != *tid { *tid = rec.tid(); return true; },
-            Some(&Err(_)) => return false,
-            None => return false,
-        }
-        
-        // if not count position
-        if let Some(Ok(record)) = bamrecs.next() {
+        // select counts table of the read group
+        let group = if split.split_counts() { event.group } else { 0 };
+
+        for site in event.sites {
             // point in regions
             let good = match regions.as_ref() {
-                Some(ref ranges) => ranges.contains(chrom_idx, checker.vir_pos(&record)),
+                Some(ref ranges) => ranges.contains(chrom_idx, site.pos),
                 None => true,
             };
-            
-            //
-            if good && checker.valid(&record) {
-                let pos = checker.vir_pos(&record);
-                if pos >= len_idx {
-                    println!("WARN:{}: read extends beyond reference sequence length ({} > {}). Read ignored.", str::from_utf8(record.qname()).unwrap(), pos + 1, len_idx);
-                } else {
-                    let pair = rdr.vir_get(pos).unwrap();
-                    
-                    if record.is_reverse() {
-		    // reverse complement here
-                        counts[pair.1 as usize].3 += 1;
-                    } else {
-                        counts[pair.0 as usize].2 += 1;
-                    } 
-                }
+
+            if !good {
+                continue;
             }
-        } 
+            let pos = site.table_pos(rlen);
+            if pos >= len_idx {
+                println!("WARN:{}:{}: read extends beyond reference sequence length ({} > {}). Read ignored.", seqinfos[chrom_idx].name, site.pos, pos + 1, len_idx);
+            } else {
+                let pair = rdr.vir_get(pos).unwrap();
+                
+                // minus strand sites use the reverse complement k-mer
+                let idx = if site.reverse { pair.1 } else { pair.0 };
+                counts.add_site(group, idx, site.reverse);
+            }
+        }
+
+        // check if we changed sequence
+        event = match source.next_event(split) {
+            Some(next) => if next.chrom == chrom { next } else { return Some(next) },
+            None => return None,
+        };
     }
 }
 
-fn bed_regions<R: ioRead + Seek>(filename: &str, table: &mut SeqTable<R>) -> BedRanges {
+fn bed_regions<R: ioRead + Seek>(filename: &str, table: &mut SeqTable<R>, resolver: &ChromResolver) -> BedRanges {
     let chroms: Vec<String> = table.sequences().iter().map(|sinfo| sinfo.name.clone()).collect();
     
-    match BedRanges::parse(filename, chroms) {
+    match BedRanges::parse(filename, chroms, resolver) {
         Ok(iter) => iter,
         Err(e) => {
             println!("Error: Failed to read BED file {}: {}", filename, e.description()); 
@@ -256,27 +136,22 @@ fn bed_regions<R: ioRead + Seek>(filename: &str, table: &mut SeqTable<R>) -> Bed
     }
 }
 
-fn region_counts<R: ioRead + Seek>(table: &mut SeqTable<R>, bedregions: &str) -> Vec<(u64, u64, u64, u64)> {
-    let bediter = bed_regions(bedregions, table);
+fn region_counts<R: ioRead + Seek>(table: &mut SeqTable<R>, bedregions: &str, resolver: &ChromResolver) -> NmerCounts {
+    let bediter = bed_regions(bedregions, table, resolver);
     
     // allocate counts table
-    let mut counts: Vec<(u64, u64, u64, u64)> = Vec::new();
-    let nmer_count = table.params.nmer_count();
-    
-    for _ in 0..nmer_count {
-        counts.push((0,0,0,0));
-    }
+    let mut counts = NmerCounts::new(table.params.nmer_count());
     
     let n_seqs = table.len();
     
     for idx in 0..n_seqs {
         let mut rdr = table.get_sequence_by_idx(idx).ok().expect("read sequence");
         
-        for &(start,end) in &bediter.sets[idx] {
+        for &(start,end) in bediter.ranges(idx) {
             for position in start..end {
                 let pair = rdr.get(position).unwrap();
-                counts[pair.0 as usize].0 += 1;
-                counts[pair.1 as usize].1 += 1;
+                counts[pair.0].0 += 1;
+                counts[pair.1].1 += 1;
             }
         }
         
@@ -285,69 +160,7 @@ fn region_counts<R: ioRead + Seek>(table: &mut SeqTable<R>, bedregions: &str) ->
     counts
 }
 
-fn tabulate_bam<R: ioRead + Seek>(bamfilename: String, seqinfos: &Vec<SequenceInfo>, pair_range: &Option<(i32, i32)>, paired: bool, rlen: usize, minqual: u8, counts: &mut Vec<(u64, u64, u64, u64)>, table: &mut SeqTable<R>, regions: Option<&BedRanges>, exact_length: bool, tail_edge: bool) {
-    println!("# tabulate {}", bamfilename);
-            
-    let bam = match bam::Reader::from_path(&bamfilename) {
-        Ok(value) => value,
-        Err(err) => {
-            println!("Error: Failed to open BAM '{}': {}", &bamfilename, err.description());
-            exit(1);
-        },
-    };
-    let names = bam.header().target_names();
-    let mut cur_tid = 0;
-    
-    // map BAM tid's to SeqTable idx's
-    let map : Vec<usize> = names.iter().map(|&id| {
-        let chrom = String::from_utf8_lossy(id);
-        for i in 0..seqinfos.len() {
-            if seqinfos[i].name.eq(&chrom) {
-                return i;
-            }
-        }
-        println!("Error: Unknown sequence name in BAM: {}", chrom);
-        exit(1);
-    }
-    ).collect();
-    
-    // reads
-    let mut iter = bam.records().peekable();
-    
-    if pair_range.is_some() || paired {
-        let checker = match *pair_range {
-            Some((min, max)) => PairedChecker {
-                tail_edge: tail_edge,
-                exact_length: exact_length,
-                read_length: rlen,
-                min_quality: minqual,
-                min_dist: min,
-                max_dist: max,
-                force_paired: paired,
-                max_distance: true,
-                select_pair: None
-            },
-            None => PairedChecker {
-                tail_edge: tail_edge,
-                exact_length: exact_length,
-                read_length: rlen,
-                min_quality: minqual,
-                min_dist: 0,
-                max_dist: 0,
-                force_paired: paired,
-                max_distance: false,
-                select_pair: None
-            },
-        };
-        while process_bam_seq(counts, table, &mut iter, &mut cur_tid, &map, &checker, regions) {}
-    } else {
-        let checker = SingleChecker { tail_edge: tail_edge, exact_length: exact_length, read_length: rlen, min_quality: minqual };
-        while process_bam_seq(counts, table, &mut iter, &mut cur_tid, &map, &checker, regions) {}
-    }
-}
-
-pub fn tabulate(seqfile: &str, bamfile: Option<&Vec<String>>, minqual: u8, regions: Option<String>, pair_range: Option<(i32, i32)>, paired: bool, exact_length: bool, tail_edge: bool) -> Vec<(u64, u64, u64, u64)> {
-    // read
+fn open_table(seqfile: &str) -> SeqTable<File> {
     let file = match File::open(seqfile) {
         Ok(value) => value,
         Err(err) => {
@@ -355,55 +168,123 @@ pub fn tabulate(seqfile: &str, bamfile: Option<&Vec<String>>, minqual: u8, regio
             exit(1);
         },
     };
-    let mut table = match SeqTable::open(file) {
+    match SeqTable::open(file) {
         Ok(value) => value,
         Err(e) => {
             println!("Error:tabulate: {}", e.to_string()); 
             exit(1); 
         },
-    };
+    }
+}
+
+/// Genome k-mer counts along with the k-mer counts at the read cut-sites of each input file
+/// (BAM/CRAM/SAM, BED6 read ends or fragments, see `source::open`) for each read group
+pub fn tabulate(seqfile: &str, inputs: Option<&Vec<String>>, regions: Option<String>, options: &ReadOptions, split: &mut ReadSplit) -> GroupCounts {
+    let read_length = SeqTableParams::from_file(seqfile).read_length as usize;
+    let sources = inputs.map(|filenames| filenames.iter().map(move |filename| {
+        println!("# tabulate {}", filename);
+        source::open(filename, read_length, options)
+    }));
+
+    tabulate_sources(seqfile, sources, regions, options, split)
+}
+
+/// Same as `tabulate`, reading cut-sites from already opened `sources`
+///
+/// The sequence lengths declared by each source are checked against the sequence table here, the
+/// later `scale` pass over the same inputs does not repeat the check.
+pub fn tabulate_sources<I: IntoIterator<Item=Box<AlignmentSource>>>(seqfile: &str, sources: Option<I>, regions: Option<String>, options: &ReadOptions, split: &mut ReadSplit) -> GroupCounts {
+    let mut table = open_table(seqfile);
+    let seqinfos = table.sequences();
+    let mut chroms = options.chroms.resolver(&seqinfos);
     
     // get counts table from file
-    let mut counts = match regions.as_ref() {
-        Some(regfile) => region_counts(&mut table, regfile),
+    let genome = match regions.as_ref() {
+        Some(regfile) => region_counts(&mut table, regfile, &chroms),
         None => table.counts().unwrap(),  
     };
-    
-    //
-    let rlen = table.params.read_length as usize;
-    let seqinfos = table.sequences();
+    let mut counts = GroupCounts::new(genome);
         
-    // if we received a BAM file, parse it
-    if let Some(bamfilenames) = bamfile {
+    // if we received read files, parse them
+    if let Some(sources) = sources {
         // bed regions
         let ranges = if let Some(regfile) = regions {
-            Some(bed_regions(&regfile, &mut table))
+            Some(bed_regions(&regfile, &mut table, &chroms))
         } else {
             None
         };
         
-        for bamfilename in bamfilenames {
-            tabulate_bam(bamfilename.clone(), &seqinfos, &pair_range, paired, rlen, minqual, &mut counts, &mut table, ranges.as_ref(), exact_length, tail_edge);
+        for mut source in sources {
+            chroms.check_lengths(&source.declared_sequences(), options.allow_length_mismatch);
+            let mut pending = source.next_event(split);
+            while let Some(event) = pending {
+                pending = tabulate_seq(&mut counts, split, &mut table, &seqinfos, &mut chroms, &mut *source, ranges.as_ref(), event);
+            }
         }
+        chroms.report();
+    }
+
+    // groups known upfront (whitelisted tag values) but without reads
+    if split.split_counts() && split.len() > 0 {
+        counts.ensure_group(split.len() - 1);
     }
     
     counts
 }
 
-pub fn print_counts(counts: &Vec<(u64, u64, u64, u64)>, with_bam: bool, params: &SeqTableParams) {
-    let mut keys = KeyIter::new(params.unmasked_count, params.mask.as_ref());
-    
-    if with_bam {
-        for i in 1..counts.len() {
-            let (plus, minus, bam_plus, bam_minus) = counts[i];
-            let key = keys.next().unwrap();
-            println!("{}\t{}\t{}\t{}\t{}\t{}", i, key, plus, minus, bam_plus, bam_minus);
+pub fn print_counts(counts: &GroupCounts, group: usize, with_bam: bool, params: &SeqTableParams) {
+    let codec = KmerCodec::new(params);
+    let sparse = counts.genome().is_sparse();
+    // sparse tables only list the observed k-mers
+    for (i, (plus, minus, bam_plus, bam_minus)) in counts.iter(group) {
+        if i == 0 || (sparse && plus + minus + bam_plus + bam_minus == 0) {
+            continue;
         }
-    } else {
-        for i in 1..counts.len() {
-            let (plus, minus, _, _) = counts[i];
-            let key = keys.next().unwrap();
+        let key = match codec.decode(i) {
+            Ok(key) => key,
+            Err(err) => {
+                println!("Error: {}", err);
+                exit(1);
+            },
+        };
+        if with_bam {
+            println!("{}\t{}\t{}\t{}\t{}\t{}", i, key, plus, minus, bam_plus, bam_minus);
+        } else {
             println!("{}\t{}\t{}\t{}", i, key, plus, minus);
         }
     }
 }
+
+/// Histogram of fragment lengths for paired reads in the BAM files, each pair is counted once (on the first mate)
+pub fn fragment_lengths(bamfilenames: &Vec<String>, minqual: u8, flags: FlagFilter) -> BTreeMap<i32, u64> {
+    let mut hist = BTreeMap::new();
+
+    for bamfilename in bamfilenames {
+        println!("# fraglen {}", bamfilename);
+
+        let reader = match HtsReader::from_path(bamfilename, None) {
+            Ok(value) => value,
+            Err(err) => {
+                println!("Error: Failed to open alignment file '{}': {}", bamfilename, err);
+                exit(1);
+            },
+        };
+
+        for record in reader {
+            if !record.is_first_in_template() || !flags.accept(&record) || record.mapq() < minqual {
+                continue;
+            }
+            if let Some(length) = fragment_length(&record) {
+                *hist.entry(length).or_insert(0) += 1;
+            }
+        }
+    }
+
+    hist
+}
+
+pub fn print_fragment_lengths(hist: &BTreeMap<i32, u64>) {
+    for (length, count) in hist {
+        println!("{}\t{}", length, count);
+    }
+}
@@ -0,0 +1,130 @@
+//!
+//!	UMI-aware removal of duplicate reads from coordinate sorted BAM files.
+//!
+use htslib::bam::record::{Record, Aux};
+use std::collections::BTreeMap;
+use filter::aligned_span;
+
+/// Location of the UMI in a record
+#[derive(Copy, Clone, Debug)]
+pub enum UmiSource {
+    /// Value of a SAM tag (eg. RX or UB)
+    Tag([u8; 2]),
+    /// Last field of the read name, after the last '_' or ':'
+    ReadName,
+}
+
+/// Duplicate read detection based on 5' position, strand, read group and UMI
+///
+/// Reads must be visited in coordinate order and `clear()` must be called when changing sequence.
+/// Reads without a UMI are never considered duplicates.
+pub struct UmiDedup {
+    source: UmiSource,
+    allow_mismatch: bool,
+    seen: BTreeMap<(i32, bool, usize), Vec<Vec<u8>>>,
+}
+
+fn umi_match(a: &[u8], b: &[u8], allow_mismatch: bool) -> bool {
+    if !allow_mismatch || a.len() != b.len() {
+        return a == b;
+    }
+    a.iter().zip(b.iter()).filter(|&(x, y)| x != y).count() <= 1
+}
+
+impl UmiDedup {
+    pub fn new(source: UmiSource, allow_mismatch: bool) -> UmiDedup {
+        UmiDedup { source: source, allow_mismatch: allow_mismatch, seen: BTreeMap::new() }
+    }
+
+    fn umi(&self, rec: &Record) -> Option<Vec<u8>> {
+        match self.source {
+            UmiSource::Tag(ref tag) => match rec.aux(tag) {
+                Some(Aux::String(value)) => Some(value.to_vec()),
+                _ => None,
+            },
+            UmiSource::ReadName => {
+                let qname = rec.qname();
+                qname.iter().rposition(|&c| c == b'_' || c == b':').map(|idx| qname[idx + 1..].to_vec())
+            },
+        }
+    }
+
+    /// Forget all reads seen so far
+    pub fn clear(&mut self) {
+        self.seen.clear();
+    }
+
+    /// Check if the record duplicates a previously seen read within the same `group`
+    pub fn is_duplicate(&mut self, rec: &Record, group: usize) -> bool {
+        // reads are sorted by leftmost position and 5' ends are never before it,
+        // so earlier 5' ends cannot be matched anymore
+        self.seen = self.seen.split_off(&(rec.pos(), false, 0));
+
+        let umi = match self.umi(rec) {
+            Some(value) => value,
+            None => return false,
+        };
+        let (start, end) = aligned_span(rec);
+        let key = (if rec.is_reverse() { end } else { start }, rec.is_reverse(), group);
+
+        let umis = self.seen.entry(key).or_insert(Vec::new());
+        let allow_mismatch = self.allow_mismatch;
+        if umis.iter().any(|seen| umi_match(seen, &umi, allow_mismatch)) {
+            return true;
+        }
+        umis.push(umi);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use htslib::bam::record::{Cigar, CigarString};
+
+    fn record(qname: &[u8], pos: i32, reverse: bool, umi: Option<&[u8]>) -> Record {
+        let mut rec = Record::new();
+        rec.set(qname, &CigarString(vec![Cigar::Match(10)]), &[b'A'; 10], &[30u8; 10]);
+        rec.set_pos(pos);
+        if reverse { rec.set_reverse(); }
+        if let Some(value) = umi {
+            rec.push_aux(b"RX", &Aux::String(value));
+        }
+        rec
+    }
+
+    #[test]
+    fn same_position_strand_and_umi_is_duplicate() {
+        let mut dedup = UmiDedup::new(UmiSource::Tag(*b"RX"), false);
+        assert!(!dedup.is_duplicate(&record(b"r01", 100, false, Some(b"ACGT")), 0));
+        assert!(dedup.is_duplicate(&record(b"r02", 100, false, Some(b"ACGT")), 0));
+        assert!(!dedup.is_duplicate(&record(b"r03", 100, false, Some(b"ACGA")), 0));
+        assert!(!dedup.is_duplicate(&record(b"r04", 100, true, Some(b"ACGT")), 0));
+        assert!(!dedup.is_duplicate(&record(b"r05", 100, false, Some(b"ACGT")), 1));
+        assert!(!dedup.is_duplicate(&record(b"r06", 100, false, None), 0));
+        assert!(!dedup.is_duplicate(&record(b"r07", 100, false, None), 0));
+    }
+
+    #[test]
+    fn reverse_reads_match_on_five_prime_end() {
+        let mut dedup = UmiDedup::new(UmiSource::Tag(*b"RX"), false);
+        // 5' end at 109 for both reads, second starting later
+        assert!(!dedup.is_duplicate(&record(b"r01", 100, true, Some(b"ACGT")), 0));
+        let mut rec = Record::new();
+        rec.set(b"r02", &CigarString(vec![Cigar::Match(5)]), &[b'A'; 5], &[30u8; 5]);
+        rec.set_pos(105);
+        rec.set_reverse();
+        rec.push_aux(b"RX", &Aux::String(b"ACGT"));
+        assert!(dedup.is_duplicate(&rec, 0));
+    }
+
+    #[test]
+    fn single_mismatch_clustering_and_read_name_umi() {
+        let mut dedup = UmiDedup::new(UmiSource::ReadName, true);
+        // read names length (with terminator) kept at a multiple of 4 so the CIGAR data stays aligned
+        assert!(!dedup.is_duplicate(&record(b"rd01_ACGTAC", 100, false, None), 0));
+        assert!(dedup.is_duplicate(&record(b"rd02_ACGTAA", 100, false, None), 0));
+        assert!(!dedup.is_duplicate(&record(b"rd03:AAGTAA", 100, false, None), 0));
+        assert!(!dedup.is_duplicate(&record(b"rd04_ACGTAC", 101, false, None), 0));
+    }
+}
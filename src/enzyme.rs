@@ -0,0 +1,170 @@
+//!
+//!	Named enzyme presets expanding into k-mer mask and scale options.
+//!
+use std::fs::File;
+use std::io::Read;
+use std::slice::Iter;
+use toml::Value;
+use toml::value::Table;
+use seqtable::SeqTableParams;
+
+const BUILTIN_PRESETS: &'static str = include_str!("enzymes.toml");
+
+/// Options a preset may set, with their TOML type (as accepted by profile files)
+const PRESET_OPTIONS: [(&'static str, &'static str); 8] = [
+    ("kmer-mask", "string"),
+    ("kmer-size", "integer"),
+    ("plus-offset", "integer"),
+    ("minus-offset", "integer"),
+    ("strand-specific", "boolean"),
+    ("shift-counts", "boolean"),
+    ("custom-shift", "string"),
+    ("tail-edge", "boolean"),
+];
+
+pub struct EnzymePreset {
+    pub name: String,
+    pub description: String,
+    pub aliases: Vec<String>,
+    settings: Value,
+}
+
+impl EnzymePreset {
+    fn parse(name: &str, value: &Value) -> Result<EnzymePreset, String> {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return Err(format!("preset '{}' is not a table", name)),
+        };
+        let mut preset = EnzymePreset {
+            name: name.to_string(),
+            description: String::new(),
+            aliases: Vec::new(),
+            settings: Value::Table(Table::new()),
+        };
+        let mut settings = Table::new();
+
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("description", &Value::String(ref text)) => preset.description = text.clone(),
+                ("aliases", &Value::Array(ref names)) => {
+                    for alias in names {
+                        match alias.as_str() {
+                            Some(alias) => preset.aliases.push(alias.to_string()),
+                            None => return Err(format!("preset '{}': aliases must be strings", name)),
+                        }
+                    }
+                },
+                (key, value) => {
+                    match PRESET_OPTIONS.iter().find(|&&(option, _)| option == key) {
+                        Some(&(_, vtype)) if vtype == value.type_str() => {},
+                        Some(&(_, vtype)) => return Err(format!("preset '{}': option '{}' must be a {}", name, key, vtype)),
+                        None => return Err(format!("preset '{}': unsupported option '{}'", name, key)),
+                    }
+                    if key == "kmer-mask" {
+                        if let Err(err) = SeqTableParams::validate_mask(value.as_str().unwrap()) {
+                            return Err(format!("preset '{}': {}", name, err));
+                        }
+                    }
+                    settings.insert(key.to_string(), value.clone());
+                },
+            }
+        }
+        let strand_specific = settings.get("strand-specific").and_then(|value| value.as_bool()).unwrap_or(false);
+        let staggered = settings.get("kmer-mask").and_then(|value| value.as_str()).map_or(false, |mask| SeqTableParams::has_staggered_cut(mask));
+        if strand_specific && staggered {
+            return Err(format!("preset '{}': strand-specific masks cannot have separate plus (C) and minus (c) cut-sites", name));
+        }
+        preset.settings = Value::Table(settings);
+        Ok(preset)
+    }
+
+    /// Options set by the preset, in the format of profile files (see `Args::apply_profile`)
+    pub fn settings(&self) -> &Value {
+        &self.settings
+    }
+}
+
+/// Registry of enzyme presets
+pub struct EnzymePresets {
+    presets: Vec<EnzymePreset>,
+}
+
+impl EnzymePresets {
+    /// Presets shipped with seqOutBias
+    pub fn builtin() -> EnzymePresets {
+        EnzymePresets::parse(BUILTIN_PRESETS).expect("valid built-in enzyme presets")
+    }
+
+    /// Parse presets from TOML, one table per preset
+    pub fn parse(text: &str) -> Result<EnzymePresets, String> {
+        let value = try!(text.parse::<Value>().map_err(|err| err.to_string()));
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return Err("expected a table of presets".to_string()),
+        };
+        let mut presets = Vec::new();
+        for (name, preset) in table {
+            presets.push(try!(EnzymePreset::parse(name, preset)));
+        }
+        Ok(EnzymePresets { presets: presets })
+    }
+
+    pub fn from_file(filename: &str) -> Result<EnzymePresets, String> {
+        let mut file = try!(File::open(filename).map_err(|err| err.to_string()));
+        let mut text = String::new();
+        try!(file.read_to_string(&mut text).map_err(|err| err.to_string()));
+        EnzymePresets::parse(&text)
+    }
+
+    /// Add presets, replacing existing presets with the same name
+    pub fn extend(&mut self, other: EnzymePresets) {
+        for preset in other.presets {
+            let name = preset.name.to_lowercase();
+            self.presets.retain(|existing| existing.name.to_lowercase() != name);
+            self.presets.push(preset);
+        }
+    }
+
+    /// Preset by name or alias (case insensitive)
+    pub fn get(&self, name: &str) -> Option<&EnzymePreset> {
+        let name = name.to_lowercase();
+        self.presets.iter().find(|preset| preset.name.to_lowercase() == name)
+            .or_else(|| self.presets.iter().find(|preset| preset.aliases.iter().any(|alias| alias.to_lowercase() == name)))
+    }
+
+    pub fn iter(&self) -> Iter<EnzymePreset> {
+        self.presets.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_presets_are_valid() {
+        let presets = EnzymePresets::builtin();
+        assert_eq!("dnase", presets.get("DNase").unwrap().name);
+        assert_eq!(Some("NXNXXXCXXNNXNNNXXN"), presets.get("dnase").unwrap().settings().get("kmer-mask").and_then(|v| v.as_str()));
+        assert!(presets.iter().all(|preset| !preset.description.is_empty() && preset.settings().get("kmer-mask").is_some()));
+    }
+
+    #[test]
+    fn user_presets_replace_builtin() {
+        let mut presets = EnzymePresets::builtin();
+        presets.extend(EnzymePresets::parse("[DNase]\nkmer-mask = \"NNCNN\"\n").unwrap());
+        presets.extend(EnzymePresets::parse("[tn5]\naliases = [\"atac\"]\nkmer-mask = \"NNCNN\"\ncustom-shift = \"4,-5\"\n").unwrap());
+        let preset = presets.get("dnase").unwrap();
+        assert_eq!(Some("NNCNN"), preset.settings().get("kmer-mask").and_then(|v| v.as_str()));
+        assert_eq!("tn5", presets.get("ATAC").unwrap().name);
+        assert_eq!(Some("4,-5"), presets.get("tn5").unwrap().settings().get("custom-shift").and_then(|v| v.as_str()));
+    }
+
+    #[test]
+    fn invalid_presets_are_rejected() {
+        assert!(EnzymePresets::parse("[x]\nkmer-mask = \"NNCNNQ\"\n").is_err());
+        assert!(EnzymePresets::parse("[x]\nshift-counts = \"yes\"\n").is_err());
+        assert!(EnzymePresets::parse("[x]\nqual = 30\n").is_err());
+        assert!(EnzymePresets::parse("[x]\nkmer-mask = \"NCNNcN\"\nstrand-specific = true\n").is_err());
+    }
+}
@@ -0,0 +1,82 @@
+//!
+//!	Random access to (optionally bgzipped) FASTA files through a .fai (and .gzi) index, using htslib.
+//!
+extern crate libc;
+
+use self::libc::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::slice;
+use fasta::GenomeSequences;
+
+#[allow(non_camel_case_types)]
+enum faidx_t {}
+
+extern "C" {
+    fn fai_load(fname: *const c_char) -> *mut faidx_t;
+    fn fai_destroy(fai: *mut faidx_t);
+    fn faidx_nseq(fai: *const faidx_t) -> c_int;
+    fn faidx_iseq(fai: *const faidx_t, i: c_int) -> *const c_char;
+    fn faidx_seq_len(fai: *const faidx_t, seq: *const c_char) -> c_int;
+    fn faidx_fetch_seq(fai: *const faidx_t, c_name: *const c_char, p_beg_i: c_int, p_end_i: c_int, len: *mut c_int) -> *mut c_char;
+}
+
+/// Indexed FASTA file
+pub struct FastaIndex {
+    fai: *mut faidx_t,
+    filename: String,
+}
+
+impl FastaIndex {
+    /// Open indexed FASTA file, the index is created if missing (bgzipped files also need a .gzi index)
+    pub fn open(filename: &str) -> Result<FastaIndex, String> {
+        let cname = try!(CString::new(filename).map_err(|_| format!("Invalid FASTA file name: {}", filename)));
+        let fai = unsafe { fai_load(cname.as_ptr()) };
+        if fai.is_null() {
+            return Err(format!("Failed to load or build the index of FASTA file '{}' (gzip compressed files must be compressed with bgzip)", filename));
+        }
+        Ok(FastaIndex { fai: fai, filename: filename.to_string() })
+    }
+}
+
+impl GenomeSequences for FastaIndex {
+    fn sequence_names(&self) -> Vec<String> {
+        let count = unsafe { faidx_nseq(self.fai) };
+        (0..count).map(|i| unsafe { CStr::from_ptr(faidx_iseq(self.fai, i)) }.to_string_lossy().into_owned()).collect()
+    }
+
+    fn sequence_length(&mut self, name: &str) -> Result<u32, String> {
+        let cname = try!(CString::new(name).map_err(|_| format!("Invalid sequence name: {}", name)));
+        let length = unsafe { faidx_seq_len(self.fai, cname.as_ptr()) };
+        if length < 0 {
+            return Err(format!("Sequence '{}' not found in FASTA file '{}'", name, self.filename));
+        }
+        Ok(length as u32)
+    }
+
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let cname = try!(CString::new(name).map_err(|_| format!("Invalid sequence name: {}", name)));
+        let length = unsafe { faidx_seq_len(self.fai, cname.as_ptr()) };
+        if length < 0 {
+            return Err(format!("Sequence '{}' not found in FASTA file '{}'", name, self.filename));
+        } else if length == 0 {
+            return Ok(Vec::new());
+        }
+        let mut fetched: c_int = 0;
+        let seq = unsafe { faidx_fetch_seq(self.fai, cname.as_ptr(), 0, length - 1, &mut fetched) };
+        if seq.is_null() || fetched < length {
+            if !seq.is_null() {
+                unsafe { libc::free(seq as *mut c_void) };
+            }
+            return Err(format!("Failed to read sequence '{}' from FASTA file '{}'", name, self.filename));
+        }
+        let bases = unsafe { slice::from_raw_parts(seq as *const u8, fetched as usize) }.to_vec();
+        unsafe { libc::free(seq as *mut c_void) };
+        Ok(bases)
+    }
+}
+
+impl Drop for FastaIndex {
+    fn drop(&mut self) {
+        unsafe { fai_destroy(self.fai) };
+    }
+}
@@ -3,17 +3,26 @@
 //!
 use std::collections::VecDeque;
 use std::io::prelude::*;
-use seqtable::{SeqBuffer, SeqTableParams, SeqTableWriter, SequenceWriter};
+use seqtable::{SeqBuffer, SeqStore, SeqTableParams, SeqTableWriter, NmerCounts};
+pub use seqtable::SoftMask;
 use tallyread::UnMap;
+use faidx::FastaIndex;
+use twobit::{TwoBitFile, is_twobit_file};
+use std::fs;
 use std::fs::File;
-use std::io::{BufReader, Bytes};
+use std::io;
+use std::io::{BufReader, Bytes, Cursor};
 use std::ffi::OsStr;
-use flate2::read::GzDecoder;
+use std::path::Path;
+use flate2::read::MultiGzDecoder;
 use std::process::exit;
-use fasta::context::{KmerIndex, EnzContext, EnzContextMasked, EnzContextMaskedStrandSpecific, EnzContextSimple};
+use fasta::context::{KmerIndex, EnzContext, EnzContextMasked, EnzContextMaskedStrandSpecific, EnzContextClassMasked, EnzContextSimple};
 
 mod context;
 
+/// First bytes of gzip (and BGZF) compressed files
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 // Base encoding
 #[repr(u8)]
 #[derive(PartialEq)]
@@ -34,9 +43,19 @@ enum State {
     End,
 }
 
-macro_rules! store_base {
-    ($e:expr, $b:expr, $n:expr) => {
-        match $e.add_base($n) {
+/// Random access to the sequences of a genome file (indexed FASTA or .2bit)
+pub trait GenomeSequences {
+    /// Sequence names, in file order
+    fn sequence_names(&self) -> Vec<String>;
+    /// Number of bases of sequence `name`
+    fn sequence_length(&mut self, name: &str) -> Result<u32, String>;
+    /// Bases of sequence `name` as ASCII letters, soft-masked bases in lowercase
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, String>;
+}
+
+macro_rules! store_index {
+    ($i:expr, $b:expr) => {
+        match $i {
           KmerIndex{ plus: Some(plus), minus: Some(minus) } => $b.push( plus + 1, minus + 1 ),
           KmerIndex{ plus: Some(plus), minus: None } => $b.push( plus + 1, 0 ),
           KmerIndex{ plus: None, minus: Some(minus) } => $b.push( 0, minus + 1 ),
@@ -44,26 +63,123 @@ macro_rules! store_base {
     }};
 }
 
-fn process_sequence<R1: Read, R2: BufRead, T:EnzContext>(seqwrt: SequenceWriter<File>, iter: &mut Bytes<R1>, enzctxt: &mut T, params: &SeqTableParams, unmap: &UnMap<R2>) -> State {
-    let mut buf = SeqBuffer::new(seqwrt, params, unmap);
+/// Sequences of a FASTA stream, read one at a time as (name, bases)
+///
+/// Bases keep their case (soft-masking), line breaks and other non-letter bytes are dropped.
+pub struct FastaSequences<R: Read> {
+    iter: Bytes<R>,
+    state: State,
+}
+
+impl<R: Read> FastaSequences<R> {
+    pub fn new(fasta: R) -> FastaSequences<R> {
+        FastaSequences { iter: fasta.bytes(), state: State::HeaderStart }
+    }
+}
+
+impl<R: Read> Iterator for FastaSequences<R> {
+    type Item = io::Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<io::Result<(String, Vec<u8>)>> {
+        let mut chrom: Vec<u8> = Vec::new();
+        // header line
+        loop {
+            let byte = match self.iter.next() {
+                Some(Ok(byte)) => byte,
+                Some(Err(err)) => return Some(Err(err)),
+                None => return None,
+            };
+            match self.state {
+                State::HeaderStart => if byte == b'>' {
+                        self.state = State::HeaderChrom;
+                    } else {
+                        return Some(Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid FASTA file. {}", byte as char))));
+                    },
+                State::HeaderChrom | State::Header => if byte == b'\n' {
+                        break;
+                    } else if self.state == State::HeaderChrom {
+                        // the name ends at the first whitespace (or the \r of CRLF line endings)
+                        if byte == b' ' || byte == b'\t' || byte == b'\r' {
+                            self.state = State::Header;
+                        } else {
+                            chrom.push(byte);
+                        }
+                    },
+                State::End => return None,
+            }
+        }
+
+        // sequence lines, up to the next header
+        let mut bases = Vec::new();
+        self.state = State::End;
+        while let Some(res) = self.iter.next() {
+            let byte = match res {
+                Ok(byte) => byte,
+                Err(err) => return Some(Err(err)),
+            };
+            if byte == b'>' {
+                self.state = State::HeaderChrom;
+                break;
+            }
+            if byte.is_ascii_alphabetic() {
+                bases.push(byte);
+            }
+        }
+        Some(Ok((String::from_utf8_lossy(&chrom).into_owned(), bases)))
+    }
+}
+
+/// Number of sequence table positions of `bases`, i.e., of A, C, G, T and N bases (other IUPAC codes are skipped)
+pub fn table_length(bases: &[u8]) -> u32 {
+    bases.iter().filter(|&&byte| match byte {
+        b'a' | b'A' | b'c' | b'C' | b'g' | b'G' | b't' | b'T' | b'n' | b'N' => true,
+        _ => false,
+    }).count() as u32
+}
+
+// store the table values of one sequence, returns the number of bases read
+fn process_sequence<R1: Read, R2: BufRead, T: EnzContext, S: SeqStore>(store: S, iter: &mut Bytes<R1>, enzctxt: &mut T, params: &SeqTableParams, unmap: &UnMap<R2>, softmask: SoftMask) -> io::Result<u32> {
+    let mut buf = SeqBuffer::new(store, params, unmap);
     let mut seqpos = 0u32;
+    // soft-masked flags of the current k-mer, used to find soft-masked cut-sites
+    let kmer_length = params.kmer_length as usize;
+    let mut soft: VecDeque<bool> = VecDeque::with_capacity(kmer_length);
+    let plus_cut = params.plus_offset as usize;
+    let minus_cut = (kmer_length - 1).checked_sub(params.minus_offset as usize);
+
+    while let Some(res) = iter.next() {
+        let byte = try!(res);
+        let base = match byte {
+            b'a' | b'A' => DNABases::A as u8,
+            b'c' | b'C' => DNABases::C as u8,
+            b'g' | b'G' => DNABases::G as u8,
+            b't' | b'T' => DNABases::T as u8,
+            b'n' | b'N' => DNABases::N as u8,
+            _ => continue,
+        };
+        let lowercase = byte >= b'a';
+        seqpos += 1;
 
-    while let Some(Ok(byte)) = iter.next() {
-        match byte {
-            b'>' => {
-                println!("# - {} bases", seqpos + 1);                
-                return State::HeaderChrom;
+        match softmask {
+            SoftMask::Ignore => store_index!(enzctxt.add_base(base), buf),
+            SoftMask::Unmappable => store_index!(enzctxt.add_base(if lowercase { DNABases::N as u8 } else { base }), buf),
+            SoftMask::CutSite => {
+                let mut index = enzctxt.add_base(base);
+                if soft.len() == kmer_length { soft.pop_front(); }
+                soft.push_back(lowercase);
+                if soft.len() == kmer_length {
+                    if soft.get(plus_cut) == Some(&true) {
+                        index.plus = None;
+                    }
+                    if minus_cut.and_then(|pos| soft.get(pos)) == Some(&true) {
+                        index.minus = None;
+                    }
+                }
+                store_index!(index, buf)
             },
-            b'a' | b'A' => { store_base!(enzctxt, buf, DNABases::A as u8); seqpos += 1; },
-            b'c' | b'C' => { store_base!(enzctxt, buf, DNABases::C as u8); seqpos += 1; },
-            b'g' | b'G' => { store_base!(enzctxt, buf, DNABases::G as u8); seqpos += 1; },
-            b't' | b'T' => { store_base!(enzctxt, buf, DNABases::T as u8); seqpos += 1; },
-            b'n' | b'N' => { store_base!(enzctxt, buf, DNABases::N as u8); seqpos += 1; },
-            _ => {},
         }
     }
-    println!("# - {} bases", seqpos + 1);
-    State::End
+    Ok(seqpos)
 }
 
 pub fn reverse_complement(mer: u32, kmersize: u8) -> u32 {
@@ -74,101 +190,261 @@ pub fn reverse_complement(mer: u32, kmersize: u8) -> u32 {
 }
 
 /// Read FASTA file and produce SeqTable file
-fn generate_seqtable_ctxt<R1: Read, R2: BufRead, T: EnzContext>(fasta: R1, tallymer: R2, params: &SeqTableParams, mut enzctxt: T, outfile: &str) {
+fn generate_seqtable_ctxt<R1: Read, R2: BufRead, T: EnzContext>(fasta: R1, tallymer: R2, params: &SeqTableParams, softmask: SoftMask, mut enzctxt: T, outfile: &str) -> io::Result<()> {
 	let mut unmap = UnMap::open(tallymer).ok().expect("Load mappability information");
-    let mut iter = fasta.bytes();
-    let mut state = State::HeaderStart;
-    let mut chrom: Vec<u8> = Vec::new();
     
     let f_out = File::create(outfile).ok().expect("create file");
-    let mut output = SeqTableWriter::new(f_out, params, 3200000).ok().expect("create store");
+    let mut output = SeqTableWriter::new(f_out, params, softmask, 3200000).ok().expect("create store");
     
-    while let Some(Ok(byte)) = iter.next() {
-        match state {
-            State::HeaderStart => if byte == b'>' {
-                    chrom.clear();
-                    state = State::HeaderChrom;
-                    enzctxt.sequence_change();
-                } else {
-                    println!("Invalid FASTA file. {}", byte as char);
-                    exit(1);
-                },
-            State::HeaderChrom =>
-                if byte == b' ' {
-                    state = State::Header;
-                } else if byte == b'\n' {
-                    let seqwrt = output.create_sequence(String::from_utf8_lossy(&chrom).into_owned());
-                    println!("# chrom: {:?}", String::from_utf8_lossy(&chrom)); 
-                    state = process_sequence(seqwrt, &mut iter, &mut enzctxt, params, &mut unmap);
-                    
-                    // after processing sequence
-                    if state == State::HeaderChrom {
-                        chrom.clear();
-                        enzctxt.sequence_change();
-                        unmap.read_next_sequence().ok().expect("failed to read tallymer data");
-                    }
-                } else {
-                    chrom.push(byte);
-                },
-            State::Header => if byte == b'\n' {
-                    let seqwrt = output.create_sequence(String::from_utf8_lossy(&chrom).into_owned());
-                    println!("# chrom: {:?}", String::from_utf8_lossy(&chrom)); 
-                    state = process_sequence(seqwrt, &mut iter, &mut enzctxt, params, &mut unmap);
-                    
-                    // after processing sequence
-                    if state == State::HeaderChrom {
-                        chrom.clear();
-                        enzctxt.sequence_change();
-                        unmap.read_next_sequence().ok().expect("failed to read tallymer data");
-                    }
-                },
-            State::End => {},
+    for (seqnumber, res) in FastaSequences::new(fasta).enumerate() {
+        let (chrom, bases) = try!(res);
+        if seqnumber > 0 {
+            unmap.read_next_sequence().ok().expect("failed to read tallymer data");
+        }
+        enzctxt.sequence_change();
+
+        println!("# chrom: {:?}", chrom);
+        let seqwrt = output.create_sequence(chrom);
+        let length = try!(process_sequence(seqwrt, &mut Cursor::new(bases).bytes(), &mut enzctxt, params, &unmap, softmask));
+        println!("# - {} bases", length + 1);
+    }
+    Ok(())
+}
+
+// evaluate $body with $ctxt bound to the enzyme context matching the k-mer mask
+macro_rules! with_enzyme_context {
+    ($params:expr, $ctxt:ident => $body:expr) => {
+        match $params.mask {
+            Some(_) if $params.has_class_positions() => { let $ctxt = EnzContextClassMasked::new($params); $body },
+            Some(_) => if $params.strand_specific {
+                let $ctxt = EnzContextMaskedStrandSpecific::new($params); $body
+            } else {
+                let $ctxt = EnzContextMasked::new($params); $body
+            },
+            None => { let $ctxt = EnzContextSimple::new($params.kmer_length, $params.strand_specific); $body },
+        }
+    };
+}
+
+/// Read FASTA file and produce SeqTable file, failing on read errors (e.g., truncated compressed input)
+pub fn generate_seqtable<R1: Read, R2: BufRead>(fasta: R1, tallymer: R2, params: &SeqTableParams, softmask: SoftMask, outfile: &str) -> io::Result<()> {
+    with_enzyme_context!(params, enzctxt => generate_seqtable_ctxt(fasta, tallymer, params, softmask, enzctxt, outfile))
+}
+
+/// Produce SeqTable file for the sequences `chroms` of a genome file, given as (file index, name) pairs in
+/// file order. Mappability information is looked up by file index.
+fn generate_seqtable_subset_ctxt<R: BufRead, T: EnzContext>(genome: &mut GenomeSequences, chroms: &Vec<(u32, String)>, tallymer: R, params: &SeqTableParams, softmask: SoftMask, mut enzctxt: T, outfile: &str) -> io::Result<()> {
+    let mut unmap = UnMap::open(tallymer).ok().expect("Load mappability information");
+
+    let f_out = File::create(outfile).ok().expect("create file");
+    let mut output = SeqTableWriter::new(f_out, params, softmask, 3200000).ok().expect("create store");
+
+    for &(seqnumber, ref chrom) in chroms {
+        let bases = match genome.fetch(chrom) {
+            Ok(bases) => bases,
+            Err(err) => {
+                println!("Error: {}", err);
+                exit(1);
+            },
         };
+        unmap.seek_sequence(seqnumber).ok().expect("failed to read tallymer data");
+        enzctxt.sequence_change();
+
+        let seqwrt = output.create_sequence(chrom.clone());
+        println!("# chrom: {:?}", chrom);
+        let length = try!(process_sequence(seqwrt, &mut Cursor::new(bases).bytes(), &mut enzctxt, params, &unmap, softmask));
+        println!("# - {} bases", length + 1);
     }
+    Ok(())
 }
 
-pub fn generate_seqtable<R1: Read, R2: BufRead>(fasta: R1, tallymer: R2, params: &SeqTableParams, outfile: &str) {
-    match params.mask {
-        Some(_) => if params.strand_specific {
-            generate_seqtable_ctxt(fasta, tallymer, params, EnzContextMaskedStrandSpecific::new(params), outfile)
-        } else {
-            generate_seqtable_ctxt(fasta, tallymer, params, EnzContextMasked::new(params), outfile)
+// SeqStore keeping the values at selected positions (sorted) while counting the k-mers of all positions
+struct PositionStore<'a> {
+    positions: &'a [u32],
+    next: usize,
+    position: u32,
+    values: &'a mut Vec<(u64, u64)>,
+    counts: &'a mut NmerCounts,
+}
+
+impl<'a> SeqStore for PositionStore<'a> {
+    fn write(&mut self, plus: u64, minus: u64) {
+        self.counts[plus].0 += 1;
+        self.counts[minus].1 += 1;
+        while self.next < self.positions.len() && self.positions[self.next] == self.position {
+            self.values.push((plus, minus));
+            self.next += 1;
+        }
+        self.position += 1;
+    }
+}
+
+fn index_positions_ctxt<R: BufRead, T: EnzContext>(sequences: &Vec<(String, Vec<u8>)>, tallymer: R, params: &SeqTableParams, softmask: SoftMask, positions: &Vec<Vec<u32>>, mut enzctxt: T) -> io::Result<(Vec<Vec<(u64, u64)>>, NmerCounts)> {
+    let mut unmap = try!(UnMap::open(tallymer));
+    let mut counts = NmerCounts::new(params.nmer_count());
+    let mut values = Vec::with_capacity(sequences.len());
+
+    for (seqnumber, &(_, ref bases)) in sequences.iter().enumerate() {
+        try!(unmap.seek_sequence(seqnumber as u32));
+        enzctxt.sequence_change();
+
+        let mut seq_values = Vec::with_capacity(positions[seqnumber].len());
+        {
+            let store = PositionStore { positions: &positions[seqnumber], next: 0, position: 0, values: &mut seq_values, counts: &mut counts };
+            try!(process_sequence(store, &mut Cursor::new(bases).bytes(), &mut enzctxt, params, &unmap, softmask));
+        }
+        values.push(seq_values);
+    }
+    Ok((values, counts))
+}
+
+/// Sequence table values at `positions` (sorted, below `table_length`) of each of the in-memory `sequences`,
+/// along with the genome k-mer counts, without writing the table
+pub fn index_positions(sequences: &Vec<(String, Vec<u8>)>, tallymer_path: &OsStr, params: &SeqTableParams, softmask: SoftMask, positions: &Vec<Vec<u32>>) -> (Vec<Vec<(u64, u64)>>, NmerCounts) {
+    let res = with_enzyme_context!(params, enzctxt => index_positions_ctxt(sequences, open_tallymer(tallymer_path), params, softmask, positions, enzctxt));
+    match res {
+        Ok(result) => result,
+        Err(err) => {
+            println!("Error: Failed to read tallymer file '{}': {}", tallymer_path.to_string_lossy(), err);
+            exit(1);
         },
-        None => generate_seqtable_ctxt(fasta, tallymer, params, EnzContextSimple::new(params.kmer_length, params.strand_specific), outfile),
     }
 }
 
-pub fn process_fasta(fasta_path: &str, tallymer_path: &OsStr, params: &SeqTableParams, outfile: &str) {
-    let f_fasta = File::open(fasta_path).ok().expect("Can't open FASTA file.");
-    let f_tallymer = File::open(tallymer_path).ok().expect("Can't open Tallymer file.");
-    
-    match GzDecoder::new(f_fasta) {
-        Ok(reader_fasta) => {
-            match GzDecoder::new(f_tallymer) {
-                Ok(reader_tallymer) => generate_seqtable(reader_fasta, BufReader::new(reader_tallymer), params, outfile),
-                Err(_) => {
-                    // re-open file
-                    let f_tallymer = File::open(tallymer_path).ok().expect("Can't open Tallymer file.");
-                    let reader_tallymer = BufReader::new(f_tallymer);
-                    
-                    generate_seqtable(reader_fasta, reader_tallymer, params, outfile);
-                },
-            }
+/// Reader over the decompressed content of `reader`
+///
+/// gzip compression, including multi-member files such as BGZF, is detected by its magic bytes.
+pub fn decompress<R: Read + 'static>(reader: R) -> io::Result<Box<Read>> {
+    let mut reader = BufReader::new(reader);
+    let gzipped = try!(reader.fill_buf()).starts_with(&GZIP_MAGIC);
+    if gzipped {
+        Ok(Box::new(try!(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// decompressed reader of a FASTA or tallymer file
+fn open_decompressed(path: &OsStr, what: &str) -> Box<Read> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("Error: Can't open {} file '{}': {}", what, path.to_string_lossy(), err);
+            exit(1);
+        },
+    };
+    match decompress(file) {
+        Ok(reader) => reader,
+        Err(err) => {
+            println!("Error: Failed to read {} file '{}': {}", what, path.to_string_lossy(), err);
+            exit(1);
+        },
+    }
+}
+
+fn open_tallymer(tallymer_path: &OsStr) -> BufReader<Box<Read>> {
+    BufReader::new(open_decompressed(tallymer_path, "Tallymer"))
+}
+
+// .2bit genome, read as FASTA
+fn open_twobit(twobit_path: &str) -> TwoBitFile<BufReader<File>> {
+    match TwoBitFile::open(twobit_path) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("Error: Failed to read .2bit file '{}': {}", twobit_path, err);
+            exit(1);
         },
-        Err(_) => {
-            // re-open file
-            let f_fasta = File::open(fasta_path).ok().expect("Can't open FASTA file.");
-            let reader_fasta = BufReader::new(f_fasta);
-            match GzDecoder::new(f_tallymer) {
-                Ok(reader_tallymer) => generate_seqtable(reader_fasta, BufReader::new(reader_tallymer), params, outfile),
-                Err(_) => {
-                    // re-open file
-                    let f_tallymer = File::open(tallymer_path).ok().expect("Can't open Tallymer file.");
-                    let reader_tallymer = BufReader::new(f_tallymer);
-                    
-                    generate_seqtable(reader_fasta, reader_tallymer, params, outfile);
+    }
+}
+
+// FASTA (plain or gzip compressed) or .2bit genome, read as FASTA
+fn open_genome(fasta_path: &str) -> Box<Read> {
+    if is_twobit_file(fasta_path) {
+        Box::new(open_twobit(fasta_path).fasta())
+    } else {
+        open_decompressed(fasta_path.as_ref(), "FASTA")
+    }
+}
+
+/// Sequences of a FASTA (plain or gzip compressed) or .2bit genome file, one at a time
+pub fn fasta_sequences(fasta_path: &str) -> FastaSequences<Box<Read>> {
+    FastaSequences::new(open_genome(fasta_path))
+}
+
+pub fn process_fasta(fasta_path: &str, tallymer_path: &OsStr, params: &SeqTableParams, softmask: SoftMask, outfile: &str) {
+    if let Err(err) = generate_seqtable(open_genome(fasta_path), open_tallymer(tallymer_path), params, softmask, outfile) {
+        // don't leave a partial table behind, it would be reused by later runs
+        println!("Error: Failed to read '{}': {}", fasta_path, err);
+        let _ = fs::remove_file(outfile);
+        exit(1);
+    }
+}
+
+/// Sequence names of a FASTA (plain or gzip compressed) or .2bit genome file, in file order
+///
+/// Names come from the .2bit or FASTA index when available, otherwise from the FASTA header lines.
+pub fn genome_sequence_names(fasta_path: &str) -> Vec<String> {
+    if is_twobit_file(fasta_path) || Path::new(&format!("{}.fai", fasta_path)).exists() {
+        return open_genome_sequences(fasta_path).sequence_names();
+    }
+    let mut reader = BufReader::new(open_genome(fasta_path));
+    let mut names = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return names,
+            Ok(_) => if line.first() == Some(&b'>') {
+                    // the name ends at the first whitespace (or the \r of CRLF line endings)
+                    let name: Vec<u8> = line[1..].iter().cloned().take_while(|&byte| byte != b' ' && byte != b'\t' && byte != b'\r' && byte != b'\n').collect();
+                    names.push(String::from_utf8_lossy(&name).into_owned());
                 },
-            }
+            Err(err) => {
+                println!("Error: Failed to read '{}': {}", fasta_path, err);
+                exit(1);
+            },
+        }
+    }
+}
+
+/// Random access to the sequences of an indexed FASTA file (the index is created if missing) or a .2bit file
+pub fn open_genome_sequences(fasta_path: &str) -> Box<GenomeSequences> {
+    let opened: Result<Box<GenomeSequences>, String> = if is_twobit_file(fasta_path) {
+        TwoBitFile::open(fasta_path).map(|file| Box::new(file) as Box<GenomeSequences>).map_err(|err| format!("Failed to read .2bit file '{}': {}", fasta_path, err))
+    } else {
+        FastaIndex::open(fasta_path).map(|index| Box::new(index) as Box<GenomeSequences>)
+    };
+    match opened {
+        Ok(genome) => genome,
+        Err(err) => {
+            println!("Error: {}", err);
+            exit(1);
         },
     }
-}
\ No newline at end of file
+}
+
+/// Produce SeqTable file for the sequences `chroms` only, reading them through the FASTA index (or from
+/// the .2bit file)
+///
+/// Sequences are written in FASTA file order, whatever their order in `chroms`.
+pub fn process_fasta_subset(fasta_path: &str, tallymer_path: &OsStr, params: &SeqTableParams, softmask: SoftMask, chroms: &Vec<String>, outfile: &str) {
+    let mut genome = open_genome_sequences(fasta_path);
+    let names = genome.sequence_names();
+    for chrom in chroms {
+        if !names.contains(chrom) {
+            println!("Error: Sequence '{}' not found in FASTA file '{}'.", chrom, fasta_path);
+            exit(1);
+        }
+    }
+    let selected: Vec<(u32, String)> = names.into_iter().enumerate()
+        .filter(|&(_, ref name)| chroms.contains(name))
+        .map(|(idx, name)| (idx as u32, name))
+        .collect();
+
+    let res = with_enzyme_context!(params, enzctxt => generate_seqtable_subset_ctxt(&mut *genome, &selected, open_tallymer(tallymer_path), params, softmask, enzctxt, outfile));
+    if let Err(err) = res {
+        println!("Error: Failed to read '{}': {}", fasta_path, err);
+        let _ = fs::remove_file(outfile);
+        exit(1);
+    }
+}
@@ -0,0 +1,119 @@
+//!
+//! Class Masked Enzyme Context - kmer positions can be reduced to two-letter base classes (R/Y, S/W, K/M)
+//!
+
+use std::collections::VecDeque;
+use super::{EnzContext, KmerIndex};
+use seqtable::{SeqTableParams, MaskPos};
+
+// Mixed radix version: included bases contribute 4 symbols, base classes 2
+pub struct EnzContextClassMasked {
+    kmer_length: usize,
+    buf: VecDeque<u8>,
+    mask: Vec<MaskPos>,
+    strand_specific: bool,
+}
+
+impl EnzContextClassMasked {
+    pub fn new(params: &SeqTableParams) -> EnzContextClassMasked {
+        EnzContextClassMasked {
+            kmer_length: params.kmer_length as usize,
+            buf: VecDeque::with_capacity(params.kmer_length as usize),
+            mask: params.mask.as_ref().unwrap().clone(),
+            strand_specific: params.strand_specific,
+        }
+    }
+
+    fn add_symbol(idx: u64, pos: MaskPos, base: u8) -> Option<u64> {
+        match pos {
+            MaskPos::Excluded => Some(idx),
+            _ if base == 4 => None, // N in a non-masked position
+            MaskPos::Base => Some(idx * 4 + base as u64),
+            MaskPos::Class(class) => Some(idx * 2 + class.symbol(base) as u64),
+        }
+    }
+
+    fn plus_index(&self) -> Option<u64> {
+        let mut idx = 0;
+        for (&base, &pos) in self.buf.iter().zip(&self.mask) {
+            idx = match EnzContextClassMasked::add_symbol(idx, pos, base) {
+                Some(value) => value,
+                None => return None,
+            };
+        }
+        Some(idx)
+    }
+
+    // mask applied to the reverse complement
+    fn minus_index(&self) -> Option<u64> {
+        let mut idx = 0;
+        for (&base, &pos) in self.buf.iter().rev().zip(&self.mask) {
+            let comp = if base == 4 { 4 } else { 3 - base };
+            idx = match EnzContextClassMasked::add_symbol(idx, pos, comp) {
+                Some(value) => value,
+                None => return None,
+            };
+        }
+        Some(idx)
+    }
+
+    fn nmer_index(&self) -> KmerIndex {
+        let plus = self.plus_index();
+        let minus = if self.strand_specific { self.minus_index() } else { plus };
+        KmerIndex { plus: plus, minus: minus }
+    }
+}
+
+impl EnzContext for EnzContextClassMasked {
+    fn sequence_change(&mut self) {
+        self.buf.clear();
+    }
+
+    fn add_base(&mut self, base: u8) -> KmerIndex {
+        if self.buf.len() == self.kmer_length { self.buf.pop_front(); }
+        self.buf.push_back(base);
+        if self.buf.len() == self.kmer_length {
+            self.nmer_index()
+        } else {
+            KmerIndex{ plus: None, minus: None }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use fasta::DNABases;
+    use super::*;
+
+    fn add_bases(ctxt: &mut EnzContextClassMasked, bases: Vec<DNABases>) -> KmerIndex {
+        let mut res = KmerIndex { plus: None, minus: None };
+        for base in bases {
+            res = ctxt.add_base(base as u8);
+        }
+        res
+    }
+
+    #[test]
+    fn class_positions_contribute_binary_symbols() {
+        // R N: purine/pyrimidine (2) x base (4)
+        let params = SeqTableParams::new(0, 0, 0, 0, &Some("RXN".to_string()), false);
+        assert_eq!(9, params.nmer_count());
+
+        let mut ctxt = EnzContextClassMasked::new(&params);
+        // G (purine = 0), T = 3
+        assert_eq!(KmerIndex { plus: Some(3), minus: Some(3) }, add_bases(&mut ctxt, vec![DNABases::G, DNABases::N, DNABases::T]));
+        // C (pyrimidine = 1), A = 0
+        assert_eq!(KmerIndex { plus: Some(4), minus: Some(4) }, add_bases(&mut ctxt, vec![DNABases::C, DNABases::A, DNABases::A]));
+        assert_eq!(KmerIndex { plus: None, minus: None }, add_bases(&mut ctxt, vec![DNABases::N, DNABases::A, DNABases::A]));
+    }
+
+    #[test]
+    fn strand_specific_reverse_complement_classes() {
+        // S W: both classes map to themselves under complement, K/M is flipped
+        let params = SeqTableParams::new(0, 0, 0, 0, &Some("SCK".to_string()), true);
+        let mut ctxt = EnzContextClassMasked::new(&params);
+        // plus AG: A weak (1), G keto (0) -> 2; minus CT: C strong (0), T keto (0) -> 0
+        assert_eq!(KmerIndex { plus: Some(2), minus: Some(0) }, add_bases(&mut ctxt, vec![DNABases::A, DNABases::G]));
+    }
+}
@@ -4,7 +4,7 @@
 
 use std::collections::VecDeque;
 use super::{EnzContext, KmerIndex};
-use seqtable::SeqTableParams;
+use seqtable::{SeqTableParams, MaskPos};
 
 // Masked version
 pub struct EnzContextMasked {
@@ -20,7 +20,7 @@ impl EnzContextMasked {
             kmer_length: params.kmer_length as usize,
             unmasked_count: params.unmasked_count as u32,
             buf: VecDeque::with_capacity(params.unmasked_count as usize),
-            mask: params.mask.as_ref().unwrap().clone(),
+            mask: params.mask.as_ref().unwrap().iter().map(|&pos| pos == MaskPos::Base).collect(),
         }
     }
 
@@ -36,7 +36,7 @@ impl EnzContextMasked {
                 mult = mult / 4;
             }
         }
-        KmerIndex{ plus: Some( idx as u32 ), minus: Some( idx as u32 ) }
+        KmerIndex{ plus: Some( idx ), minus: Some( idx ) }
     }
 }
 
@@ -4,7 +4,7 @@
 
 use std::collections::VecDeque;
 use super::{EnzContext, KmerIndex};
-use seqtable::SeqTableParams;
+use seqtable::{SeqTableParams, MaskPos};
 
 // Strand Specific Masked version
 pub struct EnzContextMaskedStrandSpecific {
@@ -17,13 +17,14 @@ pub struct EnzContextMaskedStrandSpecific {
 
 impl EnzContextMaskedStrandSpecific {
     pub fn new(params: &SeqTableParams) -> EnzContextMaskedStrandSpecific {
-        let mut rev_mask = params.mask.as_ref().unwrap().clone();
+        let mask: Vec<bool> = params.mask.as_ref().unwrap().iter().map(|&pos| pos == MaskPos::Base).collect();
+        let mut rev_mask = mask.clone();
         rev_mask.reverse();
         EnzContextMaskedStrandSpecific {
             kmer_length: params.kmer_length as usize,
             unmasked_count: params.unmasked_count as u32,
             buf: VecDeque::with_capacity(params.unmasked_count as usize),
-            mask_plus: params.mask.as_ref().unwrap().clone(),
+            mask_plus: mask,
             mask_minus: rev_mask,
         }
     }
@@ -55,8 +56,8 @@ impl EnzContextMaskedStrandSpecific {
             }
         }
         KmerIndex {
-            plus: if plus_is_some { Some(idx_plus as u32) } else { None },
-            minus: if minus_is_some { Some(idx_minus as u32) } else { None }
+            plus: if plus_is_some { Some(idx_plus) } else { None },
+            minus: if minus_is_some { Some(idx_minus) } else { None }
         }
     }
 }
@@ -89,14 +90,14 @@ mod tests {
     // AG     CG     GG     TG
     // AT     CT     GT     TT 15
 
-    fn kmer_value(bases: Vec<DNABases>) -> Option<u32> {
+    fn kmer_value(bases: Vec<DNABases>) -> Option<u64> {
         let mut res = 0;
         for base in bases {
             if base == DNABases::N {
                 return None;
             }
             res *= 4;
-            res += base as u32;
+            res += base as u64;
         }
         Some(res)
     }
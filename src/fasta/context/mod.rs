@@ -5,18 +5,20 @@
 mod simple;
 mod masked;
 mod masked_stranded;
+mod class_masked;
 
 pub use self::simple::EnzContextSimple;
 pub use self::masked::EnzContextMasked;
 pub use self::masked_stranded::EnzContextMaskedStrandSpecific;
+pub use self::class_masked::EnzContextClassMasked;
 
 /// Observed kmer on the plus and minus strands
 /// Unless the strand-specific flag is active, both values are equal
 /// and match the plus strand readout.
 #[derive(Eq,PartialEq,Debug)]
 pub struct KmerIndex {
-    pub plus: Option<u32>,
-    pub minus: Option<u32>
+    pub plus: Option<u64>,
+    pub minus: Option<u64>
 }
 
 /// Holds information on the current enzyme cut region
@@ -75,9 +75,9 @@ impl EnzContext for EnzContextSimple {
     fn add_base(&mut self, base: u8) -> KmerIndex {
         if self.update_cut_dna_value(base) {
             if self.revcomp_minus {
-                KmerIndex { plus: Some(self.cut_dna_value as u32), minus: Some(self.rev_dna_value as u32) }
+                KmerIndex { plus: Some(self.cut_dna_value), minus: Some(self.rev_dna_value) }
             } else {
-                KmerIndex { plus: Some(self.cut_dna_value as u32), minus: Some(self.cut_dna_value as u32) }
+                KmerIndex { plus: Some(self.cut_dna_value), minus: Some(self.cut_dna_value) }
             }
         } else {
             KmerIndex{ plus: None, minus: None }
@@ -96,14 +96,14 @@ mod tests {
     // AG     CG     GG     TG
     // AT     CT     GT     TT 15
 
-    fn kmer_value(bases: Vec<DNABases>) -> Option<u32> {
+    fn kmer_value(bases: Vec<DNABases>) -> Option<u64> {
         let mut res = 0;
         for base in bases {
             if base == DNABases::N {
                 return None;
             }
             res *= 4;
-            res += base as u32;
+            res += base as u64;
         }
         Some(res)
     }
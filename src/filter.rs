@@ -1,8 +1,116 @@
-use htslib::bam::record::Record;
+use htslib::bam::record::{Record, Cigar, Aux};
+
+/// SAM flags excluded by default: secondary (0x100), QC-fail (0x200) and supplementary (0x800)
+pub const DEFAULT_EXCLUDE_FLAGS: u16 = 0x100 | 0x200 | 0x800;
+/// SAM flag for PCR or optical duplicates
+pub const DUPLICATE_FLAG: u16 = 0x400;
+
+/// samtools-style filter on SAM flags
+///
+/// Records must have all `require` bits set and none of the `exclude` bits.
+#[derive(Copy, Clone, Debug)]
+pub struct FlagFilter {
+    pub require: u16,
+    pub exclude: u16,
+}
+
+impl FlagFilter {
+    /// Filter from command line style options: `exclude` replaces the default exclusions and
+    /// `exclude_duplicates` adds the duplicate flag to them
+    pub fn from_options(require: Option<u16>, exclude: Option<u16>, exclude_duplicates: bool) -> FlagFilter {
+        let mut flags = FlagFilter::default();
+        if let Some(require) = require {
+            flags.require = require;
+        }
+        if let Some(exclude) = exclude {
+            flags.exclude = exclude;
+        }
+        if exclude_duplicates {
+            flags.exclude |= DUPLICATE_FLAG;
+        }
+        flags
+    }
+
+    pub fn accept(&self, rec: &Record) -> bool {
+        let flags = rec.flags();
+        flags & self.require == self.require && flags & self.exclude == 0
+    }
+}
+
+impl Default for FlagFilter {
+    fn default() -> FlagFilter {
+        FlagFilter { require: 0, exclude: DEFAULT_EXCLUDE_FLAGS }
+    }
+}
+
+/// Parse SAM flag value, either in decimal or hexadecimal (0x prefix) notation
+pub fn parse_flags(value: &str) -> Result<u16, String> {
+    let res = if value.starts_with("0x") || value.starts_with("0X") {
+        u16::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u16>()
+    };
+    res.map_err(|_| format!("Invalid SAM flags value: {}", value))
+}
+
+/// Default Tn5 insertion shifts applied to the plus and minus strand ends of ATAC-seq fragments
+pub const TN5_SHIFT: (i32, i32) = (4, -5);
+
+/// Cut-site derived from an aligned record: reference position of the counted edge and strand
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CutSite {
+    pub pos: i32,
+    pub reverse: bool,
+}
+
+impl CutSite {
+    /// Position used to index the SeqTable
+    ///
+    /// The table stores k-mers by the start of a virtual read of the configured read length, so that
+    /// mappability is evaluated for that length whatever the length of the actual read.
+    /// For the minus strand the virtual read ends at the cut-site.
+    pub fn table_pos(&self, read_length: usize) -> i32 {
+        if self.reverse {
+            self.pos - (read_length as i32) + 1
+        } else {
+            self.pos
+        }
+    }
+}
+
+/// Iterator over the (at most two) cut-sites derived from a single record
+pub struct CutSites {
+    sites: [CutSite; 2],
+    len: usize,
+    idx: usize,
+}
+
+impl CutSites {
+    pub fn one(site: CutSite) -> CutSites {
+        CutSites { sites: [site, site], len: 1, idx: 0 }
+    }
+
+    pub fn two(first: CutSite, second: CutSite) -> CutSites {
+        CutSites { sites: [first, second], len: 2, idx: 0 }
+    }
+}
+
+impl Iterator for CutSites {
+    type Item = CutSite;
+
+    fn next(&mut self) -> Option<CutSite> {
+        if self.idx >= self.len {
+            return None;
+        }
+        self.idx += 1;
+        Some(self.sites[self.idx - 1])
+    }
+}
 
 pub trait RecordCheck {
     fn valid(&self, rec: &Record) -> bool;
-    fn vir_pos(&self, rec: &Record) -> i32;
+    /// Cut-sites contributed by a valid record
+    fn cut_sites(&self, rec: &Record) -> CutSites;
 }
 
 pub struct SingleChecker {
@@ -10,57 +118,124 @@ pub struct SingleChecker {
     pub exact_length: bool,
     pub read_length: usize,
     pub min_quality: u8,
+    pub flags: FlagFilter,
+    pub skip_soft_clipped: bool,
 }
 
-fn vir_pos_common(read_length: usize, tail_edge: bool, exact_length: bool, rec: &Record) -> i32 {
-    if tail_edge {
-        // instead of right edge of read (5') report left edge (3')
-        if rec.is_reverse() {
-            rec.pos() - (read_length as i32) + 1
-        } else {
-            rec.pos() + (rec.seq().len() as i32) - 1
-        }
+/// Reference coordinates of the first and last aligned bases of a read
+///
+/// The span is derived from the CIGAR, so soft clips and insertions do not contribute to it
+/// while deletions and skipped regions (N) do.
+pub fn aligned_span(rec: &Record) -> (i32, i32) {
+    let end = if rec.inner().core.n_cigar == 0 {
+        rec.pos() + rec.seq().len() as i32
     } else {
-        if !exact_length && rec.is_reverse() {
-            rec.pos() + (rec.seq().len() as i32) - (read_length as i32)
-        } else {
-            rec.pos()
+        rec.cigar().end_pos()
+    };
+    (rec.pos(), end - 1)
+}
+
+/// Check if the read edge used for counting (5' end, or 3' end if `tail_edge` is set) is soft-clipped
+pub fn edge_soft_clipped(rec: &Record, tail_edge: bool) -> bool {
+    let cigar = rec.cigar();
+    let not_hard_clip = |op: &&Cigar| match **op { Cigar::HardClip(_) => false, _ => true };
+    // the 5' end of forward reads is at the start of the CIGAR
+    let edge_op = if rec.is_reverse() == tail_edge {
+        cigar.iter().find(not_hard_clip)
+    } else {
+        cigar.iter().rev().find(not_hard_clip)
+    };
+    match edge_op {
+        Some(&Cigar::SoftClip(_)) => true,
+        _ => false,
+    }
+}
+
+/// Number of reference bases covered by a CIGAR string in text form (as stored in the MC tag)
+fn cigar_text_ref_length(cigar: &[u8]) -> Option<i32> {
+    let mut length = 0;
+    let mut op_length: i32 = 0;
+    let mut has_digits = false;
+    for &c in cigar {
+        match c {
+            _ if c >= b'0' && c <= b'9' => {
+                op_length = op_length * 10 + (c - b'0') as i32;
+                has_digits = true;
+                continue;
+            },
+            b'M' | b'D' | b'N' | b'=' | b'X' => length += op_length,
+            b'I' | b'S' | b'H' | b'P' => {},
+            _ => return None,
         }
+        if !has_digits {
+            return None;
+        }
+        op_length = 0;
+        has_digits = false;
     }
+    if has_digits { None } else { Some(length) }
+}
+
+/// Fragment length of a paired read
+///
+/// When the mate CIGAR is available (MC tag) the fragment spans from the leftmost aligned base
+/// of either mate to the rightmost one, otherwise the absolute TLEN is used.
+/// Returns None if the mate is unmapped, on a different sequence, or no length is available.
+pub fn fragment_length(rec: &Record) -> Option<i32> {
+    if !rec.is_paired() || rec.is_unmapped() || rec.is_mate_unmapped() || rec.tid() != rec.mtid() {
+        return None;
+    }
+    if let Some(Aux::String(mate_cigar)) = rec.aux(b"MC") {
+        if let Some(mate_length) = cigar_text_ref_length(mate_cigar) {
+            let (start, end) = aligned_span(rec);
+            let mate_end = rec.mpos() + mate_length - 1;
+            return Some(end.max(mate_end) - start.min(rec.mpos()) + 1);
+        }
+    }
+    match rec.insert_size() {
+        0 => None,
+        tlen => Some(tlen.abs()),
+    }
+}
+
+/// Counted read edge: the 5' end of the read, or the 3' end if `tail_edge` is set
+fn read_edge(tail_edge: bool, rec: &Record) -> CutSite {
+    let (start, end) = aligned_span(rec);
+    let pos = if rec.is_reverse() == tail_edge { start } else { end };
+    CutSite { pos: pos, reverse: rec.is_reverse() }
 }
 
 impl RecordCheck for SingleChecker {
     fn valid(&self, record: &Record) -> bool {
-        !record.is_unmapped() && (!self.exact_length || record.seq().len() == self.read_length) && record.mapq() >= self.min_quality
+        !record.is_unmapped() && self.flags.accept(record) && (!self.exact_length || record.seq().len() == self.read_length) && record.mapq() >= self.min_quality &&
+        !(self.skip_soft_clipped && edge_soft_clipped(record, self.tail_edge))
     }
 
-    fn vir_pos(&self, rec: &Record) -> i32 {
-        vir_pos_common(self.read_length, self.tail_edge, self.exact_length, rec)
+    fn cut_sites(&self, rec: &Record) -> CutSites {
+        CutSites::one(read_edge(self.tail_edge, rec))
     }
 }
 
-#[derive(Copy, Clone)]
-pub enum PairPosition {
-    First,
-    Last
-}
-
 pub struct PairedChecker {
     pub tail_edge: bool,
     pub exact_length: bool,
     pub read_length: usize,
     pub min_quality: u8,
+    pub flags: FlagFilter,
+    pub skip_soft_clipped: bool,
     pub min_dist: i32,
     pub max_dist: i32,
     pub force_paired: bool,
-    pub max_distance: bool,
-    pub select_pair: Option<PairPosition>
+    pub max_distance: bool
 }
 
 impl RecordCheck for PairedChecker {
     fn valid(&self, record: &Record) -> bool {
         // check single read conditions
-        if record.is_unmapped() || (self.exact_length && record.seq().len() != self.read_length) || record.mapq() < self.min_quality {
+        if record.is_unmapped() || !self.flags.accept(record) || (self.exact_length && record.seq().len() != self.read_length) || record.mapq() < self.min_quality {
+            return false;
+        }
+        if self.skip_soft_clipped && edge_soft_clipped(record, self.tail_edge) {
             return false;
         }
         // mandatory paired condition
@@ -69,33 +244,256 @@ impl RecordCheck for PairedChecker {
         }
         // check pair distance 
         if record.is_paired() && self.max_distance {
-            let dist = (record.pos() - record.mpos()).abs() + self.read_length as i32; // TODO FIX!
-            if dist < self.min_dist || dist > self.max_dist {
-                return false;
-            }
-        }
-        // filter for specific pair in paired reads
-        match self.select_pair {
-            Some( side ) => {
-                match side {
-                    PairPosition::First => {
-                        if !record.is_first_in_template() {
-                            return false;
-                        }
-                    }
-                    PairPosition::Last => {
-                        if !record.is_last_in_template() {
-                            return false;
-                        }
-                    }
-                }
+            match fragment_length(record) {
+                Some(dist) => if dist < self.min_dist || dist > self.max_dist {
+                    return false;
+                },
+                None => return false,
             }
-            _ => {}
         }
         true
     }
 
-    fn vir_pos(&self, rec: &Record) -> i32 {
-        vir_pos_common(self.read_length, self.tail_edge, self.exact_length, rec)
+    fn cut_sites(&self, rec: &Record) -> CutSites {
+        CutSites::one(read_edge(self.tail_edge, rec))
+    }
+}
+
+/// Checker for paired-end fragments whose two ends are both insertion events (e.g. Tn5 in ATAC-seq)
+///
+/// Each mate of a properly paired fragment contributes its own insertion site: the leftmost mate
+/// (first in template on ties) a plus strand site at the fragment start and the other mate a minus
+/// strand site at the fragment end, each shifted before the k-mer lookup. Mates are filtered
+/// independently, so a filtered mate only removes its own site and each site is counted once.
+pub struct FragmentChecker {
+    pub exact_length: bool,
+    pub read_length: usize,
+    pub min_quality: u8,
+    pub flags: FlagFilter,
+    pub min_dist: i32,
+    pub max_dist: i32,
+    pub max_distance: bool,
+    pub plus_shift: i32,
+    pub minus_shift: i32,
+}
+
+impl FragmentChecker {
+    fn is_leftmost(rec: &Record) -> bool {
+        rec.pos() < rec.mpos() || (rec.pos() == rec.mpos() && rec.is_first_in_template())
+    }
+}
+
+impl RecordCheck for FragmentChecker {
+    fn valid(&self, record: &Record) -> bool {
+        if record.is_unmapped() || !record.is_proper_pair() || !self.flags.accept(record) || record.mapq() < self.min_quality {
+            return false;
+        }
+        if self.exact_length && record.seq().len() != self.read_length {
+            return false;
+        }
+        match fragment_length(record) {
+            Some(dist) => !self.max_distance || (dist >= self.min_dist && dist <= self.max_dist),
+            None => false,
+        }
+    }
+
+    fn cut_sites(&self, rec: &Record) -> CutSites {
+        let (start, end) = aligned_span(rec);
+        if FragmentChecker::is_leftmost(rec) {
+            CutSites::one(CutSite { pos: start + self.plus_shift, reverse: false })
+        } else {
+            CutSites::one(CutSite { pos: end + self.minus_shift, reverse: true })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use htslib::bam::record::CigarString;
+
+    fn record(pos: i32, reverse: bool, cigar: Vec<Cigar>) -> Record {
+        let qlen = cigar.iter().map(|op| match *op {
+            Cigar::Match(l) | Cigar::Ins(l) | Cigar::SoftClip(l) | Cigar::Equal(l) | Cigar::Diff(l) => l as usize,
+            _ => 0,
+        }).sum();
+        let seq = vec![b'A'; qlen];
+        let qual = vec![30u8; qlen];
+        let mut rec = Record::new();
+        // qname length (with terminator) kept at a multiple of 4 so the CIGAR data stays aligned
+        rec.set(b"r01", &CigarString(cigar), &seq, &qual);
+        rec.set_pos(pos);
+        if reverse { rec.set_reverse(); }
+        rec
+    }
+
+    fn flagged(flags: u16) -> Record {
+        let mut rec = record(0, false, vec![Cigar::Match(10)]);
+        rec.set_flags(flags);
+        rec
+    }
+
+    #[test]
+    fn default_flags_exclude_secondary_qc_fail_and_supplementary() {
+        let filter = FlagFilter::default();
+        assert_eq!(0x900 | 0x200, filter.exclude);
+        assert!(filter.accept(&flagged(0)));
+        assert!(filter.accept(&flagged(0x10 | 0x1 | 0x2)));
+        assert!(!filter.accept(&flagged(0x100)));
+        assert!(!filter.accept(&flagged(0x200)));
+        assert!(!filter.accept(&flagged(0x800)));
+        // duplicates are kept unless requested
+        assert!(filter.accept(&flagged(DUPLICATE_FLAG)));
+    }
+
+    #[test]
+    fn exclude_duplicates_and_required_flags() {
+        let filter = FlagFilter::from_options(None, None, true);
+        assert_eq!(0x900 | 0x200 | 0x400, filter.exclude);
+        assert!(!filter.accept(&flagged(DUPLICATE_FLAG)));
+        assert!(!filter.accept(&flagged(0x100)));
+
+        // an explicit exclusion replaces the default, duplicates are still added
+        let filter = FlagFilter::from_options(Some(0x2), Some(0x4), true);
+        assert_eq!(0x4 | 0x400, filter.exclude);
+        assert!(filter.accept(&flagged(0x2 | 0x100)));
+        assert!(!filter.accept(&flagged(0x1)));
+        assert!(!filter.accept(&flagged(0x2 | 0x400)));
+    }
+
+    #[test]
+    fn parse_decimal_and_hexadecimal_flags() {
+        assert_eq!(Ok(2304), parse_flags("2304"));
+        assert_eq!(Ok(0x900), parse_flags("0x900"));
+        assert_eq!(Ok(0xF04), parse_flags("0XF04"));
+        assert!(parse_flags("0x1G").is_err());
+        assert!(parse_flags("70000").is_err());
+    }
+
+    fn checker(tail_edge: bool) -> SingleChecker {
+        SingleChecker { tail_edge: tail_edge, exact_length: false, read_length: 10, min_quality: 0, flags: FlagFilter::default(), skip_soft_clipped: true }
+    }
+
+    #[test]
+    fn span_ignores_soft_clips_and_insertions() {
+        let rec = record(100, false, vec![Cigar::SoftClip(3), Cigar::Match(5), Cigar::Ins(2), Cigar::Match(5), Cigar::SoftClip(4)]);
+        assert_eq!((100, 109), aligned_span(&rec));
+    }
+
+    #[test]
+    fn span_includes_deletions_and_skipped_regions() {
+        let rec = record(100, true, vec![Cigar::Match(5), Cigar::Del(2), Cigar::Match(5), Cigar::RefSkip(100), Cigar::Match(5)]);
+        assert_eq!((100, 216), aligned_span(&rec));
+    }
+
+    #[test]
+    fn reverse_read_five_prime_end_uses_reference_span() {
+        let rec = record(100, true, vec![Cigar::Match(4), Cigar::RefSkip(50), Cigar::Match(4), Cigar::SoftClip(2)]);
+        assert_eq!(157, read_edge(false, &rec).pos);
+        assert_eq!(100, read_edge(true, &rec).pos);
+        // virtual read of the configured length ending at the 5' end
+        assert_eq!(157 - 9, checker(false).cut_sites(&rec).next().unwrap().table_pos(10));
+    }
+
+    #[test]
+    fn forward_read_tail_edge_uses_reference_span() {
+        let rec = record(100, false, vec![Cigar::Match(4), Cigar::Del(3), Cigar::Match(4)]);
+        assert_eq!(100, read_edge(false, &rec).pos);
+        assert_eq!(110, read_edge(true, &rec).pos);
+        assert_eq!(110, checker(true).cut_sites(&rec).next().unwrap().table_pos(10));
+    }
+
+    fn mate_pair(pos: i32, mpos: i32, tlen: i32, cigar: Vec<Cigar>) -> Record {
+        let mut rec = record(pos, false, cigar);
+        rec.set_paired();
+        rec.set_mpos(mpos);
+        rec.set_insert_size(tlen);
+        rec
+    }
+
+    #[test]
+    fn fragment_length_from_tlen() {
+        let rec = mate_pair(100, 150, -80, vec![Cigar::Match(30)]);
+        assert_eq!(Some(80), fragment_length(&rec));
+        assert_eq!(None, fragment_length(&mate_pair(100, 150, 0, vec![Cigar::Match(30)])));
+    }
+
+    #[test]
+    fn fragment_length_prefers_mate_cigar() {
+        // dovetailed pair: mate starts before the read, read extends past mate end
+        let mut rec = mate_pair(100, 95, 999, vec![Cigar::SoftClip(5), Cigar::Match(30)]);
+        rec.push_aux(b"MC", &Aux::String(b"20M2D3M10S"));
+        assert_eq!(Some(35), fragment_length(&rec));
+
+        let mut rec = mate_pair(100, 95, 42, vec![Cigar::Match(30)]);
+        rec.push_aux(b"MC", &Aux::String(b"*"));
+        assert_eq!(Some(42), fragment_length(&rec));
+    }
+
+    fn fragment_checker() -> FragmentChecker {
+        FragmentChecker { exact_length: false, read_length: 10, min_quality: 5, flags: FlagFilter::default(), min_dist: 0, max_dist: 0, max_distance: false, plus_shift: TN5_SHIFT.0, minus_shift: TN5_SHIFT.1 }
+    }
+
+    // proper pair covering 100..179, mates aligned at 100..129 and 150..179
+    fn fragment_mates() -> (Record, Record) {
+        let mut left = mate_pair(100, 150, 80, vec![Cigar::Match(30)]);
+        left.set_proper_pair();
+        left.set_mapq(30);
+        let mut right = mate_pair(150, 100, -80, vec![Cigar::Match(30)]);
+        right.set_proper_pair();
+        right.set_reverse();
+        right.set_mapq(30);
+        (left, right)
+    }
+
+    fn fragment_sites(checker: &FragmentChecker, mates: &[&Record]) -> Vec<CutSite> {
+        mates.iter().filter(|rec| checker.valid(rec)).flat_map(|rec| checker.cut_sites(rec)).collect()
+    }
+
+    #[test]
+    fn fragment_sites_are_shifted_one_per_mate() {
+        let checker = fragment_checker();
+        let (left, right) = fragment_mates();
+        assert_eq!(vec![CutSite { pos: 104, reverse: false }], checker.cut_sites(&left).collect::<Vec<CutSite>>());
+        assert_eq!(vec![CutSite { pos: 174, reverse: true }], checker.cut_sites(&right).collect::<Vec<CutSite>>());
+        assert_eq!(vec![CutSite { pos: 104, reverse: false }, CutSite { pos: 174, reverse: true }], fragment_sites(&checker, &[&left, &right]));
+
+        let improper = mate_pair(100, 150, 80, vec![Cigar::Match(30)]);
+        assert!(!checker.valid(&improper));
+    }
+
+    #[test]
+    fn filtered_left_mate_keeps_right_site() {
+        let checker = fragment_checker();
+        let (mut left, right) = fragment_mates();
+        left.set_mapq(2);
+        assert_eq!(vec![CutSite { pos: 174, reverse: true }], fragment_sites(&checker, &[&left, &right]));
+        left.set_mapq(30);
+        left.set_flags(left.flags() | 0x200);
+        assert_eq!(vec![CutSite { pos: 174, reverse: true }], fragment_sites(&checker, &[&left, &right]));
+    }
+
+    #[test]
+    fn filtered_right_mate_keeps_left_site() {
+        let checker = fragment_checker();
+        let (left, mut right) = fragment_mates();
+        right.set_mapq(2);
+        assert_eq!(vec![CutSite { pos: 104, reverse: false }], fragment_sites(&checker, &[&left, &right]));
+        right.set_mapq(30);
+        right.set_flags(right.flags() | 0x800);
+        assert_eq!(vec![CutSite { pos: 104, reverse: false }], fragment_sites(&checker, &[&left, &right]));
+    }
+
+    #[test]
+    fn soft_clipped_five_prime_end_is_detected_per_strand() {
+        let fwd = record(100, false, vec![Cigar::HardClip(5), Cigar::SoftClip(2), Cigar::Match(8)]);
+        assert!(edge_soft_clipped(&fwd, false));
+        assert!(!edge_soft_clipped(&fwd, true));
+        assert!(!checker(false).valid(&fwd));
+
+        let rev = record(100, true, vec![Cigar::SoftClip(2), Cigar::Match(8)]);
+        assert!(!edge_soft_clipped(&rev, false));
+        assert!(edge_soft_clipped(&rev, true));
+        assert!(checker(false).valid(&rev));
     }
 }
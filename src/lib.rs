@@ -6,6 +6,9 @@ extern crate byteorder;
 extern crate bincode;
 extern crate flate2;
 extern crate rust_htslib as htslib;
+extern crate toml;
+#[cfg(test)]
+extern crate tempdir;
 
 use std::fs;
 use std::io::ErrorKind;
@@ -31,11 +34,22 @@ pub mod tallyrun;
 pub mod tallyread;
 pub mod seqtable;
 pub mod fasta;
+pub mod faidx;
+pub mod twobit;
 pub mod filter;
+pub mod split;
+pub mod dedup;
+pub mod source;
+pub mod chroms;
+pub mod regions;
+pub mod enzyme;
 pub mod counts;
 pub mod bigwig;
 pub mod scale;
+pub mod masksearch;
+pub mod calibrate;
 pub mod outputfile;
+pub mod manifest;
 
 // C API
 pub mod c_api;
@@ -10,6 +10,7 @@ extern crate rust_htslib;
 use seqoutbiaslib::tallyrun;
 use seqoutbiaslib::seqtable;
 use seqoutbiaslib::fasta;
+use seqoutbiaslib::fasta::SoftMask;
 use seqoutbiaslib::counts;
 use seqoutbiaslib::scale;
 use seqoutbiaslib::file_exists;
@@ -18,13 +19,26 @@ use docopt::Docopt;
 use std::process::exit;
 use std::error::Error;
 use std::path::Path;
+use std::fs;
 use std::fs::File;
 use seqoutbiaslib::seqtable::SeqTable;
 use profile::Profile;
 use toml::Value;
-use std::io::Read;
-use seqoutbiaslib::filter::PairPosition;
+use rustc_serialize::json::ToJson;
+use std::io::{Read, BufRead, BufReader};
+use seqoutbiaslib::filter::{FlagFilter, TN5_SHIFT};
+use seqoutbiaslib::split;
+use seqoutbiaslib::split::ReadSplit;
+use seqoutbiaslib::dedup::UmiSource;
+use seqoutbiaslib::source;
+use seqoutbiaslib::source::ReadOptions;
+use seqoutbiaslib::chroms::ChromAliases;
+use seqoutbiaslib::enzyme::EnzymePresets;
 use seqoutbiaslib::outputfile::OutFilename;
+use seqoutbiaslib::manifest::{RunManifest, manifest_filename};
+use seqoutbiaslib::masksearch;
+use seqoutbiaslib::masksearch::{SiteSet, MaskScorer};
+use seqoutbiaslib::calibrate;
 use std::ffi::OsStr;
 
 /* Main usage/arguments */
@@ -34,30 +48,96 @@ Cut-site frequencies
 
 Usage:
   seqOutBias tallymer <fasta-file> <read-size> [--parts=<n>]
+  seqOutBias seqtable merge <input-tbl>... --out=<outfile> [--manifest=<file>]
+  seqOutBias seqtable subset <input-tbl> [--chroms=<list|file>] [--regions=<bedfile>] [--chrom-aliases=<file>] --out=<outfile> [--manifest=<file>]
   seqOutBias seqtable <fasta-file> [options]
   seqOutBias dump <seqtbl-file> [<seqrange>]
-  seqOutBias table <seqtbl-file> [<bam-file>...] [--qual=<q>] [--regions=<bedfile>] [--pdist=<min:max>] [--only-paired] [--exact-length] [--tail-edge]
+  seqOutBias info <seqtbl-file> [--json]
+  seqOutBias validate <seqtbl-file> [--json]
+  seqOutBias table <seqtbl-file> [<bam-file>...] [--qual=<q>] [--require-flags=<f>] [--exclude-flags=<f>] [--exclude-duplicates] [--regions=<bedfile>] [--pdist=<min:max>] [--only-paired] [--exact-length] [--tail-edge] [--skip-soft-clipped] [--fragment-mode] [--custom-shift=<plus,minus>] [--split-by-tag=<tag>] [--tag-whitelist=<file>] [--dedup-umi] [--umi-tag=<tag>] [--umi-from-name] [--umi-mismatch] [--reference=<fasta>] [--chrom-aliases=<file>] [--skip-unknown-chroms] [--allow-length-mismatch]
+  seqOutBias fraglen <bam-file>... [--qual=<q>] [--require-flags=<f>] [--exclude-flags=<f>] [--exclude-duplicates]
   seqOutBias scale <seqtbl-file> <bam-file>... [options]
+  seqOutBias enzymes [--enzymes=<file>]
+  seqOutBias masksearch <fasta-file> <bam-file>... --sites=<bed6> --start-mask=<str> [options]
+  seqOutBias calibrate <fasta-file> <bam-file>... [options]
   seqOutBias <fasta-file> <bam-file>... [options]
   seqOutBias (-h | --help)
   seqOutBias --version
 
+Read files (<bam-file>) are SAM/BAM/CRAM alignments, BED6 read ends (.bed, .bed.gz) or 10x-style
+fragment files (.tsv, .tsv.gz: chrom, start, end, barcode, count). Fragment ends are already shifted
+for the Tn5 insertion and are counted once per line, with the barcode used by --split-by-tag and the
+fragment length by --pdist. Options that need alignment records are rejected for these files:
+quality, SAM flags, --only-paired, --exact-length, --skip-soft-clipped, --fragment-mode and --dedup-umi.
+
+The <fasta-file> can also be a UCSC .2bit genome, with its soft-masked blocks read as lowercase bases.
+
+The info command reports the parameters, sequences, block count, compression ratio and the fraction
+of unmappable positions per strand of a seqtable. The validate command decodes every block and checks
+it against the sequence lengths and k-mer counts, exiting with an error if any check fails.
+
+The seqtable merge command combines sequence tables built with the same parameters (e.g., a genome
+and its spike-ins) into one table. Sequence names must be distinct across the input tables.
+
+The seqtable subset command writes a table with only the --chroms sequences and/or the sequences
+overlapping the --regions (BED), with positions outside the regions made unmappable. The genome k-mer
+counts are recomputed, no FASTA file or mappability run is needed.
+
+The masksearch command runs a hill-climbing search over k-mer masks. Starting from --start-mask, it
+repeatedly unmasks the X position that gives the flattest scaled read profile over the --sites (BED6,
+comma separated files, one per reference factor, sites of equal width) and reports the best mask.
+
+The calibrate command reports the base frequencies within --window bp of the read 5' ends and their
+information content (bits) relative to the genome. Offsets with at least --min-info bits make up the
+recommended k-mer mask, with the cut-site just before the first read base (offset 0).
+
 Options:
   -h --help                    Show this screen.
   --version                    Show version.
   --kmer-size=<n>              Kmer size [default: 4].
   --tallymer=<file>            Unmappable positions file produced by tallymer (seq, pos).
-  --gt-workdir=<path>          Working directory for Genome Tools.
+  --gt-workdir=<path>          Working directory for Genome Tools. For a .2bit genome, the suffix tree is built
+                               from a temporary FASTA copy written here, which needs about 1 byte of free
+                               disk space per genome base (about 3.2 GB for a human genome).
   --plus-offset=<p>            Cut-site offset on plus strand, eg. p=2 AA[A]A [default: 2].
   --minus-offset=<m>           Cut-site offset on minus strand, eg. Eg, m=2 A[A]AA [default: 2].
   --kmer-mask=<str>            String indicating relevant kmer positions and cut-site, eg. NNXXNNCXXXXNNXXNN.
+                               Staggered cuts use C for the plus and c for the minus strand cut-site,
+                               eg. NNCNNNNcNN.
+                               R/Y, S/W or K/M positions only include the base class (purine/pyrimidine,
+                               strong/weak or keto/amino).
   --strand-specific            kmer is considered strand specific, i.e., it is flipped for the minus strand.
                                In this case, the minus-offset must be identical to the plus-offset.
+  --softmask-as-unmappable     Treat lowercase (soft-masked) FASTA bases like N, making k-mers that include
+                               them unmappable. Use with an empty --tallymer file (e.g. /dev/null) to skip the
+                               mappability computation.
+  --softmask-cut-sites         Only make cut-sites at lowercase (soft-masked) FASTA bases unmappable.
+  --chroms=<list|file>         Only include these sequences in the seqtable (comma separated names or a file
+                               with one name per line). The FASTA file is read through its .fai index (created
+                               if missing), bgzipped files also need a .gzi index. .2bit files are read directly.
+  --sites=<bed6>               Reference sites for masksearch (comma separated BED6 files).
+  --start-mask=<str>           Initial masksearch k-mer mask with a cut-site, eg. XXXXXXCXXXXXX.
+  --window=<n>                 Bases on each side of the read 5' end examined by calibrate [default: 20].
+  --min-info=<bits>            Minimum information content of the calibrate mask positions [default: 0.05].
   --read-size=<r>              Read length [default: 36].
   --parts=<n>                  Split suffix tree generation into n parts [default: 4].
   --qual=<q>                   Minimum read quality [default: 0].
+  --require-flags=<f>          Only accept reads with all of these SAM flags set (decimal or 0x hex).
+  --exclude-flags=<f>          Only accept reads with none of these SAM flags set (decimal or 0x hex).
+                               Defaults to 0xB00 (secondary, QC-fail and supplementary).
+  --exclude-duplicates         Also exclude reads marked as duplicates (0x400).
+  --dedup-umi                  Count reads sharing the same 5' position, strand and UMI only once.
+  --umi-tag=<tag>              SAM tag holding the UMI [default: RX].
+  --umi-from-name              Take the UMI from the read name, after the last '_' or ':'.
+  --umi-mismatch               UMIs differing at a single base are considered the same.
+  --reference=<fasta>          Reference FASTA used to decode CRAM read files.
+  --chrom-aliases=<file>       Sequence name aliases, one sequence per line with whitespace separated names.
+                               Names are also matched by adding or removing a 'chr' prefix.
+  --skip-unknown-chroms        Skip reads on sequences missing from the sequence table instead of failing.
+  --allow-length-mismatch      Proceed when read file sequence lengths (@SQ LN) differ from the sequence table.
   --regions=<bedfile>          Count only cut-sites inside the regions indicated in the BED file.
-  --out=<outfile>              Output seqtable filename (defaults to fasta file basename with .tbl extension).
+  --json                       Print info or validate results as JSON.
+  -o --out=<outfile>           Output seqtable filename (defaults to fasta file basename with .tbl extension).
   --bed=<bedfile>              Output scaled BED filename (defaults to BAM file basename with '_scaled.bed' extension).
   --bed-stranded-positive      BED written with stranded output have positive counts on both strands.
   --skip-bed                   Skip creating the BED file output.
@@ -67,19 +147,30 @@ Options:
   --shift-counts               Shift minus strand counts.
   --custom-shift=<plus,minus>  Shift strand counts by specified amounts (defaults to no shift).
   --no-scale                   Skip actual scaling in 'scale' command.
-  --pdist=<min:max>            Distance range for included paired reads.
+  --pdist=<min:max>            Fragment length range for included paired reads (see 'fraglen' command).
   --only-paired                Only accept aligned reads that have a mapped pair.
   --out-split-pairends         Split output files by pair end (_PE1 and _PE2).
+  --split-by-tag=<tag>         Split k-mer counts and output files by the value of a SAM tag (eg. CB or RG).
+  --tag-whitelist=<file>       Only split out the tag values listed in file (one per line), other reads are pooled (_other).
   --exact-length               Only accept BAM reads with length equal to 'read-size'.
   --tail-edge                  Use tail edge of reads (3') instead of start edge (5').
+  --fragment-mode              Count both insertion sites of properly paired fragments (e.g. ATAC-seq), one per mate
+                               with each mate filtered on its own. Sites are shifted before the k-mer lookup by
+                               the --custom-shift amounts [default Tn5 shift: 4,-5].
+  --skip-soft-clipped          Skip reads whose counted edge (5', or 3' with --tail-edge) is soft-clipped.
   --profile=<file>             Apply options from profile file. These values take precedence over command line flags.
+  --enzyme=<name>              Apply the k-mer mask and scale options of an enzyme preset (see 'enzymes' command).
+                               Preset values take precedence over command line flags, profile values over both.
+  --enzymes=<file>             Additional enzyme presets (TOML, same format as the built-in presets).
+  --manifest=<file>            Output JSON run manifest filename (defaults to output basename with '.manifest.json' extension, next to the output).
 ";
 
-#[derive(Debug, RustcDecodable, Profile)]
+#[derive(Debug, RustcDecodable, RustcEncodable, Profile)]
 struct Args {
     arg_fasta_file: String,
     arg_read_size: u16,
     arg_seqtbl_file: String,
+    arg_input_tbl: Vec<String>,
     arg_seqrange: Option<String>,
     arg_bam_file: Option<Vec<String>>,
     flag_kmer_size: u8,
@@ -88,11 +179,29 @@ struct Args {
     flag_plus_offset: u8,
     flag_minus_offset: u8,
     flag_kmer_mask: Option<String>,
+    flag_softmask_as_unmappable: bool,
+    flag_softmask_cut_sites: bool,
+    flag_chroms: Option<String>,
+    flag_sites: Option<String>,
+    flag_start_mask: Option<String>,
+    flag_window: u32,
+    flag_min_info: f64,
     flag_version: bool,
     flag_read_size: u16,
     flag_parts: u8,
     flag_qual: u8,
+    flag_require_flags: Option<String>,
+    flag_exclude_flags: Option<String>,
+    flag_exclude_duplicates: bool,
+    flag_dedup_umi: bool,
+    flag_umi_tag: String,
+    flag_umi_from_name: bool,
+    flag_umi_mismatch: bool,
     flag_regions: Option<String>,
+    flag_reference: Option<String>,
+    flag_chrom_aliases: Option<String>,
+    flag_skip_unknown_chroms: bool,
+    flag_allow_length_mismatch: bool,
     flag_out: Option<String>,
     flag_stranded: bool,
     flag_bed: Option<String>,
@@ -106,15 +215,31 @@ struct Args {
     flag_pdist: Option<String>,
     flag_only_paired: bool,
     flag_out_split_pairends: bool,
+    flag_json: bool,
+    flag_split_by_tag: Option<String>,
+    flag_tag_whitelist: Option<String>,
     flag_exact_length: bool,
     flag_tail_edge: bool,
+    flag_fragment_mode: bool,
+    flag_skip_soft_clipped: bool,
     flag_strand_specific: bool,
     flag_profile: Option<String>,
+    flag_enzyme: Option<String>,
+    flag_enzymes: Option<String>,
+    flag_manifest: Option<String>,
     cmd_tallymer: bool,
     cmd_seqtable: bool,
+    cmd_merge: bool,
+    cmd_subset: bool,
     cmd_dump: bool,
+    cmd_info: bool,
+    cmd_validate: bool,
     cmd_table: bool,
+    cmd_fraglen: bool,
     cmd_scale: bool,
+    cmd_enzymes: bool,
+    cmd_masksearch: bool,
+    cmd_calibrate: bool,
 }
 
 fn parse_range(range: &str) -> (i32, i32) {
@@ -146,6 +271,21 @@ fn parse_amounts(amounts: &str) -> (i32, i32) {
     return (plus_val, minus_val)
 }
 
+fn parse_flag_filter(require: &Option<String>, exclude: &Option<String>, exclude_duplicates: bool) -> FlagFilter {
+    let parse = |value: &String| match seqoutbiaslib::filter::parse_flags(value) {
+        Ok(value) => value,
+        Err(error) => {
+            println!("{}", error);
+            exit(1);
+        }
+    };
+    FlagFilter::from_options(require.as_ref().map(&parse), exclude.as_ref().map(&parse), exclude_duplicates)
+}
+
+fn file_basename(filename: &str) -> String {
+    Path::new(filename).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(String::new())
+}
+
 fn stem_filename(stem_src: &str, suffix: &str, out_arg: Option<String>) -> String {
     match out_arg {
         Some(out) => out,
@@ -157,6 +297,33 @@ fn stem_filename(stem_src: &str, suffix: &str, out_arg: Option<String>) -> Strin
     }
 }
 
+// write the run manifest, by default next to the `output` file
+fn write_manifest(manifest: &mut RunManifest, output: &str, manifest_arg: Option<String>) {
+    let manifest_file = manifest_arg.unwrap_or_else(|| manifest_filename(output));
+    match manifest.write(&manifest_file) {
+        Ok(_) => println!("# manifest produced {}", manifest_file),
+        Err(err) => println!("Error producing manifest file: {}", err.description()),
+    }
+}
+
+// sequence names given as a comma separated list or as a file, with one name per line
+fn parse_chroms(value: &str) -> Vec<String> {
+    if !file_exists(value) {
+        return value.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect();
+    }
+    let file = match File::open(value) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("Error: Failed to open sequence names file '{}': {}", value, err.description());
+            exit(1);
+        },
+    };
+    BufReader::new(file).lines().map(|line| line.ok().expect("read sequence names file"))
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split_whitespace().next().unwrap().to_string())
+        .collect()
+}
+
 fn validate_mask(mask: &str) {
     if let Err(error) = seqtable::SeqTableParams::validate_mask(mask) {
         println!("{}", error);
@@ -171,15 +338,60 @@ fn main() {
                             .unwrap_or_else(|e| e.exit());
 
     // Apply profile over args
-    if args.flag_profile.is_some() {
-        let profile_filename = args.flag_profile.as_ref().unwrap().clone();
+    let profile = args.flag_profile.clone().map(|profile_filename| {
         println!("# Profile file: {}", profile_filename );
         let mut file = File::open(profile_filename).expect("Failed to open profile file." );
         let mut string = String::with_capacity(file.metadata().map(|m| m.len() as usize + 1).unwrap_or(0) );
         file.read_to_string(&mut string).expect( "Failed to read profile file." );
 
-        let value = string.parse::<Value>().unwrap();
-        args.apply_profile(&value);
+        string.parse::<Value>().unwrap()
+    });
+    if let Some(ref value) = profile {
+        args.apply_profile(value);
+    }
+
+    // Enzyme presets
+    let mut presets = EnzymePresets::builtin();
+    if let Some(ref filename) = args.flag_enzymes {
+        match EnzymePresets::from_file(filename) {
+            Ok(user_presets) => presets.extend(user_presets),
+            Err(err) => {
+                println!("Error: Failed to read enzyme presets '{}': {}", filename, err);
+                exit(1);
+            }
+        }
+    }
+
+    if args.cmd_enzymes {
+        for preset in presets.iter() {
+            let aliases = if preset.aliases.is_empty() { String::new() } else { format!(" (aliases: {})", preset.aliases.join(", ")) };
+            println!("{}{}: {}", preset.name, aliases, preset.description);
+            if let Some(settings) = preset.settings().as_table() {
+                for (key, value) in settings {
+                    println!("  --{}={}", key, value.as_str().map(|s| s.to_string()).unwrap_or(value.to_string()));
+                }
+            }
+        }
+        return;
+    }
+
+    if let Some(name) = args.flag_enzyme.clone() {
+        let settings = match presets.get(&name) {
+            Some(preset) => {
+                println!("# Enzyme preset: {}", preset.name);
+                preset.settings().clone()
+            },
+            None => {
+                let names: Vec<&str> = presets.iter().map(|preset| preset.name.as_str()).collect();
+                println!("Error: Unknown enzyme '{}', available presets: {}", name, names.join(", "));
+                exit(1);
+            }
+        };
+        args.apply_profile(&settings);
+        // profile values take precedence over the preset
+        if let Some(ref value) = profile {
+            args.apply_profile(value);
+        }
     }
 
     if args.flag_version {
@@ -189,6 +401,12 @@ fn main() {
             env!( "CARGO_PKG_VERSION_PATCH" ) );
         return;
     }
+
+    // Record resolved arguments for the run manifest
+    let mut manifest = RunManifest::new(&args);
+    if let Some(ref profile_filename) = args.flag_profile {
+        manifest.add_input(profile_filename);
+    }
     
     let dist_range = match args.flag_pdist {
         Some(range) => Some(parse_range(&range)),
@@ -205,11 +423,117 @@ fn main() {
         exit(1);
     }
 
+    if args.flag_out_split_pairends && args.arg_bam_file.as_ref().map_or(false, |files| files.iter().any(|f| !source::is_alignment_file(f))) {
+        println!("--out-split-pairends requires SAM/BAM/CRAM read files");
+        exit(1);
+    }
+
+    if args.flag_out_split_pairends && args.flag_split_by_tag.is_some() {
+        println!("--out-split-pairends and --split-by-tag cannot be used together");
+        exit(1);
+    }
+
+    if args.flag_tag_whitelist.is_some() && args.flag_split_by_tag.is_none() {
+        println!("--tag-whitelist requires option --split-by-tag");
+        exit(1);
+    }
+
+    if args.flag_fragment_mode && (args.flag_shift_counts || args.flag_tail_edge || args.flag_skip_soft_clipped || args.flag_out_split_pairends) {
+        println!("--fragment-mode cannot be used with --shift-counts, --tail-edge, --skip-soft-clipped or --out-split-pairends");
+        exit(1);
+    }
+
+    let flags = parse_flag_filter(&args.flag_require_flags, &args.flag_exclude_flags, args.flag_exclude_duplicates);
+
     let shift_amounts = match args.flag_custom_shift {
         Some(amounts) => Some(parse_amounts(&amounts)),
         None => None
     };
+
+    // in fragment mode the shift is applied to the insertion sites instead of the pile-up
+    let (shift_amounts, fragment_shift) = if args.flag_fragment_mode {
+        (None, Some(shift_amounts.unwrap_or(TN5_SHIFT)))
+    } else {
+        (shift_amounts, None)
+    };
     
+    let mut split = if args.flag_out_split_pairends {
+        ReadSplit::PairEnd
+    } else if let Some(ref tag) = args.flag_split_by_tag {
+        if tag.len() != 2 {
+            println!("Invalid SAM tag: {}", tag);
+            exit(1);
+        }
+        let whitelist = args.flag_tag_whitelist.as_ref().map(|filename| {
+            manifest.add_input(filename);
+            match split::read_whitelist(filename) {
+                Ok(values) => values,
+                Err(err) => {
+                    println!("Error: Failed to read tag whitelist '{}': {}", filename, err.description());
+                    exit(1);
+                }
+            }
+        });
+        ReadSplit::by_tag(tag, whitelist)
+    } else {
+        ReadSplit::None
+    };
+
+    let dedup_umi = if args.flag_dedup_umi {
+        let source = if args.flag_umi_from_name {
+            UmiSource::ReadName
+        } else {
+            let tag = &args.flag_umi_tag;
+            if tag.len() != 2 {
+                println!("Invalid SAM tag: {}", tag);
+                exit(1);
+            }
+            UmiSource::Tag([tag.as_bytes()[0], tag.as_bytes()[1]])
+        };
+        Some((source, args.flag_umi_mismatch))
+    } else {
+        None
+    };
+
+    let chroms = match args.flag_chrom_aliases {
+        Some(ref filename) => {
+            manifest.add_input(filename);
+            match ChromAliases::from_file(filename, args.flag_skip_unknown_chroms) {
+                Ok(value) => value,
+                Err(err) => {
+                    println!("Error: Failed to read sequence name aliases '{}': {}", filename, err.description());
+                    exit(1);
+                }
+            }
+        },
+        None => ChromAliases::new(args.flag_skip_unknown_chroms),
+    };
+
+    let read_options = ReadOptions {
+        min_quality: args.flag_qual,
+        flags: flags,
+        pair_range: dist_range,
+        only_paired: args.flag_only_paired,
+        exact_length: args.flag_exact_length,
+        tail_edge: args.flag_tail_edge,
+        skip_soft_clipped: args.flag_skip_soft_clipped,
+        fragment_shift: fragment_shift,
+        dedup_umi: dedup_umi,
+        reference: args.flag_reference.clone(),
+        chroms: chroms,
+        allow_length_mismatch: args.flag_allow_length_mismatch,
+    };
+
+    let softmask = match (args.flag_softmask_as_unmappable, args.flag_softmask_cut_sites) {
+        (false, false) => SoftMask::Ignore,
+        (true, false) => SoftMask::Unmappable,
+        (false, true) => SoftMask::CutSite,
+        (true, true) => {
+            println!("Error: '--softmask-as-unmappable' and '--softmask-cut-sites' cannot be used together.");
+            exit(1);
+        },
+    };
+
     // Check for data output commands
     
     if args.cmd_dump {
@@ -222,14 +546,161 @@ fn main() {
         return;
     }
     
+    if args.cmd_info {
+        match seqtable::table_info(&args.arg_seqtbl_file) {
+            Ok(info) => if args.flag_json { println!("{}", info.to_json().pretty()) } else { info.print() },
+            Err(err) => {
+                println!("Error: {}", err);
+                exit(1);
+            },
+        }
+        return;
+    }
+
+    if args.cmd_validate {
+        let validation = seqtable::validate_table(&args.arg_seqtbl_file);
+        if args.flag_json {
+            println!("{}", validation.to_json().pretty());
+        } else {
+            validation.print();
+        }
+        if !validation.is_valid() {
+            exit(1);
+        }
+        return;
+    }
+
+    if args.cmd_merge {
+        let outfile = match args.flag_out {
+            Some(ref outfile) => outfile.clone(),
+            None => {
+                println!("Error: 'seqtable merge' requires '--out'.");
+                exit(1);
+            },
+        };
+        if file_exists(&outfile) {
+            println!("Error: output file {} already exists!", outfile);
+            exit(1);
+        }
+        manifest.start_phase("merge");
+        for filename in &args.arg_input_tbl {
+            manifest.add_input(filename);
+        }
+        if let Err(err) = seqtable::merge_seqtables(&args.arg_input_tbl, &outfile) {
+            println!("Error: {}", err);
+            let _ = fs::remove_file(&outfile);
+            exit(1);
+        }
+        println!("# seqtable merged {} tables into {}", args.arg_input_tbl.len(), outfile);
+        manifest.add_output(&outfile);
+        manifest.set_seqtable(&outfile, &seqtable::SeqTableParams::from_file(&outfile));
+        write_manifest(&mut manifest, &outfile, args.flag_manifest);
+        return;
+    }
+
+    if args.cmd_subset {
+        let outfile = args.flag_out.clone().unwrap();
+        if args.flag_chroms.is_none() && args.flag_regions.is_none() {
+            println!("Error: 'seqtable subset' requires '--chroms' and/or '--regions'.");
+            exit(1);
+        }
+        if file_exists(&outfile) {
+            println!("Error: output file {} already exists!", outfile);
+            exit(1);
+        }
+        manifest.start_phase("subset");
+        let chroms = args.flag_chroms.as_ref().map(|value| {
+            if file_exists(value) {
+                manifest.add_input(value);
+            }
+            parse_chroms(value)
+        });
+        let input = &args.arg_input_tbl[0];
+        manifest.add_input(input);
+        if let Some(ref regions) = args.flag_regions {
+            manifest.add_input(regions);
+        }
+        if let Err(err) = seqtable::subset_seqtable(input, chroms.as_ref(), args.flag_regions.as_ref().map(|s| s.as_str()), &read_options.chroms, &outfile) {
+            println!("Error: {}", err);
+            let _ = fs::remove_file(&outfile);
+            exit(1);
+        }
+        println!("# seqtable subset of {} written to {}", input, outfile);
+        manifest.add_output(&outfile);
+        manifest.set_seqtable(&outfile, &seqtable::SeqTableParams::from_file(&outfile));
+        write_manifest(&mut manifest, &outfile, args.flag_manifest);
+        return;
+    }
+
     if args.cmd_table {
         let has_bam = args.arg_bam_file.is_some();
-        let counts = counts::tabulate(&args.arg_seqtbl_file, args.arg_bam_file.as_ref(), args.flag_qual, args.flag_regions, dist_range, args.flag_only_paired, args.flag_exact_length, args.flag_tail_edge);
+        let counts = counts::tabulate(&args.arg_seqtbl_file, args.arg_bam_file.as_ref(), args.flag_regions, &read_options, &mut split);
         let params = seqtable::SeqTableParams::from_file(&args.arg_seqtbl_file);
-        counts::print_counts(&counts, has_bam, &params);
+        for group in 0..counts.len() {
+            if split.split_counts() {
+                println!("# group {}", split.name(group));
+            }
+            counts::print_counts(&counts, group, has_bam, &params);
+        }
         return;
     }
     
+    if args.cmd_fraglen {
+        let hist = counts::fragment_lengths(args.arg_bam_file.as_ref().unwrap(), args.flag_qual, flags);
+        counts::print_fragment_lengths(&hist);
+        return;
+    }
+    
+    if args.cmd_masksearch {
+        let start_mask = args.flag_start_mask.clone().unwrap();
+        // the search ends with all positions unmasked
+        validate_mask(&start_mask.replace("X", "N").replace("x", "N"));
+        if !start_mask.contains('C') && !start_mask.contains('c') {
+            println!("Error: '--start-mask' must include a cut-site (C).");
+            exit(1);
+        }
+        let site_sets: Vec<SiteSet> = args.flag_sites.as_ref().unwrap().split(',').map(|filename| {
+            match SiteSet::from_bed(filename) {
+                Ok(set) => set,
+                Err(err) => {
+                    println!("Error: Failed to read sites file '{}': {}", filename, err);
+                    exit(1);
+                },
+            }
+        }).collect();
+        for set in &site_sets {
+            println!("# sites {}: {}", set.name(), set.len());
+        }
+
+        let tally_path = match args.flag_tallymer {
+            Some(ref path) => path.into(),
+            None => tallyrun::tallymer_createfile(&args.arg_fasta_file, args.flag_read_size, args.flag_parts, args.flag_gt_workdir.clone()),
+        };
+        let fasta_file = &args.arg_fasta_file;
+        let inputs = args.arg_bam_file.as_ref().unwrap();
+        let (read_size, strand_specific, shift_counts) = (args.flag_read_size, args.flag_strand_specific, args.flag_shift_counts);
+        let scorer = MaskScorer::new(fasta_file, &tally_path, read_size, strand_specific, softmask, inputs, &read_options, shift_counts, &shift_amounts, site_sets);
+        let steps = masksearch::hill_climb(&start_mask, |mask| scorer.score_mask(mask));
+
+        for &(score, ref mask) in &steps {
+            println!("{}\t{}", score, mask);
+        }
+        if let Some(&(score, ref mask)) = steps.iter().min_by(|a, b| a.0.partial_cmp(&b.0).unwrap()) {
+            println!("# optimal mask: {} (score {})", mask, score);
+        }
+        return;
+    }
+
+    if args.cmd_calibrate {
+        if args.flag_window == 0 {
+            println!("Error: '--window' must be at least 1.");
+            exit(1);
+        }
+        let calibration = calibrate::calibrate(&args.arg_fasta_file, args.arg_bam_file.as_ref().unwrap(), args.flag_read_size, &read_options, args.flag_window);
+        calibration.print(args.flag_min_info, args.flag_read_size);
+        return;
+    }
+
     // Check for main sequence commands
     let mut run_tallymer = !args.flag_tallymer.is_some();
     let mut run_seqtable = true;
@@ -250,7 +721,7 @@ fn main() {
         // all three phases
         
         // catch cmd names being interpreted as fasta_file names
-        if args.arg_fasta_file.eq("dump") || args.arg_fasta_file.eq("table") || args.arg_fasta_file.eq("tallymer") || args.arg_fasta_file.eq("seqtable") || args.arg_fasta_file.eq("scale") {
+        if args.arg_fasta_file.eq("dump") || args.arg_fasta_file.eq("info") || args.arg_fasta_file.eq("validate") || args.arg_fasta_file.eq("table") || args.arg_fasta_file.eq("fraglen") || args.arg_fasta_file.eq("tallymer") || args.arg_fasta_file.eq("seqtable") || args.arg_fasta_file.eq("scale") || args.arg_fasta_file.eq("masksearch") || args.arg_fasta_file.eq("calibrate") {
             println!("Invalid arguments to {} command.", args.arg_fasta_file);
             println!("{}", USAGE);
             exit(1);
@@ -265,21 +736,27 @@ fn main() {
     // Process main sequence phases
     
     // phase 1 - tallymer
+    // tallymer, BED and bigWig files are written to the current directory
+    let mut manifest_output = file_basename(&args.arg_fasta_file);
     let tally_path = if run_tallymer {
         if !file_exists(&args.arg_fasta_file) {
             println!("Error: FASTA file {} does not exist!", args.arg_fasta_file);
             exit(1);
         }
         
+        manifest.start_phase("tallymer");
+        manifest.add_input(&args.arg_fasta_file);
         let path = if args.cmd_tallymer {
             tallyrun::tallymer_createfile(&args.arg_fasta_file, args.arg_read_size, args.flag_parts, args.flag_gt_workdir)
         } else {
             tallyrun::tallymer_createfile(&args.arg_fasta_file, args.flag_read_size, args.flag_parts, args.flag_gt_workdir)
         };
         println!("# tallymer produced/found {:}", path.to_str().unwrap());
+        manifest.set_tallymer(&path.to_string_lossy());
         Some(path)
     } else if let Some(path) = args.flag_tallymer {
         println!("# using supplied tallymer file {:}", path);
+        manifest.set_tallymer(&path);
         Some(path.clone().into())
     } else { None };
     
@@ -288,6 +765,14 @@ fn main() {
 
         if let Some(ref mask) = args.flag_kmer_mask {
             validate_mask(mask);
+        } else if args.flag_kmer_size as u32 * 2 > seqtable::MAX_INDEX_BITS {
+            println!("Error: '--kmer-size' can be at most {}.", seqtable::MAX_INDEX_BITS / 2);
+            exit(1);
+        }
+
+        if args.flag_strand_specific && args.flag_kmer_mask.as_ref().map_or(false, |mask| seqtable::SeqTableParams::has_staggered_cut(mask)) {
+            println!("Error: '--strand-specific' cannot be used with separate plus (C) and minus (c) cut-sites.");
+            exit(1);
         }
 
         if args.flag_strand_specific && args.flag_plus_offset != args.flag_minus_offset {
@@ -303,6 +788,15 @@ fn main() {
             &args.flag_kmer_mask,
             args.flag_strand_specific);
         
+        // the tallymer file numbers all FASTA sequences, so it is computed for the whole file
+        let chroms_subset = args.flag_chroms.as_ref().map(|value| {
+            if file_exists(value) {
+                manifest.add_input(value);
+            }
+            parse_chroms(value)
+        });
+
+        manifest.start_phase("seqtable");
         println!("# kmer-size: {}", seq_params.kmer_length);
         println!("# plus-offset: {}", seq_params.plus_offset);
         println!("# minus-offset: {}", seq_params.minus_offset);
@@ -310,7 +804,9 @@ fn main() {
         let suffix = format!("_{}.{}.{}.{}.tbl", seq_params.read_length, seq_params.kmer_length, seq_params.plus_offset, seq_params.minus_offset);
         let outfile = stem_filename(&args.arg_fasta_file, &suffix, args.flag_out);
         
-        if file_exists(&outfile) {
+        // the FASTA file is the provenance of the table, whether it is generated or reused
+        manifest.add_input(&args.arg_fasta_file);
+        let outfile = if file_exists(&outfile) {
             let file = File::open(&outfile).ok().expect("read file");
             let table = match SeqTable::open(file) {
                 Ok(value) => value,
@@ -319,7 +815,20 @@ fn main() {
                     exit(1);
                 },
             };
-            if table.equivalent(&outfile, &seq_params) {
+            // without --chroms, the table must hold all the genome sequences (not a previous subset)
+            let fasta_file = &args.arg_fasta_file;
+            let same_chroms = || {
+                let mut names: Vec<String> = table.sequences().into_iter().map(|info| info.name).collect();
+                let mut requested = match chroms_subset {
+                    Some(ref chroms) => chroms.clone(),
+                    None => fasta::genome_sequence_names(fasta_file),
+                };
+                names.sort();
+                requested.sort();
+                requested.dedup();
+                names == requested
+            };
+            if table.equivalent(&outfile, &seq_params) && table.softmask == softmask && same_chroms() {
                 println!("# seqtable reusing existing {}", &outfile);
                 outfile
             } else {
@@ -327,11 +836,19 @@ fn main() {
                 exit(1);
             }
         } else {
-            fasta::process_fasta(&args.arg_fasta_file, &tally_path.unwrap(), &seq_params, &outfile);
+            match chroms_subset {
+                Some(ref chroms) => fasta::process_fasta_subset(&args.arg_fasta_file, &tally_path.unwrap(), &seq_params, softmask, chroms, &outfile),
+                None => fasta::process_fasta(&args.arg_fasta_file, &tally_path.unwrap(), &seq_params, softmask, &outfile),
+            }
             println!("# seqtable produced {}", &outfile);
+            manifest.add_output(&outfile);
             outfile
-        }
+        };
+        manifest.set_seqtable(&outfile, &seq_params);
+        manifest_output = outfile.clone();
+        outfile
     } else {
+        manifest.set_seqtable(&args.arg_seqtbl_file, &seqtable::SeqTableParams::from_file(&args.arg_seqtbl_file));
         args.arg_seqtbl_file
     };
     
@@ -342,19 +859,22 @@ fn main() {
                 println!("Error: BAM file {} does not exist!", filename);
                 exit(1);
             }
+            manifest.add_input(filename);
+        }
+        if let Some(ref regions) = args.flag_regions {
+            manifest.add_input(regions);
         }
         
         let bamfile = args.arg_bam_file.as_ref().unwrap()[0].clone(); // use the first name for reference
-        let counts = counts::tabulate(&seqtable_file, args.arg_bam_file.as_ref(), args.flag_qual, args.flag_regions, dist_range, args.flag_only_paired, args.flag_exact_length, args.flag_tail_edge);
+        manifest_output = file_basename(&bamfile);
+        manifest.start_phase("tabulate");
+        let counts = counts::tabulate(&seqtable_file, args.arg_bam_file.as_ref(), args.flag_regions, &read_options, &mut split);
 
-        let pileup_variants = if args.flag_out_split_pairends {
-            vec![ ("_PE1", Some(PairPosition::First)), ("_PE2", Some(PairPosition::Last))]
-        } else {
-            vec![ ("", None)]
-        };
+        manifest.start_phase("scale");
+        let pileups = scale::scale(&seqtable_file, &counts, args.arg_bam_file.as_ref().unwrap(), &read_options, args.flag_shift_counts, &shift_amounts, args.flag_no_scale, &mut split);
 
-        for ( suffix_prefix, select_pair) in pileup_variants {
-            let pileup = scale::scale(&seqtable_file, &counts, args.arg_bam_file.as_ref().unwrap(), args.flag_qual, args.flag_shift_counts, &shift_amounts, args.flag_no_scale, &dist_range, args.flag_only_paired, args.flag_exact_length, args.flag_tail_edge, select_pair);
+        for (group, pileup) in pileups.iter().enumerate() {
+            let suffix_prefix: &str = &split.suffix(group);
 
             if !args.flag_skip_bed {
                 let mut outfile_bed = OutFilename::from( &bamfile, &args.flag_bed, "bed");
@@ -370,8 +890,12 @@ fn main() {
                     exit(1);
                 }
 
+                manifest.start_phase(&format!("write-bed{}", suffix_prefix));
                 match pileup.write_bed(&outfile_bed, args.flag_stranded, args.flag_bed_stranded_positive) {
-                    Ok(_) => println!("# scale produced {}", &outfile_bed.filename().to_string_lossy()),
+                    Ok(_) => {
+                        println!("# scale produced {}", &outfile_bed.filename().to_string_lossy());
+                        manifest.add_output(&outfile_bed.filename().to_string_lossy());
+                    },
                     Err(err) => println!("Error producing BED file: {}", err.description()),
                 }
             } else {
@@ -382,13 +906,14 @@ fn main() {
                 let mut outfile_bw = OutFilename::from( &bamfile, &args.flag_bw, "bigWig");
                 outfile_bw.append_suffix(OsStr::new(suffix_prefix));
 
+                manifest.start_phase(&format!("write-bigwig{}", suffix_prefix));
                 match pileup.write_bw(&outfile_bw, args.flag_stranded) {
                     Ok((f1, f2)) => {
-                        if f2.is_some() {
-                            println!("# scale produced {}", f1);
-                            println!("# scale produced {}", f2.unwrap());
-                        } else {
-                            println!("# scale produced {}", f1);
+                        println!("# scale produced {}", f1);
+                        manifest.add_output(&f1);
+                        if let Some(f2) = f2 {
+                            println!("# scale produced {}", f2);
+                            manifest.add_output(&f2);
                         }
                     },
                     Err(err) => println!("Error producing BigWig file: {}", err.description()),
@@ -398,4 +923,7 @@ fn main() {
             }
         }
     }
+
+    // write run manifest
+    write_manifest(&mut manifest, &manifest_output, args.flag_manifest);
 }
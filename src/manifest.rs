@@ -0,0 +1,186 @@
+//!
+//!	Machine-readable record of a run: resolved parameters, inputs, outputs and timings.
+//!
+use rustc_serialize::Encodable;
+use rustc_serialize::json::{self, Json, ToJson};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::Result;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use seqtable::SeqTableParams;
+
+pub struct RunManifest {
+    args: Json,
+    seqtable: Option<Json>,
+    inputs: Vec<String>,
+    tallymer: Option<String>,
+    outputs: Vec<String>,
+    timings: Vec<(String, f64)>,
+    phase: Option<(String, Instant)>,
+}
+
+fn file_entry(path: &str) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("path".to_string(), path.to_json());
+    obj.insert("size".to_string(), fs::metadata(path).ok().map(|meta| meta.len()).to_json());
+    Json::Object(obj)
+}
+
+/// Default manifest filename for a run producing `output`: the output basename with a
+/// '.manifest.json' extension, in the same directory
+pub fn manifest_filename(output: &str) -> String {
+    let path = Path::new(output);
+    let mut name = path.file_stem().unwrap().to_os_string();
+    name.push(".manifest.json");
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Sequence table parameters as a JSON object
+pub fn params_json(params: &SeqTableParams) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("kmer_length".to_string(), params.kmer_length.to_json());
+    obj.insert("plus_offset".to_string(), params.plus_offset.to_json());
+    obj.insert("minus_offset".to_string(), params.minus_offset.to_json());
+    obj.insert("read_length".to_string(), params.read_length.to_json());
+    obj.insert("mask".to_string(), params.mask_string().to_json());
+    obj.insert("unmasked_count".to_string(), params.unmasked_count.to_json());
+    obj.insert("strand_specific".to_string(), params.strand_specific.to_json());
+    Json::Object(obj)
+}
+
+impl RunManifest {
+    /// Create a new manifest recording the supplied (already resolved) command line arguments
+    pub fn new<T: Encodable>(args: &T) -> RunManifest {
+        let args = json::encode(args).ok().and_then(|text| Json::from_str(&text).ok()).unwrap_or(Json::Null);
+
+        RunManifest {
+            args: args,
+            seqtable: None,
+            inputs: Vec::new(),
+            tallymer: None,
+            outputs: Vec::new(),
+            timings: Vec::new(),
+            phase: None,
+        }
+    }
+
+    /// Record the seqtable file used by the run and the parameters it was generated with
+    pub fn set_seqtable(&mut self, filename: &str, params: &SeqTableParams) {
+        let mut obj = BTreeMap::new();
+        obj.insert("file".to_string(), file_entry(filename));
+        obj.insert("params".to_string(), params_json(params));
+        self.seqtable = Some(Json::Object(obj));
+    }
+
+    /// Record an input file, once
+    pub fn add_input(&mut self, filename: &str) {
+        if !self.inputs.iter().any(|name| name == filename) {
+            self.inputs.push(filename.to_string());
+        }
+    }
+
+    pub fn set_tallymer(&mut self, filename: &str) {
+        self.tallymer = Some(filename.to_string());
+    }
+
+    pub fn add_output(&mut self, filename: &str) {
+        self.outputs.push(filename.to_string());
+    }
+
+    /// Start timing a new phase, closing the previous one if still open
+    pub fn start_phase(&mut self, name: &str) {
+        self.end_phase();
+        self.phase = Some((name.to_string(), Instant::now()));
+    }
+
+    /// Stop timing the current phase
+    pub fn end_phase(&mut self) {
+        if let Some((name, start)) = self.phase.take() {
+            let elapsed = start.elapsed();
+            let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            self.timings.push((name, seconds));
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_json());
+        obj.insert("args".to_string(), self.args.clone());
+        obj.insert("seqtable".to_string(), self.seqtable.clone().unwrap_or(Json::Null));
+        obj.insert("inputs".to_string(), Json::Array(self.inputs.iter().map(|name| file_entry(name)).collect()));
+        obj.insert("tallymer".to_string(), self.tallymer.as_ref().map(|name| file_entry(name)).unwrap_or(Json::Null));
+        obj.insert("outputs".to_string(), Json::Array(self.outputs.iter().map(|name| file_entry(name)).collect()));
+        obj.insert("timings".to_string(), Json::Array(self.timings.iter().map(|&(ref name, seconds)| {
+            let mut timing = BTreeMap::new();
+            timing.insert("phase".to_string(), name.to_json());
+            timing.insert("seconds".to_string(), seconds.to_json());
+            Json::Object(timing)
+        }).collect()));
+        Json::Object(obj)
+    }
+
+    /// Write manifest to disk in JSON format
+    pub fn write(&mut self, filename: &str) -> Result<()> {
+        self.end_phase();
+        let mut f = try!(File::create(filename));
+        try!(write!(f, "{}\n", self.to_json().pretty()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempdir::TempDir;
+
+    #[derive(RustcEncodable)]
+    struct Args {
+        flag_kmer_size: u8,
+    }
+
+    #[test]
+    fn written_manifest() {
+        let dir = TempDir::new("seqoutbias_manifest").unwrap();
+        let filename = dir.path().join("run.manifest.json").to_str().unwrap().to_string();
+        let tblname = dir.path().join("genome.tbl").to_str().unwrap().to_string();
+        File::create(&tblname).unwrap().write_all(b"table").unwrap();
+
+        let mut manifest = RunManifest::new(&Args { flag_kmer_size: 4 });
+        manifest.start_phase("seqtable");
+        manifest.add_input("genome.fa");
+        manifest.add_input("genome.fa");
+        manifest.set_seqtable(&tblname, &SeqTableParams::new(4, 2, 2, 36, &None, false));
+        manifest.start_phase("tabulate");
+        manifest.add_input("reads.bam");
+        manifest.add_output("reads.bw");
+        manifest.write(&filename).unwrap();
+
+        let mut text = String::new();
+        File::open(&filename).unwrap().read_to_string(&mut text).unwrap();
+        let json = Json::from_str(&text).unwrap();
+        assert_eq!(Some(4), json.find_path(&["args", "flag_kmer_size"]).and_then(|v| v.as_u64()));
+        let inputs: Vec<&str> = json["inputs"].as_array().unwrap().iter().map(|entry| entry["path"].as_string().unwrap()).collect();
+        assert_eq!(vec!["genome.fa", "reads.bam"], inputs);
+        assert_eq!(Some(&Json::Null), json["inputs"][0].find("size"));
+        assert_eq!(Some(5), json.find_path(&["seqtable", "file", "size"]).and_then(|v| v.as_u64()));
+        assert_eq!(Some(2), json.find_path(&["seqtable", "params", "plus_offset"]).and_then(|v| v.as_u64()));
+        assert_eq!(Some(&Json::Null), json.find("tallymer"));
+        assert_eq!(Some("reads.bw"), json["outputs"][0]["path"].as_string());
+
+        // phases in order, the last one closed on write
+        let timings = json["timings"].as_array().unwrap();
+        let phases: Vec<&str> = timings.iter().map(|timing| timing["phase"].as_string().unwrap()).collect();
+        assert_eq!(vec!["seqtable", "tabulate"], phases);
+        assert!(timings.iter().all(|timing| timing["seconds"].as_f64().unwrap() >= 0f64));
+    }
+
+    #[test]
+    fn manifest_next_to_output() {
+        assert_eq!("/data/hg38_36.4.2.2.manifest.json", manifest_filename("/data/hg38_36.4.2.2.tbl"));
+        assert_eq!("reads.manifest.json", manifest_filename("reads.bam"));
+    }
+}
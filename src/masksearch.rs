@@ -0,0 +1,351 @@
+//!
+//!	Hill-climbing search for k-mer masks that minimize the cut-site bias at reference sites
+//! (in-process version of docs/R/seqOutBias_hcsearch.R).
+//!
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::process::exit;
+use seqtable::{SeqTableParams, SequenceInfo};
+use fasta;
+use fasta::SoftMask;
+use counts::GroupCounts;
+use scale;
+use scale::{PileUp, ScaleFactors};
+use source;
+use source::{ReadOptions, skip_sequence};
+use split::ReadSplit;
+use chroms::ChromResolver;
+
+/// BED6 site
+struct Site {
+    chrom: String,
+    start: u32,
+    reverse: bool,
+}
+
+/// Sites of one reference factor (eg. motif instances), all of the same width
+pub struct SiteSet {
+    name: String,
+    width: u32,
+    sites: Vec<Site>,
+}
+
+impl SiteSet {
+    /// Read BED6 file, sites on the minus strand are read in reverse
+    pub fn from_bed(filename: &str) -> Result<SiteSet> {
+        let file = try!(File::open(filename));
+        let reader = BufReader::new(file);
+        let mut sites = Vec::new();
+        let mut width = None;
+
+        for res in reader.lines() {
+            let line = try!(res);
+            if line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 6 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("expected BED6 line: {}", line)));
+            }
+            let start: u32 = try!(fields[1].parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid start coordinate: {}", fields[1]))));
+            let end: u32 = try!(fields[2].parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid end coordinate: {}", fields[2]))));
+            if end <= start || width.map_or(false, |width| width != end - start) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("all sites must have the same (positive) width: {}", line)));
+            }
+            width = Some(end - start);
+            sites.push(Site { chrom: fields[0].to_string(), start: start, reverse: fields[5] == "-" });
+        }
+
+        match width {
+            Some(width) => Ok(SiteSet { name: filename.to_string(), width: width, sites: sites }),
+            None => Err(Error::new(ErrorKind::InvalidData, "no sites in file")),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.sites.len()
+    }
+
+    /// Sum of the pile-up values (both strands) at each position of the sites, following the site strand
+    pub fn profile(&self, pileup: &PileUp, resolver: &ChromResolver) -> Vec<f64> {
+        let mut profile = vec![0f64; self.width as usize];
+        for site in &self.sites {
+            let chrom_idx = match resolver.resolve(&site.chrom) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            for offset in 0..self.width {
+                if let Some(&(plus, minus)) = pileup.get(chrom_idx, site.start + offset) {
+                    let idx = if site.reverse { self.width - 1 - offset } else { offset };
+                    profile[idx as usize] += plus + minus;
+                }
+            }
+        }
+        profile
+    }
+}
+
+/// Standard deviation of the profile, a flat profile means no remaining cut-site bias
+fn profile_deviation(profile: &Vec<f64>) -> f64 {
+    let n = profile.len() as f64;
+    if profile.len() < 2 {
+        return 0f64;
+    }
+    let mean = profile.iter().sum::<f64>() / n;
+    (profile.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1f64)).sqrt()
+}
+
+/// Bias score of a pile-up, summed over the site sets (lower is better)
+pub fn bias_score(pileup: &PileUp, site_sets: &Vec<SiteSet>, resolver: &ChromResolver) -> f64 {
+    site_sets.iter().map(|set| profile_deviation(&set.profile(pileup, resolver))).sum()
+}
+
+/// Masks with one more unmasked position, replacing an X by an N
+pub fn neighbours(mask: &str) -> Vec<String> {
+    let chars: Vec<char> = mask.chars().collect();
+    (0..chars.len()).filter(|&idx| chars[idx] == 'X' || chars[idx] == 'x').map(|idx| {
+        let mut next = chars.clone();
+        next[idx] = 'N';
+        next.into_iter().collect()
+    }).collect()
+}
+
+/// Starting from `start_mask`, repeatedly move to the best scoring neighbour until all positions
+/// are unmasked. Returns the (score, mask) pair chosen at each step.
+pub fn hill_climb<F: FnMut(&str) -> f64>(start_mask: &str, mut score: F) -> Vec<(f64, String)> {
+    let mut steps = Vec::new();
+    let mut candidates = neighbours(start_mask);
+
+    while !candidates.is_empty() {
+        let mut best: Option<(f64, String)> = None;
+        for mask in candidates {
+            let value = score(&mask);
+            if best.as_ref().map_or(true, |&(best_value, _)| value < best_value) {
+                best = Some((value, mask));
+            }
+        }
+        let (value, mask) = best.unwrap();
+        println!("# masksearch step {}: {}\t{}", steps.len() + 1, value, mask);
+        candidates = neighbours(&mask);
+        steps.push((value, mask));
+    }
+    steps
+}
+
+// read cut-site, `slot` locates its table position in `MaskScorer::positions`
+struct ReadSite {
+    slot: usize,
+    pos: i32,
+    reverse: bool,
+}
+
+/// Bias scores of candidate masks, computed in memory
+///
+/// The genome, the read cut-sites and the site windows are read once. Each mask then re-indexes the genome
+/// k-mers, keeping the table values at the read cut-sites only.
+pub struct MaskScorer {
+    sequences: Vec<(String, Vec<u8>)>,
+    seqinfos: Vec<SequenceInfo>,
+    tallymer: OsString,
+    read_length: u16,
+    strand_specific: bool,
+    softmask: SoftMask,
+    shift: bool,
+    shift_amounts: Option<(i32, i32)>,
+    // per sequence: sorted table positions of the cut-sites, reads and merged site windows
+    positions: Vec<Vec<u32>>,
+    reads: Vec<Vec<ReadSite>>,
+    windows: Vec<Vec<(u32, u32)>>,
+    site_sets: Vec<SiteSet>,
+    resolver: ChromResolver,
+}
+
+impl MaskScorer {
+    pub fn new(fasta_file: &str, tallymer: &OsStr, read_length: u16, strand_specific: bool, softmask: SoftMask, inputs: &Vec<String>, options: &ReadOptions, shift: bool, shift_amounts: &Option<(i32, i32)>, site_sets: Vec<SiteSet>) -> MaskScorer {
+        let sequences: Vec<(String, Vec<u8>)> = match fasta::fasta_sequences(fasta_file).collect() {
+            Ok(sequences) => sequences,
+            Err(err) => {
+                println!("Error: Failed to read FASTA file '{}': {}", fasta_file, err);
+                exit(1);
+            },
+        };
+        let seqinfos: Vec<SequenceInfo> = sequences.iter().map(|&(ref name, ref bases)| {
+            SequenceInfo { name: name.clone(), length: fasta::table_length(bases) }
+        }).collect();
+        let mut resolver = options.chroms.resolver(&seqinfos);
+
+        // read cut-sites
+        let mut sites: Vec<Vec<(u32, i32, bool)>> = seqinfos.iter().map(|_| Vec::new()).collect();
+        let mut split = ReadSplit::None;
+        for filename in inputs {
+            let mut source = source::open(filename, read_length as usize, options);
+            resolver.check_lengths(&source.declared_sequences(), options.allow_length_mismatch);
+            let mut pending = source.next_event(&mut split);
+            while let Some(first) = pending {
+                let chrom = first.chrom;
+                let sidx = match resolver.sequence_index(source.chrom_name(chrom)) {
+                    Some(idx) => idx,
+                    None => {
+                        pending = skip_sequence(&mut *source, &mut split, &mut resolver, first);
+                        continue;
+                    },
+                };
+                let slen = seqinfos[sidx].length as i32;
+                let mut event = first;
+                pending = loop {
+                    for site in event.sites {
+                        let pos = site.table_pos(read_length as usize);
+                        if pos >= slen {
+                            println!("WARN:{}:{}: read extends beyond reference sequence length ({} > {}). Read ignored.", seqinfos[sidx].name, site.pos, pos + 1, slen);
+                        } else if pos >= 0 {
+                            sites[sidx].push((pos as u32, site.pos, site.reverse));
+                        }
+                    }
+                    event = match source.next_event(&mut split) {
+                        Some(next) => if next.chrom == chrom { next } else { break Some(next) },
+                        None => break None,
+                    };
+                };
+            }
+        }
+        resolver.report();
+
+        let mut positions = Vec::with_capacity(sites.len());
+        let mut reads = Vec::with_capacity(sites.len());
+        for seq_sites in sites {
+            let mut seq_positions: Vec<u32> = seq_sites.iter().map(|&(table_pos, _, _)| table_pos).collect();
+            seq_positions.sort();
+            seq_positions.dedup();
+            reads.push(seq_sites.into_iter().map(|(table_pos, pos, reverse)| {
+                ReadSite { slot: seq_positions.binary_search(&table_pos).unwrap(), pos: pos, reverse: reverse }
+            }).collect());
+            positions.push(seq_positions);
+        }
+
+        // site windows
+        let mut windows: Vec<Vec<(u32, u32)>> = seqinfos.iter().map(|_| Vec::new()).collect();
+        for set in &site_sets {
+            for site in &set.sites {
+                if let Some(idx) = resolver.resolve(&site.chrom) {
+                    windows[idx].push((site.start, site.start + set.width));
+                }
+            }
+        }
+        for seq_windows in windows.iter_mut() {
+            seq_windows.sort();
+            let mut merged: Vec<(u32, u32)> = Vec::with_capacity(seq_windows.len());
+            for &(start, end) in seq_windows.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *seq_windows = merged;
+        }
+
+        // fragment sites are shifted before the k-mer lookup, so the pile-up itself is not shifted
+        let (shift, shift_amounts) = if options.fragment_shift.is_some() { (false, Some((0, 0))) } else { (shift, *shift_amounts) };
+
+        MaskScorer {
+            sequences: sequences,
+            seqinfos: seqinfos,
+            tallymer: tallymer.to_os_string(),
+            read_length: read_length,
+            strand_specific: strand_specific,
+            softmask: softmask,
+            shift: shift,
+            shift_amounts: shift_amounts,
+            positions: positions,
+            reads: reads,
+            windows: windows,
+            site_sets: site_sets,
+            resolver: resolver,
+        }
+    }
+
+    fn in_window(&self, chrom_idx: usize, pos: i32) -> bool {
+        if pos < 0 {
+            return false;
+        }
+        let pos = pos as u32;
+        let windows = &self.windows[chrom_idx];
+        // last window starting at or before pos
+        match windows.binary_search_by(|&(start, _)| start.cmp(&pos)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(idx) => pos < windows[idx - 1].1,
+        }
+    }
+
+    /// Bias score of the scaled read pile-up obtained with `mask`
+    pub fn score_mask(&self, mask: &str) -> f64 {
+        let params = SeqTableParams::new(0, 0, 0, self.read_length, &Some(mask.to_string()), self.strand_specific);
+        let (values, genome) = fasta::index_positions(&self.sequences, &self.tallymer, &params, self.softmask, &self.positions);
+
+        // minus strand sites use the reverse complement k-mer
+        let kmer = |sidx: usize, read: &ReadSite| {
+            let (plus, minus) = values[sidx][read.slot];
+            if read.reverse { minus } else { plus }
+        };
+
+        let mut counts = GroupCounts::new(genome);
+        for (sidx, seq_reads) in self.reads.iter().enumerate() {
+            for read in seq_reads {
+                counts.add_site(0, kmer(sidx, read), read.reverse);
+            }
+        }
+
+        // only the pile-up within the site windows is scored
+        let scale = ScaleFactors::new(&counts, 0);
+        let (plus_shift, minus_shift) = scale::pileup_shifts(&params, self.shift, &self.shift_amounts);
+        let mut pileup = PileUp::new(&self.seqinfos, plus_shift, minus_shift, false);
+        for (sidx, seq_reads) in self.reads.iter().enumerate() {
+            for read in seq_reads {
+                let shifted = read.pos + if read.reverse { minus_shift } else { plus_shift };
+                if self.in_window(sidx, shifted) {
+                    pileup.add(sidx, read.pos, read.reverse, kmer(sidx, read), &scale);
+                }
+            }
+        }
+
+        bias_score(&pileup, &self.site_sets, &self.resolver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neighbours_unmask_one_position() {
+        assert_eq!(vec!["NXCX".to_string(), "XNCX".to_string(), "XXCN".to_string()], neighbours("XXCX"));
+        assert!(neighbours("NNCN").is_empty());
+    }
+
+    #[test]
+    fn hill_climb_follows_best_neighbour() {
+        // score favours unmasking the positions next to the cut-site first
+        let steps = hill_climb("XXCXX", |mask| {
+            let weights = [4f64, 1f64, 0f64, 2f64, 3f64];
+            mask.chars().zip(weights.iter()).map(|(c, w)| if c == 'N' { *w } else { 0f64 }).sum()
+        });
+        let masks: Vec<&str> = steps.iter().map(|&(_, ref mask)| mask.as_str()).collect();
+        assert_eq!(vec!["XNCXX", "XNCNX", "XNCNN", "NNCNN"], masks);
+        assert_eq!(10f64, steps[3].0);
+    }
+
+    #[test]
+    fn deviation_of_flat_profile_is_zero() {
+        assert_eq!(0f64, profile_deviation(&vec![2f64, 2f64, 2f64]));
+        assert_eq!(1f64, profile_deviation(&vec![1f64, 2f64, 3f64]));
+    }
+}
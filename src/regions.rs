@@ -0,0 +1,124 @@
+//!
+//!	Regions read from BED files, as non-overlapping ranges per sequence table sequence.
+//!
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+use std::io::ErrorKind;
+use std::io::Error as ioError;
+use std::ops::Range;
+use std::cmp::Ordering;
+use chroms::ChromResolver;
+
+/// Non-overlapping BED regions per sequence, sorted by start
+pub struct BedRanges {
+  sets: Vec<Vec<(u32, u32)>>,
+  chrom_idx: usize,
+  row_idx: usize,
+}
+
+impl BedRanges {
+  
+  pub fn parse(filename: &str, chroms: Vec<String>, resolver: &ChromResolver) -> Result<BedRanges> {
+    let file = try!(File::open(filename));
+    let reader = BufReader::new(file);
+    let mut n_lines = 0;
+    let mut n_no_chrom = 0;
+    let mut sets = Vec::new();
+    
+    for _ in 0..chroms.len() {
+      sets.push(Vec::new());
+    }
+    
+    for res in reader.lines() {
+      let line = try!(res);
+      let parts: Vec<&str> = line.split('\t').collect();
+      
+      //
+      if parts.len() < 3 {
+        return Err(ioError::new(ErrorKind::Other, format!("not enough fields in line '{}'", line)));
+      }
+      n_lines += 1;
+      
+      // locate chromosome in set
+      let idx = match resolver.resolve(parts[0]) {
+        Some(idx) => idx,
+        None => {
+          n_no_chrom += 1;
+          continue;
+        }
+      };
+      
+      // add region to set
+      let start: u32 = match parts[1].parse() {
+        Ok(value) => value,
+        Err(_) => return Err(ioError::new(ErrorKind::Other, format!("invalid field value in line '{}'", line))),
+      };
+      let end: u32 = match parts[2].parse() {
+        Ok(value) => value,
+        Err(_) => return Err(ioError::new(ErrorKind::Other, format!("invalid field value in line '{}'", line))),
+      };
+      sets[idx].push((start, end));
+    }
+    
+    // sort sets by starting position
+    for i in 0..sets.len() {
+      sets[i].sort_by(|a, b| a.0.cmp(&b.0)); // TODO: consider converting this to sort_by_key - requires rust 1.7
+    }
+    
+    // check that no stored range overlaps
+    for i in 0..sets.len() {
+      for j in 1..sets[i].len() {
+        if sets[i][j].0 < sets[i][j - 1].1 {
+          return Err(ioError::new(ErrorKind::Other, format!("overlapping ranges in {}: {}-{} & {}-{}", chroms[i], sets[i][j - 1].0, sets[i][j - 1].1, sets[i][j].0, sets[i][j].1)));
+        }
+      }
+    }
+    
+    if n_no_chrom > 0 {
+      println!("WARN:{}: {} rows ({:.2} %) from unknown chromosomes.", filename, n_no_chrom, n_no_chrom as f32 / n_lines as f32 * 100.0);
+    }
+    
+    Ok(BedRanges{ sets: sets, chrom_idx: 0, row_idx: 0})
+  }
+  
+  /// (start, end) regions of sequence `chrom_idx`
+  pub fn ranges(&self, chrom_idx: usize) -> &Vec<(u32, u32)> {
+      &self.sets[chrom_idx]
+  }
+
+  /// Check if `position` of sequence `chrom_idx` is within a region
+  pub fn contains(&self, chrom_idx: usize, position: i32) -> bool {
+      if position < 0 { return false; }
+      let position = position as u32;
+      self.sets[chrom_idx].binary_search_by(|probe| {
+          if probe.0 <= position && probe.1 > position {
+              Ordering::Equal
+          } else if probe.0 > position {
+              Ordering::Greater
+          } else {
+              Ordering::Less
+          }
+      }).is_ok()
+  }
+}
+
+// Iterator over BED regions returns chromosome index (as per supplied map) and the region coordinates in the form of a iteratable Range
+impl Iterator for BedRanges {
+  type Item = (usize, Range<u32>);
+  
+  fn next(&mut self) -> Option<(usize, Range<u32>)> {
+    if self.row_idx >= self.sets[self.chrom_idx].len() {
+      self.row_idx = 0;
+      self.chrom_idx += 1;
+    }
+    if self.chrom_idx >= self.sets.len() {
+      return None;
+    }
+    
+    let result = Some((self.chrom_idx, Range { start: self.sets[self.chrom_idx][self.row_idx].0, end: self.sets[self.chrom_idx][self.row_idx].1}));
+    self.row_idx += 1;
+    result
+  }
+}
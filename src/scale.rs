@@ -1,10 +1,6 @@
 //!
 //! Code to create a scaled read-count track 
 //!
-use htslib::bam;
-use htslib::bam::Read;
-use htslib::bam::Reader;
-use htslib::bam::Records;
 use std::path::Path;
 use std::error::Error;
 use std::fs::File;
@@ -15,13 +11,16 @@ use std::io::Write as ioWrite;
 use std::io::Seek;
 use std::io::Error as ioError;
 use std::process::exit;
-use std::iter::Peekable;
-use seqtable::{SeqTable,SequenceInfo};
+use seqtable::{SeqTable,SeqTableParams,SequenceInfo,NmerCount};
+use counts::GroupCounts;
 use std::collections::BTreeMap;
 use std::collections::btree_map::Iter;
 use bigwig::write_bigwig;
 use bigwig::Strand;
-use filter::{RecordCheck, PairedChecker, SingleChecker, PairPosition};
+use split::ReadSplit;
+use source;
+use source::{AlignmentSource, SiteEvent, ReadOptions, skip_sequence};
+use chroms::{ChromAliases, ChromResolver};
 use outputfile::OutFilename;
 
 #[derive(Debug)]
@@ -36,7 +35,7 @@ pub struct PileUp {
 
 impl PileUp {
     
-    fn new(sinfos: &Vec<SequenceInfo>, plus_shift: i32, minus_shift: i32, no_scale: bool) -> PileUp {
+    pub fn new(sinfos: &Vec<SequenceInfo>, plus_shift: i32, minus_shift: i32, no_scale: bool) -> PileUp {
         let mut chroms = Vec::new();
         let mut counts = Vec::new();
         let mut chrom_sizes = Vec::new();
@@ -57,57 +56,59 @@ impl PileUp {
         }
     }
     
-    fn add_data<R: ioRead+Seek, C: RecordCheck>(&mut self, table: &mut SeqTable<R>, bamrecs: &mut Peekable<Records<Reader>>, tid: &mut i32, map: &Vec<usize>, scale: &Vec<(f64, f64)>, checker: &C) -> bool {
-        // skip unmapped sequences (tid = -1)
-        if *tid < 0 {
-            match bamrecs.next() {
-                Some(Ok(ref rec)) => { *tid = rec.tid(); return true; },
-                Some(Err(_)) => return false,
-                None => return false,
-            }
-        }
-        
-        let sidx = map[*tid as usize];
+    // add the reads on one sequence to their pile-ups, returns the first event on the next sequence
+    fn add_data<R: ioRead+Seek>(pileups: &mut Vec<PileUp>, split: &mut ReadSplit, table: &mut SeqTable<R>, chroms: &mut ChromResolver, source: &mut AlignmentSource, scales: &Vec<ScaleFactors>, first: SiteEvent) -> Option<SiteEvent> {
+        let chrom = first.chrom;
+        let sidx = match chroms.sequence_index(source.chrom_name(chrom)) {
+            Some(idx) => idx,
+            None => return skip_sequence(source, split, chroms, first),
+        };
         let rlen = table.params.read_length as usize;
         let slen = table.len_by_idx(sidx).ok().expect("read sequence length") as i32;
         let mut rdr = table.get_sequence_by_idx(sidx).ok().expect("read sequence");
+        let mut event = first;
 
         loop {
-            // check if we changed sequence
-            match bamrecs.peek() {
-                Some(&Ok(ref rec)) => if rec.tid() != *tid { *tid = rec.tid(); return true; },
-                Some(&Err(_)) => return false,
-                None => return false,
-            }
-            
-            // if not count position
-            if let Some(Ok(record)) = bamrecs.next() {
-                if checker.valid(&record) {
-                    let pos = checker.vir_pos(&record);
+            // select pile-up (and scale factors) of the read group
+            let group = event.group;
+            if group < pileups.len() {
+                let counts_group = if split.split_counts() { group } else { 0 };
+                let scale = &scales[counts_group];
+                let pileup = &mut pileups[group];
+
+                for site in event.sites {
+                    let pos = site.table_pos(rlen);
                     if pos < slen {
                         let (plus_idx, minus_idx) = rdr.vir_get(pos).unwrap();
                         
-                        if record.is_reverse() {
-                            if minus_idx == 0 {
-                                /* no data */
-                            } else {
-                                let inc = if self.no_scale { 1f64 } else { scale[minus_idx as usize].1 };
-                                let minus_pos = (checker.vir_pos(&record) + rlen as i32 - 1i32 + self.minus_shift) as u32;
-                                self.counts[sidx as usize].entry(minus_pos).or_insert((0f64, 0f64)).1 += inc;
-                            }
+                        if site.reverse {
+                            pileup.add(sidx, site.pos, true, minus_idx, scale);
                         } else {
-                            if plus_idx == 0 {
-                                /* no data */
-                            } else {
-                                let inc = if self.no_scale { 1f64 } else { scale[plus_idx as usize].0 };
-                                let plus_pos = (checker.vir_pos(&record) + self.plus_shift) as u32;
-                                self.counts[sidx as usize].entry(plus_pos).or_insert((0f64, 0f64)).0 += inc;
-                            }
+                            pileup.add(sidx, site.pos, false, plus_idx, scale);
                         }
                     }
                 }
-            } 
+            }
+
+            // check if we changed sequence
+            event = match source.next_event(split) {
+                Some(next) => if next.chrom == chrom { next } else { return Some(next) },
+                None => return None,
+            };
+        }
+    }
+    
+    /// Add the read cut-site at `pos`, with k-mer index `idx` in the sequence table, at its shifted position
+    pub fn add(&mut self, chrom_idx: usize, pos: i32, reverse: bool, idx: u64, scale: &ScaleFactors) {
+        let slen = self.chrom_sizes[chrom_idx] as i32;
+        let shifted = pos + if reverse { self.minus_shift } else { self.plus_shift };
+        if idx == 0 || shifted < 0 || shifted >= slen {
+            /* no data */
+            return;
         }
+        let inc = if self.no_scale { 1f64 } else if reverse { scale.get(idx).1 } else { scale.get(idx).0 };
+        let entry = self.counts[chrom_idx].entry(shifted as u32).or_insert((0f64, 0f64));
+        if reverse { entry.1 += inc } else { entry.0 += inc }
     }
     
     pub fn write_bed(&self, filename: &OutFilename, stranded: bool, both_positive: bool) -> Result<(),ioError> {
@@ -199,23 +200,66 @@ fn scale_factor(exp: u64, etotal: u64, obs: u64, ototal: u64) -> f64 {
     if fobs > 0f64 { fexp / fobs } else { 0f64 }
 }
 
-fn compute_scale_factors(counts: &Vec<(u64, u64, u64, u64)>) -> Vec<(f64, f64)> {
-    // compute totals
-    // skip first row which contains unmappable/unusable position counts
-    let totals = counts.iter().skip(1).fold((0u64, 0u64, 0u64, 0u64),
-        |acc, &(sp, sm, bp, bm)| ( acc.0 + sp, acc.1 + sm, acc.2 + bp, acc.3 + bm));
- 
-    // compute scale
+/// Scale factors of one read group, computed from its k-mer counts as needed
+pub struct ScaleFactors<'a> {
+    counts: &'a GroupCounts,
+    group: usize,
+    totals: NmerCount,
+}
+
+impl<'a> ScaleFactors<'a> {
+    pub fn new(counts: &'a GroupCounts, group: usize) -> ScaleFactors<'a> {
+        // totals skip the first row which contains unmappable/unusable position counts
+        ScaleFactors { counts: counts, group: group, totals: counts.totals(group) }
+    }
+
     // x = Obs * ExpFreq / ObsFreq
     // scale = ExpFreq / ObsFreq
-    counts.iter().map(|&(sp, sm, bp, bm)| {
-        ( scale_factor(sp, totals.0, bp, totals.2),
-          scale_factor(sm, totals.1, bm, totals.3)
+    pub fn get(&self, idx: u64) -> (f64, f64) {
+        let (sp, sm, bp, bm) = self.counts.get(self.group, idx);
+        ( scale_factor(sp, self.totals.0, bp, self.totals.2),
+          scale_factor(sm, self.totals.1, bm, self.totals.3)
         )
-    }).collect()
+    }
 }
 
-pub fn scale(seqfile: &str, counts: &Vec<(u64, u64, u64, u64)>, bamfiles: &Vec<String>, minqual: u8, shift: bool, shift_amounts: &Option<(i32, i32)>, no_scale: bool, pair_range: &Option<(i32, i32)>, paired: bool, exact_length: bool, tail_edge: bool, pair_side: Option<PairPosition>) -> PileUp {
+/// Pile-up (plus, minus) shifts, `shift` moves minus strand cut-sites to the plus strand cut-site of the k-mer
+pub fn pileup_shifts(params: &SeqTableParams, shift: bool, shift_amounts: &Option<(i32, i32)>) -> (i32, i32) {
+    let minus_shift = if shift {
+        (params.plus_offset as i16 - (params.kmer_length as i16 - params.minus_offset as i16 - 1i16)) as i32
+    } else {
+        if let Some(( _, minus)) = *shift_amounts {
+            minus
+        } else {
+            0i32
+        }
+    };
+    let plus_shift = if let Some((plus, _)) = *shift_amounts {
+        plus
+    } else {
+        0i32
+    };
+    (plus_shift, minus_shift)
+}
+
+/// Pile-ups of the (scaled) read cut-sites in the input files, one per read group, see `counts::tabulate`
+pub fn scale(seqfile: &str, counts: &GroupCounts, inputs: &Vec<String>, options: &ReadOptions, shift: bool, shift_amounts: &Option<(i32, i32)>, no_scale: bool, split: &mut ReadSplit) -> Vec<PileUp> {
+    let read_length = SeqTableParams::from_file(seqfile).read_length as usize;
+    let sources = inputs.iter().map(|filename| {
+        println!("# scale {}", filename);
+        source::open(filename, read_length, options)
+    });
+
+    // fragment sites are shifted before the k-mer lookup, so the pile-up itself is not shifted
+    if options.fragment_shift.is_some() {
+        scale_sources(seqfile, counts, sources, false, &Some((0, 0)), no_scale, &options.chroms, split)
+    } else {
+        scale_sources(seqfile, counts, sources, shift, shift_amounts, no_scale, &options.chroms, split)
+    }
+}
+
+/// Same as `scale`, reading cut-sites from already opened `sources`
+pub fn scale_sources<I: IntoIterator<Item=Box<AlignmentSource>>>(seqfile: &str, counts: &GroupCounts, sources: I, shift: bool, shift_amounts: &Option<(i32, i32)>, no_scale: bool, aliases: &ChromAliases, split: &mut ReadSplit) -> Vec<PileUp> {
     // read
     let file = match File::open(seqfile) {
         Ok(value) => value,
@@ -233,87 +277,22 @@ pub fn scale(seqfile: &str, counts: &Vec<(u64, u64, u64, u64)>, bamfiles: &Vec<S
     };
     
     let seqinfos = table.sequences();
-    let rlen = table.params.read_length as usize;
-    let minus_shift = if shift {
-        let res = (table.params.plus_offset as i16 - (table.params.kmer_length as i16 - table.params.minus_offset as i16 - 1i16)) as i32;
-        println!("# minus strand shift = {} bp", res);
-        res
-    } else {
-        if let Some(( _, minus)) = *shift_amounts {
-            minus
-        } else {
-            0i32
-        }
-    };
-    let plus_shift = if let Some((plus, _)) = *shift_amounts {
-        plus
-    } else {
-        0i32
-    };
+    let mut chroms = aliases.resolver(&seqinfos);
+    let (plus_shift, minus_shift) = pileup_shifts(&table.params, shift, shift_amounts);
+    if shift {
+        println!("# minus strand shift = {} bp", minus_shift);
+    }
 
-    let mut pileup = PileUp::new(&seqinfos, plus_shift, minus_shift, no_scale);
+    let mut pileups: Vec<PileUp> = (0..split.len()).map(|_| PileUp::new(&seqinfos, plus_shift, minus_shift, no_scale)).collect();
+    let scales: Vec<ScaleFactors> = (0..counts.len()).map(|group| ScaleFactors::new(counts, group)).collect();
     
-    for bamfile in bamfiles {
-        println!("# scale {}", &bamfile);
-        let bam = match bam::Reader::from_path(&Path::new(bamfile)) {
-            Ok(value) => value,
-            Err(err) => {
-                println!("Error: Failed to open BAM file '{}': {}", bamfile, err.description());
-                exit(1);
-            },
-        };
-        let names = bam.header().target_names();
-        let mut cur_tid = 0;
-        
-        // map BAM tid's to SeqTable idx's
-        let map : Vec<usize> = names.iter().map(|&id| {
-            let chrom = String::from_utf8_lossy(id);
-            for i in 0..seqinfos.len() {
-                if seqinfos[i].name.eq(&chrom) {
-                    return i;
-                }
-            }
-            println!("Error: Unknown sequence name in BAM: {}", chrom);
-            exit(1);
-        }
-        ).collect();
-        
-        // reads        
-        let mut iter = bam.records().peekable();
-        let scale = compute_scale_factors(&counts);
-        
-        if pair_range.is_some() || paired {
-            let checker = match *pair_range {
-                Some((min, max)) => PairedChecker {
-                    tail_edge: tail_edge,
-                    exact_length: exact_length,
-                    read_length: rlen,
-                    min_quality: minqual,
-                    min_dist: min,
-                    max_dist: max,
-                    force_paired: paired,
-                    max_distance: true,
-                    select_pair: pair_side
-
-                },
-                None => PairedChecker {
-                    tail_edge: tail_edge,
-                    exact_length: exact_length,
-                    read_length: rlen,
-                    min_quality: minqual,
-                    min_dist: 0,
-                    max_dist: 0,
-                    force_paired: paired,
-                    max_distance: false,
-                    select_pair: pair_side
-                },
-            };
-            while pileup.add_data(&mut table, &mut iter, &mut cur_tid, &map, &scale, &checker) {}
-        } else {
-            let checker = SingleChecker { tail_edge: tail_edge, exact_length: exact_length, read_length: rlen, min_quality: minqual };
-            while pileup.add_data(&mut table, &mut iter, &mut cur_tid, &map, &scale, &checker) {}
+    for mut source in sources {
+        let mut pending = source.next_event(split);
+        while let Some(event) = pending {
+            pending = PileUp::add_data(&mut pileups, split, &mut table, &mut chroms, &mut *source, &scales, event);
         }
     }
+    chroms.report();
     
-    pileup
+    pileups
 }
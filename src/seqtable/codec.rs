@@ -0,0 +1,154 @@
+//!
+//!	Conversion between sequence table k-mer indexes and k-mer strings, following the k-mer mask.
+//!
+use super::{SeqTableParams, MaskPos};
+
+const BASES: [char; 4] = ['A', 'C', 'G', 'T'];
+
+/// Maps table indexes (1-based, index 0 holds the unmappable positions) to k-mer strings and back
+///
+/// Excluded (X) positions decode to N, base class positions to their IUPAC letter.
+#[derive(Clone, Debug)]
+pub struct KmerCodec {
+    mask: Vec<MaskPos>,
+}
+
+fn complement(c: char) -> char {
+    match c {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' => 'A',
+        'R' => 'Y',
+        'Y' => 'R',
+        'K' => 'M',
+        'M' => 'K',
+        // S, W and N are their own complement
+        other => other,
+    }
+}
+
+impl KmerCodec {
+    pub fn new(params: &SeqTableParams) -> KmerCodec {
+        let mask = match params.mask {
+            Some(ref mask) => mask.clone(),
+            None => vec![MaskPos::Base; params.unmasked_count as usize],
+        };
+        KmerCodec { mask: mask }
+    }
+
+    /// k-mer length, including the excluded positions
+    pub fn kmer_length(&self) -> usize {
+        self.mask.len()
+    }
+
+    /// Number of table indexes, including index 0
+    pub fn nmer_count(&self) -> u64 {
+        self.mask.iter().fold(1u64, |acc, pos| acc * pos.radix() as u64) + 1
+    }
+
+    /// k-mer of table index `idx`, an error unless `1 <= idx < nmer_count()`
+    pub fn decode(&self, idx: u64) -> Result<String, String> {
+        if idx == 0 || idx >= self.nmer_count() {
+            return Err(format!("k-mer index {} out of range (1 to {})", idx, self.nmer_count() - 1));
+        }
+        let mut value = idx - 1;
+        let mut key = Vec::with_capacity(self.mask.len());
+
+        // last position is the least significant digit
+        for pos in self.mask.iter().rev() {
+            let digit = (value % pos.radix() as u64) as usize;
+            value /= pos.radix() as u64;
+            key.push(match *pos {
+                MaskPos::Excluded => 'N',
+                MaskPos::Base => BASES[digit],
+                MaskPos::Class(class) => class.letters()[digit],
+            });
+        }
+        Ok(key.iter().rev().collect())
+    }
+
+    /// Table index of `kmer` (case insensitive)
+    ///
+    /// Excluded positions accept any character. Base class positions accept a base or one of the class letters.
+    pub fn encode(&self, kmer: &str) -> Result<u64, String> {
+        let chars: Vec<char> = kmer.chars().map(|c| c.to_ascii_uppercase()).collect();
+        if chars.len() != self.mask.len() {
+            return Err(format!("k-mer '{}' must have length {}", kmer, self.mask.len()));
+        }
+        let mut idx = 0u64;
+        for (&c, pos) in chars.iter().zip(&self.mask) {
+            let base = BASES.iter().position(|&b| b == c);
+            let digit = match (*pos, base) {
+                (MaskPos::Excluded, _) => 0,
+                (MaskPos::Base, Some(base)) => base as u64,
+                (MaskPos::Class(class), Some(base)) => class.symbol(base as u8) as u64,
+                (MaskPos::Class(class), None) => match class.letters().iter().position(|&l| l == c) {
+                    Some(symbol) => symbol as u64,
+                    None => return Err(format!("k-mer '{}': invalid character '{}' for mask position {}", kmer, c, pos.to_char())),
+                },
+                (MaskPos::Base, None) => return Err(format!("k-mer '{}': invalid base '{}'", kmer, c)),
+            };
+            idx = idx * pos.radix() as u64 + digit;
+        }
+        Ok(idx + 1)
+    }
+
+    /// Table index of the reverse complement of the k-mer of index `idx`, as used for the minus strand of
+    /// strand specific tables
+    ///
+    /// None when `idx` is out of range, or when the mask is not symmetric and the reverse complement can't be
+    /// determined, i.e., an included position falls on an excluded position of `idx` or on a different base class.
+    pub fn revcomp(&self, idx: u64) -> Option<u64> {
+        let kmer = match self.decode(idx) {
+            Ok(kmer) => kmer,
+            Err(_) => return None,
+        };
+        let rc: String = kmer.chars().rev().map(complement).collect();
+        if rc.chars().zip(&self.mask).any(|(c, &pos)| c == 'N' && pos != MaskPos::Excluded) {
+            return None;
+        }
+        self.encode(&rc).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let codec = KmerCodec::new(&SeqTableParams::new(3, 1, 1, 36, &None, false));
+        assert_eq!(65, codec.nmer_count());
+        assert_eq!(Ok("AAA".to_string()), codec.decode(1));
+        assert_eq!(Ok("TTT".to_string()), codec.decode(64));
+        assert!(codec.decode(0).is_err());
+        assert!(codec.decode(65).is_err());
+        assert_eq!(None, codec.revcomp(65));
+        assert_eq!(Ok(28), codec.encode("cgt"));
+        assert_eq!(Ok("CGT".to_string()), codec.decode(28));
+        assert!(codec.encode("CGN").is_err());
+        assert!(codec.encode("CG").is_err());
+    }
+
+    #[test]
+    fn masked_kmers() {
+        let codec = KmerCodec::new(&SeqTableParams::new(0, 0, 0, 36, &Some("NXCRN".to_string()), false));
+        assert_eq!(4 * 2 * 4 + 1, codec.nmer_count());
+        // excluded positions accept anything and decode to N
+        assert_eq!(codec.encode("GAGT"), codec.encode("GTAT"));
+        assert_eq!(Ok("GNRT".to_string()), codec.decode(codec.encode("GAGT").unwrap()));
+        assert_eq!(codec.encode("GNRT"), codec.encode("GCGT"));
+        assert!(codec.encode("GNST").is_err());
+    }
+
+    #[test]
+    fn reverse_complement() {
+        let codec = KmerCodec::new(&SeqTableParams::new(0, 0, 0, 36, &Some("NRCRN".to_string()), true));
+        let idx = codec.encode("GRYA").unwrap();
+        assert_eq!(Ok("TRYC".to_string()), codec.decode(codec.revcomp(idx).unwrap()));
+        // NX mask is not symmetric, the excluded base is unknown on the other strand
+        let codec = KmerCodec::new(&SeqTableParams::new(0, 0, 0, 36, &Some("NCX".to_string()), true));
+        assert_eq!(None, codec.revcomp(1));
+    }
+}
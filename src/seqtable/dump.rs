@@ -15,8 +15,8 @@ fn dump_header<R: Read + Seek>(table: &SeqTable<R>) {
     println!("# plus-offset:  {}", params.plus_offset);
     println!("# minus-offset: {}", params.minus_offset);
     println!("# read-size:    {}", params.read_length);
-    if let Some(ref mask) = params.mask {
-        println!("# kmer-mask: {}", mask.iter().map(|&flag| if flag { 'N' } else { 'X' } ).collect::<String>());
+    if let Some(mask) = params.mask_string() {
+        println!("# kmer-mask: {}", mask);
     };
     println!("# strand-specific: {}", params.strand_specific);
 }
@@ -0,0 +1,276 @@
+//!
+//!	Summary and integrity check of a sequence table, printed as text or JSON.
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek};
+use rustc_serialize::json::{Json, ToJson};
+use manifest::params_json;
+use super::{SeqTable, SeqTableParams, SoftMask};
+
+/// Size of one sequence in the table
+pub struct SequenceSummary {
+    pub name: String,
+    pub length: u32,
+    pub blocks: usize,
+    /// Size of the blocks before compression, in bytes
+    pub encoded_size: u64,
+    pub compressed_size: u64,
+}
+
+/// Table parameters and sizes, without decoding the sequence blocks
+pub struct TableInfo {
+    pub params: SeqTableParams,
+    pub softmask: SoftMask,
+    pub block_length: u32,
+    pub file_size: u64,
+    pub sequences: Vec<SequenceSummary>,
+    /// Unmappable (index 0) positions on the plus and minus strands, from the genome counts
+    pub unmappable: (u64, u64),
+}
+
+fn ratio(count: u64, total: u64) -> f64 {
+    if total > 0 { count as f64 / total as f64 } else { 0f64 }
+}
+
+fn open_table(filename: &str) -> Result<SeqTable<File>, String> {
+    let file = try!(File::open(filename).map_err(|err| format!("Failed to open sequence table file '{}': {}", filename, err)));
+    SeqTable::open(file).map_err(|err| format!("Failed to read sequence table file '{}': {}", filename, err))
+}
+
+impl TableInfo {
+    pub fn positions(&self) -> u64 {
+        self.sequences.iter().map(|seq| seq.length as u64).sum()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.sequences.iter().map(|seq| seq.blocks).sum()
+    }
+
+    /// Size of the blocks before compression over their compressed size
+    pub fn compression_ratio(&self) -> f64 {
+        let encoded = self.sequences.iter().map(|seq| seq.encoded_size).sum();
+        ratio(encoded, self.sequences.iter().map(|seq| seq.compressed_size).sum())
+    }
+
+    /// Fraction of unmappable positions on the plus and minus strands
+    pub fn unmappable_fraction(&self) -> (f64, f64) {
+        (ratio(self.unmappable.0, self.positions()), ratio(self.unmappable.1, self.positions()))
+    }
+
+    pub fn print(&self) {
+        let unmappable = self.unmappable_fraction();
+        println!("kmer-size:          {}", self.params.kmer_length);
+        println!("kmer-mask:          {}", self.params.mask_string().unwrap_or("-".to_string()));
+        println!("plus-offset:        {}", self.params.plus_offset);
+        println!("minus-offset:       {}", self.params.minus_offset);
+        println!("read-size:          {}", self.params.read_length);
+        println!("strand-specific:    {}", self.params.strand_specific);
+        println!("softmask:           {}", self.softmask.name());
+        println!("file-size:          {}", self.file_size);
+        println!("block-length:       {}", self.block_length);
+        println!("blocks:             {}", self.block_count());
+        println!("compression-ratio:  {:.2}", self.compression_ratio());
+        println!("positions:          {}", self.positions());
+        println!("unmappable-plus:    {:.4}", unmappable.0);
+        println!("unmappable-minus:   {:.4}", unmappable.1);
+        println!("sequences:          {}", self.sequences.len());
+        for seq in &self.sequences {
+            println!("{}\t{}", seq.name, seq.length);
+        }
+    }
+}
+
+impl ToJson for TableInfo {
+    fn to_json(&self) -> Json {
+        let unmappable = self.unmappable_fraction();
+        let mut obj = BTreeMap::new();
+        obj.insert("params".to_string(), params_json(&self.params));
+        obj.insert("softmask".to_string(), self.softmask.name().to_json());
+        obj.insert("file_size".to_string(), self.file_size.to_json());
+        obj.insert("block_length".to_string(), self.block_length.to_json());
+        obj.insert("blocks".to_string(), self.block_count().to_json());
+        obj.insert("compression_ratio".to_string(), self.compression_ratio().to_json());
+        obj.insert("positions".to_string(), self.positions().to_json());
+        obj.insert("unmappable_plus".to_string(), unmappable.0.to_json());
+        obj.insert("unmappable_minus".to_string(), unmappable.1.to_json());
+        obj.insert("sequences".to_string(), Json::Array(self.sequences.iter().map(|seq| {
+            let mut entry = BTreeMap::new();
+            entry.insert("name".to_string(), seq.name.to_json());
+            entry.insert("length".to_string(), seq.length.to_json());
+            entry.insert("blocks".to_string(), seq.blocks.to_json());
+            entry.insert("encoded_size".to_string(), seq.encoded_size.to_json());
+            entry.insert("compressed_size".to_string(), seq.compressed_size.to_json());
+            Json::Object(entry)
+        }).collect()));
+        Json::Object(obj)
+    }
+}
+
+/// Parameters and sizes of sequence table `filename`
+pub fn table_info(filename: &str) -> Result<TableInfo, String> {
+    let mut table = try!(open_table(filename));
+    let counts = try!(table.counts().map_err(|err| format!("Failed to read sequence table file '{}': {}", filename, err)));
+    let unmappable = counts[0];
+    Ok(TableInfo {
+        params: table.params.clone(),
+        softmask: table.softmask,
+        block_length: table.block_length(),
+        file_size: fs::metadata(filename).map(|meta| meta.len()).unwrap_or(0),
+        sequences: table.infotable().iter().map(|info| SequenceSummary {
+            name: info.name.clone(),
+            length: info.length,
+            blocks: info.blocks.len(),
+            encoded_size: info.blocks.iter().map(|block| block.enc_size).sum(),
+            compressed_size: info.blocks.iter().map(|block| block.comp_size).sum(),
+        }).collect(),
+        unmappable: (unmappable.0, unmappable.1),
+    })
+}
+
+/// Result of decoding all blocks of a sequence table
+pub struct Validation {
+    pub sequences: usize,
+    pub blocks: u64,
+    pub positions: u64,
+    pub errors: Vec<String>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn print(&self) {
+        for error in &self.errors {
+            println!("ERROR: {}", error);
+        }
+        println!("{}: {} sequences, {} blocks, {} positions checked, {} errors",
+                 if self.is_valid() { "valid" } else { "invalid" }, self.sequences, self.blocks, self.positions, self.errors.len());
+    }
+}
+
+impl ToJson for Validation {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("valid".to_string(), self.is_valid().to_json());
+        obj.insert("sequences".to_string(), self.sequences.to_json());
+        obj.insert("blocks".to_string(), self.blocks.to_json());
+        obj.insert("positions".to_string(), self.positions.to_json());
+        obj.insert("errors".to_string(), self.errors.to_json());
+        Json::Object(obj)
+    }
+}
+
+// decode the blocks of every sequence, checking their lengths and table indexes
+fn validate_sequences<R: Read + Seek>(table: &mut SeqTable<R>, result: &mut Validation) {
+    let nmer_count = table.params.nmer_count();
+    let block_length = table.block_length() as usize;
+    let infotable = table.infotable().clone();
+    for info in &infotable {
+        let mut length = 0u64;
+        let mut decoded = true;
+        for (idx, block) in info.blocks.iter().enumerate() {
+            result.blocks += 1;
+            let values = match table.decode_block(block) {
+                Ok(values) => values,
+                Err(err) => {
+                    result.errors.push(format!("sequence '{}' block {}: {}", info.name, idx, err));
+                    decoded = false;
+                    continue;
+                },
+            };
+            let last = idx + 1 == info.blocks.len();
+            if values.len() > block_length || (!last && values.len() != block_length) || values.is_empty() {
+                result.errors.push(format!("sequence '{}' block {}: {} values, block length is {}", info.name, idx, values.len(), block_length));
+            }
+            if let Some(&(plus, minus)) = values.iter().find(|&&(plus, minus)| plus >= nmer_count || minus >= nmer_count) {
+                result.errors.push(format!("sequence '{}' block {}: k-mer index ({}, {}) out of range (< {})", info.name, idx, plus, minus, nmer_count));
+            }
+            length += values.len() as u64;
+        }
+        if decoded && length != info.length as u64 {
+            result.errors.push(format!("sequence '{}': blocks hold {} positions, length is {}", info.name, length, info.length));
+        }
+        result.positions += length;
+    }
+}
+
+/// Decode every block of sequence table `filename`, checking them against the sequence lengths and
+/// the genome counts
+pub fn validate_table(filename: &str) -> Validation {
+    let mut result = Validation { sequences: 0, blocks: 0, positions: 0, errors: Vec::new() };
+    let mut table = match open_table(filename) {
+        Ok(table) => table,
+        Err(err) => {
+            result.errors.push(err);
+            return result;
+        },
+    };
+    result.sequences = table.len();
+    validate_sequences(&mut table, &mut result);
+
+    match table.counts() {
+        Ok(counts) => {
+            if counts.len() != table.params.nmer_count() {
+                result.errors.push(format!("counts table has {} k-mer indexes, expected {}", counts.len(), table.params.nmer_count()));
+            }
+            let (plus, minus) = counts.iter().fold((0, 0), |acc, (_, count)| (acc.0 + count.0, acc.1 + count.1));
+            let positions = table.sequences().iter().map(|seq| seq.length as u64).sum();
+            if plus != positions || minus != positions {
+                result.errors.push(format!("counts table totals ({}, {}) differ from the {} table positions", plus, minus, positions));
+            }
+        },
+        Err(err) => result.errors.push(format!("counts table: {}", err)),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::{SeekFrom, Write};
+    use seqtable::tests::write_table;
+    use tempdir::TempDir;
+
+    #[test]
+    fn info_and_validation() {
+        let dir = TempDir::new("seqoutbias_info").unwrap();
+        let filename = dir.path().join("info.tbl").to_str().unwrap().to_string();
+        let params = SeqTableParams::new(1, 0, 0, 1, &None, false);
+        write_table(&filename, &params, vec![("chr1", vec![(1, 2), (0, 4), (0, 1)]), ("chr2", vec![(2, 0)])]);
+
+        let info = table_info(&filename).unwrap();
+        assert_eq!((4, 3), (info.positions(), info.block_count()));
+        assert_eq!((0.5, 0.25), info.unmappable_fraction());
+        assert_eq!(vec![2, 1], info.sequences.iter().map(|seq| seq.blocks).collect::<Vec<usize>>());
+        let validation = validate_table(&filename);
+        assert!(validation.is_valid());
+        assert_eq!((2, 3, 4), (validation.sequences, validation.blocks, validation.positions));
+
+        // recorded sizes beyond a full block are rejected before allocating
+        {
+            let mut table = open_table(&filename).unwrap();
+            let mut block = table.infotable()[0].blocks[0].clone();
+            block.enc_size = 1 << 40;
+            assert!(table.decode_block(&block).unwrap_err().to_string().contains("exceeds"));
+            block.enc_size = 1;
+            block.comp_size = 1 << 40;
+            assert!(table.compressed_block(&block).unwrap_err().to_string().contains("beyond the end of the file"));
+        }
+
+        // corrupt the first block
+        let offset = {
+            let table = open_table(&filename).unwrap();
+            table.infotable()[0].blocks[0].offset
+        };
+        let mut file = OpenOptions::new().write(true).open(&filename).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0xff, 0xff, 0xff]).unwrap();
+        let validation = validate_table(&filename);
+        assert!(!validation.is_valid());
+        assert!(validation.errors[0].starts_with("sequence 'chr1' block 0"));
+    }
+}
@@ -0,0 +1,90 @@
+//!
+//!	Merge sequence tables built with the same parameters, e.g. for host and spike-in genomes.
+//!
+use std::collections::HashSet;
+use std::fs::File;
+use super::{SeqTable, SeqTableWriter};
+
+/// Write the sequences of all `inputs`, in order, to `outfile`
+///
+/// The compressed sequence blocks are copied as is and the genome k-mer counts are summed. Inputs must
+/// have identical parameters, soft-mask modes and block lengths, and distinct sequence names.
+pub fn merge_seqtables(inputs: &Vec<String>, outfile: &str) -> Result<(), String> {
+    let mut tables = Vec::new();
+    for filename in inputs {
+        let file = try!(File::open(filename).map_err(|err| format!("Failed to open sequence table file '{}': {}", filename, err)));
+        let table = try!(SeqTable::open(file).map_err(|err| format!("Failed to read sequence table file '{}': {}", filename, err)));
+        tables.push(table);
+    }
+    if tables.is_empty() {
+        return Err("No sequence tables to merge.".to_string());
+    }
+
+    // check compatibility
+    let mut names = HashSet::new();
+    for (filename, table) in inputs.iter().zip(&tables) {
+        if table.params != tables[0].params {
+            return Err(format!("Sequence table '{}' parameters differ from those of '{}'.", filename, inputs[0]));
+        }
+        if table.softmask != tables[0].softmask {
+            return Err(format!("Sequence table '{}' soft-mask mode differs from that of '{}'.", filename, inputs[0]));
+        }
+        if table.block_length() != tables[0].block_length() {
+            return Err(format!("Sequence table '{}' block length differs from that of '{}'.", filename, inputs[0]));
+        }
+        for info in table.infotable() {
+            if !names.insert(info.name.clone()) {
+                return Err(format!("Duplicate sequence name '{}' in '{}'.", info.name, filename));
+            }
+        }
+    }
+
+    let params = tables[0].params.clone();
+    let block_length = tables[0].block_length();
+    let f_out = try!(File::create(outfile).map_err(|err| format!("Failed to create '{}': {}", outfile, err)));
+    let mut output = try!(SeqTableWriter::new(f_out, &params, tables[0].softmask, block_length).map_err(|err| err.to_string()));
+
+    for (filename, table) in inputs.iter().zip(tables.iter_mut()) {
+        let write_error = |err: ::std::io::Error| format!("Failed to copy sequences of '{}': {}", filename, err);
+        let counts = try!(table.counts().map_err(&write_error));
+        output.add_counts(&counts);
+
+        let infotable = table.infotable().clone();
+        let max_block_size = table.max_block_size();
+        for info in &infotable {
+            try!(output.append_compressed_sequence(info, max_block_size, |block| table.compressed_block(block)).map_err(&write_error));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use seqtable::SeqTableParams;
+    use seqtable::tests::{write_table, read_table};
+    use tempdir::TempDir;
+
+    #[test]
+    fn merged_sequences_and_counts() {
+        let dir = TempDir::new("seqoutbias_merge").unwrap();
+        let path = |name: &str| dir.path().join(format!("{}.tbl", name)).to_str().unwrap().to_string();
+        let (host, spike, merged, dup) = (path("host"), path("spike"), path("merged"), path("dup"));
+        let params = SeqTableParams::new(1, 0, 0, 1, &None, false);
+        write_table(&host, &params, vec![("chr1", vec![(1, 2), (3, 4), (0, 1)]), ("chr2", vec![(2, 2)])]);
+        write_table(&spike, &params, vec![("spike1", vec![(4, 4), (1, 0), (2, 3), (3, 0), (1, 1)])]);
+
+        merge_seqtables(&vec![host.clone(), spike.clone()], &merged).unwrap();
+        let (sequences, counts) = read_table(&merged);
+        let names: Vec<&str> = sequences.iter().map(|&(ref name, _)| name.as_str()).collect();
+        assert_eq!(vec!["chr1", "chr2", "spike1"], names);
+        assert_eq!(vec![(1, 2), (3, 4), (0, 1)], sequences[0].1);
+        assert_eq!(vec![(4, 4), (1, 0), (2, 3), (3, 0), (1, 1)], sequences[2].1);
+        assert_eq!(vec![(1, 2), (3, 2), (2, 2), (2, 1), (1, 2)], counts);
+
+        // duplicate names and different parameters are rejected
+        assert!(merge_seqtables(&vec![host.clone(), host.clone()], &dup).is_err());
+        write_table(&dup, &SeqTableParams::new(2, 0, 0, 1, &None, false), vec![("chr3", vec![(1, 1)])]);
+        assert!(merge_seqtables(&vec![host.clone(), dup.clone()], &merged).is_err());
+    }
+}
@@ -9,16 +9,166 @@ use std::io::BufRead;
 use std::fs::File;
 use std::cmp;
 
-const TBL_VERSION : u8 = 5u8;
+const TBL_VERSION : u8 = 6u8;
+/// Previous file version, still read: masks stored as flags, no soft-mask mode and dense counts
+const TBL_VERSION_V5 : u8 = 5u8;
 
-#[derive(Clone, Debug)]
+/// Largest k-mer index size, in bits, of the 64-bit table indexes
+pub const MAX_INDEX_BITS: u32 = 62;
+
+/// Two-letter base class of an IUPAC-degenerate mask position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BaseClass {
+  /// purine (R = A/G) or pyrimidine (Y = C/T)
+  PurinePyrimidine,
+  /// strong (S = C/G) or weak (W = A/T)
+  StrongWeak,
+  /// keto (K = G/T) or amino (M = A/C)
+  KetoAmino,
+}
+
+impl BaseClass {
+  /// Binary symbol (0 or 1) of a base (0 = A, 1 = C, 2 = G, 3 = T)
+  pub fn symbol(&self, base: u8) -> u8 {
+    match *self {
+      BaseClass::PurinePyrimidine => if base == 0 || base == 2 { 0 } else { 1 },
+      BaseClass::StrongWeak => if base == 1 || base == 2 { 0 } else { 1 },
+      BaseClass::KetoAmino => if base == 2 || base == 3 { 0 } else { 1 },
+    }
+  }
+
+  /// IUPAC letters of symbols 0 and 1
+  pub fn letters(&self) -> [char; 2] {
+    match *self {
+      BaseClass::PurinePyrimidine => ['R', 'Y'],
+      BaseClass::StrongWeak => ['S', 'W'],
+      BaseClass::KetoAmino => ['K', 'M'],
+    }
+  }
+}
+
+/// k-mer mask position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaskPos {
+  /// ignored position (X)
+  Excluded,
+  /// base included in the k-mer index (N)
+  Base,
+  /// only the base class is included in the k-mer index (R/Y, S/W or K/M)
+  Class(BaseClass),
+}
+
+impl MaskPos {
+  pub fn from_char(c: char) -> Option<MaskPos> {
+    match c.to_ascii_uppercase() {
+      'X' => Some(MaskPos::Excluded),
+      'N' => Some(MaskPos::Base),
+      'R' | 'Y' => Some(MaskPos::Class(BaseClass::PurinePyrimidine)),
+      'S' | 'W' => Some(MaskPos::Class(BaseClass::StrongWeak)),
+      'K' | 'M' => Some(MaskPos::Class(BaseClass::KetoAmino)),
+      _ => None,
+    }
+  }
+
+  /// Mask character, class positions use the letter of symbol 0
+  pub fn to_char(&self) -> char {
+    match *self {
+      MaskPos::Excluded => 'X',
+      MaskPos::Base => 'N',
+      MaskPos::Class(class) => class.letters()[0],
+    }
+  }
+
+  /// Number of symbols contributed to the k-mer index
+  pub fn radix(&self) -> u32 {
+    match *self {
+      MaskPos::Excluded => 1,
+      MaskPos::Base => 4,
+      MaskPos::Class(_) => 2,
+    }
+  }
+
+  /// Code stored in the sequence table file
+  pub fn code(&self) -> u8 {
+    match *self {
+      MaskPos::Excluded => 0,
+      MaskPos::Base => 1,
+      MaskPos::Class(BaseClass::PurinePyrimidine) => 2,
+      MaskPos::Class(BaseClass::StrongWeak) => 3,
+      MaskPos::Class(BaseClass::KetoAmino) => 4,
+    }
+  }
+
+  pub fn from_code(code: u8) -> Option<MaskPos> {
+    match code {
+      0 => Some(MaskPos::Excluded),
+      1 => Some(MaskPos::Base),
+      2 => Some(MaskPos::Class(BaseClass::PurinePyrimidine)),
+      3 => Some(MaskPos::Class(BaseClass::StrongWeak)),
+      4 => Some(MaskPos::Class(BaseClass::KetoAmino)),
+      _ => None,
+    }
+  }
+}
+
+/// Handling of lowercase (soft-masked, e.g. by RepeatMasker) bases
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SoftMask {
+  /// lowercase bases are used like uppercase bases
+  Ignore,
+  /// lowercase bases are treated as N, k-mers including them are unmappable
+  Unmappable,
+  /// only cut-sites at lowercase bases are unmappable
+  CutSite,
+}
+
+impl SoftMask {
+  /// Code stored in the sequence table file
+  pub fn code(&self) -> u8 {
+    match *self {
+      SoftMask::Ignore => 0,
+      SoftMask::Unmappable => 1,
+      SoftMask::CutSite => 2,
+    }
+  }
+
+  /// Name of the mode, as in the command line options
+  pub fn name(&self) -> &'static str {
+    match *self {
+      SoftMask::Ignore => "ignore",
+      SoftMask::Unmappable => "unmappable",
+      SoftMask::CutSite => "cut-sites",
+    }
+  }
+
+  pub fn from_code(code: u8) -> Option<SoftMask> {
+    match code {
+      0 => Some(SoftMask::Ignore),
+      1 => Some(SoftMask::Unmappable),
+      2 => Some(SoftMask::CutSite),
+      _ => None,
+    }
+  }
+}
+
+fn is_cut_marker(c: char) -> bool {
+  c == 'C' || c == 'c'
+}
+
+/// Number of k-mer bases before the cut `marker` in `mask`
+fn cut_position(mask: &str, marker: char) -> Option<u8> {
+  mask.chars().position(|c| c == marker).map(|idx| mask.chars().take(idx).filter(|&c| !is_cut_marker(c)).count() as u8)
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct SeqTableParams {
 	pub kmer_length: u8,
 	pub plus_offset: u8,
 	pub minus_offset: u8,
 	pub read_length: u16,
   // masked n-mers
-  pub mask: Option<Vec<bool>>,
+  pub mask: Option<Vec<MaskPos>>,
+  // number of non-excluded mask positions
   pub unmasked_count: u8,
   // strand_specific enzymes - mask is flipped on minus strand
   pub strand_specific: bool,
@@ -28,28 +178,36 @@ impl SeqTableParams {
   pub fn new(mut kmer_length: u8, mut plus_offset: u8, mut minus_offset: u8, read_length: u16, mask: &Option<String>, strand_specific: bool) -> Self {
     match *mask {
       Some(ref maskstr) => {
-        let uc_mask = maskstr.to_uppercase();
-        if let Some(idx) = uc_mask.find('C') {
-          kmer_length = uc_mask.len() as u8 - 1;
-          plus_offset = idx as u8;
-          if strand_specific {
-            minus_offset = idx as u8;
-          } else {
-            minus_offset = kmer_length - (idx as u8);
-          }
-        } else {
-          kmer_length = uc_mask.len() as u8;
+        let bases: Vec<char> = maskstr.chars().filter(|&c| !is_cut_marker(c)).collect();
+        kmer_length = bases.len() as u8;
+
+        // a lowercase 'c' marks a separate minus strand cut-site
+        match (cut_position(maskstr, 'C'), cut_position(maskstr, 'c')) {
+          (Some(plus), Some(minus)) => {
+            plus_offset = plus;
+            minus_offset = kmer_length - minus;
+          },
+          (Some(idx), None) | (None, Some(idx)) => {
+            plus_offset = idx;
+            if strand_specific {
+              minus_offset = idx;
+            } else {
+              minus_offset = kmer_length - idx;
+            }
+          },
+          (None, None) => {},
         }
 
-        let bmask: Vec<bool> = uc_mask.chars().filter(|c| *c != 'C').map(|c| c == 'N').collect();
-        let bcount = bmask.iter().fold(0u8, |acc, &x| if x { acc + 1 } else { acc });
+        let bmask: Vec<MaskPos> = bases.iter().map(|&c| MaskPos::from_char(c).unwrap_or(MaskPos::Excluded)).collect();
+        let bcount = bmask.iter().filter(|&&pos| pos != MaskPos::Excluded).count() as u8;
+        let plain = bmask.iter().all(|&pos| pos == MaskPos::Base);
 
         SeqTableParams {
           kmer_length: kmer_length,
           plus_offset: plus_offset,
           minus_offset: minus_offset,
           read_length: read_length,
-          mask: if bcount < bmask.len() as u8 { Some(bmask) } else { None },
+          mask: if plain { None } else { Some(bmask) },
           unmasked_count:  bcount,
           strand_specific: strand_specific,
         }
@@ -85,30 +243,64 @@ impl SeqTableParams {
     table.params().clone()
   }
 
-  pub fn nmer_count(&self) -> u32 {
-    4u32.pow(self.unmasked_count as u32) + 1
+  /// Whether sequence blocks store 64-bit k-mer indexes, 32-bit pairs are used while all indexes fit
+  pub fn wide_indexes(&self) -> bool {
+    self.nmer_count() > u32::max_value() as u64
+  }
+
+  pub fn nmer_count(&self) -> u64 {
+    match self.mask {
+      Some(ref mask) => mask.iter().fold(1u64, |acc, pos| acc * pos.radix() as u64) + 1,
+      None => 4u64.pow(self.unmasked_count as u32) + 1,
+    }
+  }
+
+  /// Whether the mask reduces some positions to a base class
+  pub fn has_class_positions(&self) -> bool {
+    match self.mask {
+      Some(ref mask) => mask.iter().any(|pos| match *pos { MaskPos::Class(_) => true, _ => false }),
+      None => false,
+    }
+  }
+
+  /// Mask as a string (without the cut-site), None if all positions are included
+  pub fn mask_string(&self) -> Option<String> {
+    self.mask.as_ref().map(|mask| mask.iter().map(|pos| pos.to_char()).collect())
+  }
+
+  /// Whether the mask has separate plus (C) and minus (c) strand cut-sites
+  pub fn has_staggered_cut(mask: &str) -> bool {
+    cut_position(mask, 'C').is_some() && cut_position(mask, 'c').is_some()
   }
 
   pub fn validate_mask(mask: &str) -> Result<(), String> {
-    let mut c_count = 0;
+    let mut plus_count = 0;
+    let mut minus_count = 0;
     let mut n_count = 0;
+    let mut index_bits = 0;
     for c in mask.chars() {
-        if c == 'C' || c == 'c' {
-            c_count += 1;
+        if c == 'C' {
+            plus_count += 1;
+        } else if c == 'c' {
+            minus_count += 1;
         } else if c == 'x' || c == 'X' {
             // ok, nothing to do
-        } else if c == 'n' || c == 'N' {
+        } else if let Some(pos) = MaskPos::from_char(c) {
             n_count += 1;
+            index_bits += if pos == MaskPos::Base { 2 } else { 1 };
         } else {
             return Err(format!("Invalid kmer-mask, unknown character: {}", c));
         }
     }
 
     if n_count == 0 {
-      return Err("Invalid kmer-mask, must have at least one unmasked (N) position.".to_string());
+      return Err("Invalid kmer-mask, must have at least one unmasked (N, R/Y, S/W or K/M) position.".to_string());
     }
-    if c_count > 1 {
-      return Err("Invalid kmer-mask, can only have one cut position (C).".to_string());
+    if plus_count > 1 || minus_count > 1 {
+      return Err("Invalid kmer-mask, can only have one plus strand (C) and one minus strand (c) cut position.".to_string());
+    }
+    if index_bits > MAX_INDEX_BITS {
+      return Err(format!("Invalid kmer-mask, k-mer index needs more than {} bits (at most {} N positions).", MAX_INDEX_BITS, MAX_INDEX_BITS / 2));
     }
 
     return Ok(());
@@ -116,12 +308,17 @@ impl SeqTableParams {
 }
 
 pub trait SeqStore {
-    fn write(&mut self, plus: u32, minus: u32);
+    fn write(&mut self, plus: u64, minus: u64);
 }
 
 mod write;
 mod read;
 mod dump;
+mod nmers;
+mod codec;
+mod merge;
+mod subset;
+mod info;
 
 // re-exports
 pub use self::write::SeqTableWriter;
@@ -130,6 +327,11 @@ pub use self::read::SeqTable;
 pub use self::read::SequenceInfo;
 pub use self::dump::dump_seqtable;
 pub use self::dump::dump_seqtable_range;
+pub use self::nmers::{NmerCount, NmerCounts};
+pub use self::codec::KmerCodec;
+pub use self::merge::merge_seqtables;
+pub use self::subset::subset_seqtable;
+pub use self::info::{TableInfo, SequenceSummary, Validation, table_info, validate_table};
 
 /// This buffer is used to translate between coordinate systems
 /// Maps the n-mer table index values from the FASTA scan coordinates
@@ -138,8 +340,8 @@ pub struct SeqBuffer<'a, S: SeqStore, R: 'a + BufRead> {
     store: S,
     position: u32,
     written: u32, // TODO: consider storing this on SeqStore, i.e., add a method fn count(&self) -> u32
-    plus_values: VecDeque<u32>,
-    minus_values: VecDeque<u32>,
+    plus_values: VecDeque<u64>,
+    minus_values: VecDeque<u64>,
     plus_skip: u16,
     minus_skip: u16,
     common_skip: u16,
@@ -189,7 +391,7 @@ impl<'a, S: SeqStore, R: BufRead> SeqBuffer<'a, S, R> {
     }
     
     // Write values into underlying SeqStore, masking unmappable positions
-    fn write(&mut self, plus_value: u32, minus_value: u32) {
+    fn write(&mut self, plus_value: u64, minus_value: u64) {
       let UnMapPosition{ plus: unmap_plus, minus: unmap_minus } = self.unmap.is_unmappable(self.written);
             
       let idx_plus = if unmap_plus { 0 } else { plus_value };
@@ -200,7 +402,7 @@ impl<'a, S: SeqStore, R: BufRead> SeqBuffer<'a, S, R> {
     }
     
     /// Push a new n-mer table index into buffer
-    pub fn push(&mut self, table_index_plus: u32, table_index_minus: u32) {
+    pub fn push(&mut self, table_index_plus: u64, table_index_minus: u64) {
       if self.common_skip > 0 {
         self.common_skip -= 1;
         self.plus_skip -= 1;
@@ -254,3 +456,87 @@ impl<'a, S: SeqStore, R: BufRead> Drop for SeqBuffer<'a, S, R> {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    // table with one sequence per (name, values) pair, using 2 value blocks
+    pub fn write_table(filename: &str, params: &SeqTableParams, sequences: Vec<(&str, Vec<(u64, u64)>)>) {
+        let f_out = File::create(filename).unwrap();
+        let mut output = SeqTableWriter::new(f_out, params, SoftMask::Ignore, 2).unwrap();
+        for (name, values) in sequences {
+            let mut seqwrt = output.create_sequence(name.to_string());
+            for (plus, minus) in values {
+                seqwrt.write(plus, minus);
+            }
+        }
+    }
+
+    pub fn read_table(filename: &str) -> (Vec<(String, Vec<(u64, u64)>)>, Vec<(u64, u64)>) {
+        let mut table = SeqTable::open(File::open(filename).unwrap()).unwrap();
+        let mut sequences = Vec::new();
+        for (idx, info) in table.sequences().iter().enumerate() {
+            let mut reader = table.get_sequence_by_idx(idx).unwrap();
+            sequences.push((info.name.clone(), (0..info.length).map(|pos| reader.get(pos).unwrap()).collect()));
+        }
+        let counts = table.counts().unwrap().iter().map(|(_, c)| (c.0, c.1)).collect();
+        (sequences, counts)
+    }
+
+    #[test]
+    fn single_cut_site_offsets() {
+        let params = SeqTableParams::new(0, 0, 0, 36, &Some("NNCNNXN".to_string()), false);
+        assert_eq!((6, 2, 4), (params.kmer_length, params.plus_offset, params.minus_offset));
+        assert_eq!(Some("NNNNXN".to_string()), params.mask_string());
+
+        let params = SeqTableParams::new(0, 0, 0, 36, &Some("NNcNNXN".to_string()), true);
+        assert_eq!((6, 2, 2), (params.kmer_length, params.plus_offset, params.minus_offset));
+    }
+
+    #[test]
+    fn staggered_cut_site_offsets() {
+        let params = SeqTableParams::new(0, 0, 0, 36, &Some("NNCNNNNcNN".to_string()), false);
+        assert_eq!((8, 2, 2), (params.kmer_length, params.plus_offset, params.minus_offset));
+        // minus cut-site before the plus cut-site
+        let params = SeqTableParams::new(0, 0, 0, 36, &Some("NcNNCNNN".to_string()), false);
+        assert_eq!((6, 3, 5), (params.kmer_length, params.plus_offset, params.minus_offset));
+
+        assert!(SeqTableParams::has_staggered_cut("NNCNNNNcNN"));
+        assert!(!SeqTableParams::has_staggered_cut("NNCNN"));
+        assert!(SeqTableParams::validate_mask("NNCNNNNcNN").is_ok());
+        assert!(SeqTableParams::validate_mask("NNCNNCNN").is_err());
+        assert!(SeqTableParams::validate_mask("NNcNNcNN").is_err());
+    }
+
+    #[test]
+    fn reads_version_5_tables() {
+        // ref2.fa test genome, read size 5 and k-mer mask NXCNN
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/resources/v5/ref2_5.4.2.2.tbl");
+        let (sequences, counts) = read_table(filename);
+        let table = SeqTable::open(File::open(filename).unwrap()).unwrap();
+        assert_eq!(SeqTableParams::new(0, 0, 0, 5, &Some("NXCNN".to_string()), false), table.params);
+        assert_eq!(SoftMask::Ignore, table.softmask);
+
+        assert_eq!(vec![("1".to_string(), 21)], sequences.iter().map(|&(ref name, ref values)| (name.clone(), values.len())).collect::<Vec<_>>());
+        assert_eq!(vec![(0, 15), (0, 26), (5, 0), (2, 0)], sequences[0].1[0..4].to_vec());
+        assert_eq!(65, counts.len());
+        assert_eq!((1, 1), counts[3]);
+    }
+
+    #[test]
+    fn equivalent_tables_share_the_mask() {
+        let dir = TempDir::new("seqoutbias_equivalent").unwrap();
+        let filename = dir.path().join("mask.tbl").to_str().unwrap().to_string();
+        let params = SeqTableParams::new(0, 0, 0, 36, &Some("NNCNN".to_string()), false);
+        write_table(&filename, &params, vec![("chr1", vec![(1, 2)])]);
+        let table = SeqTable::open(File::open(&filename).unwrap()).unwrap();
+
+        assert!(table.equivalent(&filename, &SeqTableParams::new(4, 2, 2, 36, &None, false)));
+        // same k-mer length and offsets
+        assert!(!table.equivalent(&filename, &SeqTableParams::new(0, 0, 0, 36, &Some("NRCRN".to_string()), false)));
+        assert!(!table.equivalent(&filename, &SeqTableParams::new(4, 2, 2, 36, &None, true)));
+    }
+}
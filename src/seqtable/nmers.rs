@@ -0,0 +1,142 @@
+//!
+//!	Per k-mer count tables, dense for small k-mer index ranges and hash based for large ones.
+//!
+extern crate bincode;
+
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::ops::{Index, IndexMut};
+use bincode::rustc_serialize::{encode_into, decode_from, EncodingResult, DecodingResult};
+
+/// Counts of one k-mer: (plus genome, minus genome, plus reads, minus reads)
+pub type NmerCount = (u64, u64, u64, u64);
+
+/// Largest k-mer index range (4^10 + 1) stored as a dense vector
+const MAX_DENSE_LENGTH: u64 = 1048577;
+
+static ZERO_COUNT: NmerCount = (0, 0, 0, 0);
+
+/// Counts for k-mer indexes `0..len()`, index 0 holds the unmappable positions
+#[derive(Clone, Debug, PartialEq)]
+pub enum NmerCounts {
+    Dense(Vec<NmerCount>),
+    Sparse(u64, HashMap<u64, NmerCount>),
+}
+
+// on disk representation, sparse counts are sorted to keep files reproducible
+#[derive(RustcEncodable, RustcDecodable)]
+enum StoredCounts {
+    Dense(Vec<NmerCount>),
+    Sparse(u64, Vec<(u64, NmerCount)>),
+}
+
+impl NmerCounts {
+    /// Zero counts for `nmer_count` k-mer indexes
+    pub fn new(nmer_count: u64) -> NmerCounts {
+        if nmer_count <= MAX_DENSE_LENGTH {
+            NmerCounts::Dense(vec![(0, 0, 0, 0); nmer_count as usize])
+        } else {
+            NmerCounts::Sparse(nmer_count, HashMap::new())
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        match *self {
+            NmerCounts::Dense(ref counts) => counts.len() as u64,
+            NmerCounts::Sparse(len, _) => len,
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        match *self {
+            NmerCounts::Dense(_) => false,
+            NmerCounts::Sparse(_, _) => true,
+        }
+    }
+
+    /// Stored (index, counts) pairs in index order, all indexes for dense tables
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(u64, NmerCount)> + 'a> {
+        match *self {
+            NmerCounts::Dense(ref counts) => Box::new(counts.iter().enumerate().map(|(idx, &count)| (idx as u64, count))),
+            NmerCounts::Sparse(_, ref counts) => {
+                let mut entries: Vec<(u64, NmerCount)> = counts.iter().map(|(&idx, &count)| (idx, count)).collect();
+                entries.sort_by_key(|&(idx, _)| idx);
+                Box::new(entries.into_iter())
+            },
+        }
+    }
+
+    /// Sum of the counts of all mappable k-mers (index 0 excluded)
+    pub fn totals(&self) -> NmerCount {
+        self.iter().filter(|&(idx, _)| idx > 0).fold((0, 0, 0, 0),
+            |acc, (_, (sp, sm, bp, bm))| (acc.0 + sp, acc.1 + sm, acc.2 + bp, acc.3 + bm))
+    }
+
+    pub fn encode_into<W: Write>(&self, writer: &mut W) -> EncodingResult<()> {
+        let stored = match *self {
+            NmerCounts::Dense(ref counts) => StoredCounts::Dense(counts.clone()),
+            NmerCounts::Sparse(len, _) => StoredCounts::Sparse(len, self.iter().collect()),
+        };
+        encode_into(&stored, writer, bincode::SizeLimit::Infinite)
+    }
+
+    pub fn decode_from<R: Read>(reader: &mut R) -> DecodingResult<NmerCounts> {
+        let stored: StoredCounts = try!(decode_from(reader, bincode::SizeLimit::Infinite));
+        Ok(match stored {
+            StoredCounts::Dense(counts) => NmerCounts::Dense(counts),
+            StoredCounts::Sparse(len, entries) => NmerCounts::Sparse(len, entries.into_iter().collect()),
+        })
+    }
+}
+
+impl Index<u64> for NmerCounts {
+    type Output = NmerCount;
+
+    fn index(&self, idx: u64) -> &NmerCount {
+        match *self {
+            NmerCounts::Dense(ref counts) => &counts[idx as usize],
+            NmerCounts::Sparse(_, ref counts) => counts.get(&idx).unwrap_or(&ZERO_COUNT),
+        }
+    }
+}
+
+impl IndexMut<u64> for NmerCounts {
+    fn index_mut(&mut self, idx: u64) -> &mut NmerCount {
+        match *self {
+            NmerCounts::Dense(ref mut counts) => &mut counts[idx as usize],
+            NmerCounts::Sparse(_, ref mut counts) => counts.entry(idx).or_insert((0, 0, 0, 0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn sparse_counts_for_large_index_ranges() {
+        let mut counts = NmerCounts::new(4u64.pow(20) + 1);
+        assert!(counts.is_sparse());
+        counts[4u64.pow(19)].0 += 2;
+        counts[3].2 += 1;
+        counts[0].0 += 5;
+
+        assert_eq!((0, 0, 0, 0), counts[7]);
+        assert_eq!(vec![0, 3, 4u64.pow(19)], counts.iter().map(|(idx, _)| idx).collect::<Vec<u64>>());
+        assert_eq!((2, 0, 1, 0), counts.totals());
+
+        let mut buf = Vec::new();
+        counts.encode_into(&mut buf).unwrap();
+        assert_eq!(counts, NmerCounts::decode_from(&mut Cursor::new(buf)).unwrap());
+    }
+
+    #[test]
+    fn dense_counts_for_small_index_ranges() {
+        let mut counts = NmerCounts::new(17);
+        assert!(!counts.is_sparse());
+        counts[16].3 += 1;
+        assert_eq!(17, counts.iter().count());
+        assert_eq!((0, 0, 0, 1), counts.totals());
+    }
+}
@@ -8,25 +8,53 @@ use std::io::SeekFrom;
 use std::io::Result;
 use std::io::Error;
 use std::io::ErrorKind;
+use std::mem::size_of;
 use byteorder::{LittleEndian, ReadBytesExt};
-use bincode::rustc_serialize::{decode_from, decode};
-use flate2::{Decompress,Flush};
+use bincode::rustc_serialize::{decode_from, decode, DecodingResult};
+use flate2::{Decompress,Flush,Status};
 
 use super::SeqTableParams;
+use super::MaskPos;
+use super::SoftMask;
+use super::{NmerCount, NmerCounts};
 use super::write::SeqInfo;
 use super::write::SeqBlock;
 
+// block values, stored as 32-bit pairs unless the table needs wide k-mer indexes
+fn decode_values(data: &[u8], wide_indexes: bool) -> DecodingResult<Vec<(u64, u64)>> {
+    if wide_indexes {
+        decode(data)
+    } else {
+        let values: Vec<(u32, u32)> = try!(decode(data));
+        Ok(values.into_iter().map(|(plus, minus)| (plus as u64, minus as u64)).collect())
+    }
+}
+
+/// Largest encoded block size: `block_length` values plus the bincode length prefix
+fn max_encoded_size(block_length: u32, wide_indexes: bool) -> u64 {
+    let value_size = if wide_indexes { size_of::<(u64, u64)>() } else { size_of::<(u32, u32)>() };
+    size_of::<u64>() as u64 + block_length as u64 * value_size as u64
+}
+
 #[derive(Debug)]
 pub struct SeqTable<R: Read + Seek> {
+    version: u8,
     pub params: SeqTableParams,
+    /// Whether the blocks hold 64-bit k-mer indexes, version 5 tables only hold 32-bit indexes
+    wide_indexes: bool,
+    /// Handling of lowercase FASTA bases when the table was generated
+    pub softmask: SoftMask,
     block_length: u32,
     infotable: Vec<SeqInfo>,
     reader: R,
     dec_buffer: Vec<u8>,
     read_buffer: Vec<u8>,
     counts_offset: u64,
+    /// File size, bounding the offsets and sizes read from the file
+    file_length: u64,
 }
 
+#[derive(Clone, Debug)]
 pub struct SequenceInfo {
     pub name: String,
     pub length: u32,
@@ -37,8 +65,8 @@ impl<R: Read + Seek> SeqTable<R> {
         try!(reader.seek(SeekFrom::Start(0)));
         // load version
         let version = try!(reader.read_u8());
-        if version != super::TBL_VERSION {
-            return Err(Error::new(ErrorKind::InvalidData, format!("Incompatible file version {}, expected {}.", version, super::TBL_VERSION)));
+        if version != super::TBL_VERSION && version != super::TBL_VERSION_V5 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Incompatible file version {}, expected {}, regenerate the table with this version of seqOutBias.", version, super::TBL_VERSION)));
         }
         // load parameters
         let cl = try!(reader.read_u8());
@@ -47,12 +75,33 @@ impl<R: Read + Seek> SeqTable<R> {
         let mo = try!(reader.read_u8());
         let rl = try!(reader.read_u16::<LittleEndian>());
         let ss = try!(reader.read_u8()) == 1;
+        let softmask = if version == super::TBL_VERSION_V5 {
+            SoftMask::Ignore
+        } else {
+            match SoftMask::from_code(try!(reader.read_u8())) {
+                Some(value) => value,
+                None => return Err(Error::new(ErrorKind::InvalidData, "Invalid soft-mask mode.")),
+            }
+        };
         // load mask if needed
-        let mask = if uc < cl {
+        let mask = if version == super::TBL_VERSION_V5 {
+            // one included/excluded flag per position, present when some position is excluded
+            if uc < cl {
+                let mut res = Vec::new();
+                for _ in 0..cl {
+                    res.push(if try!(reader.read_u8()) == 1 { MaskPos::Base } else { MaskPos::Excluded });
+                }
+                Some(res)
+            } else {
+                None
+            }
+        } else if try!(reader.read_u8()) == 1 {
             let mut res = Vec::new();
             for _ in 0..cl {
-                let flag = try!(reader.read_u8());
-                res.push(flag == 1);
+                match MaskPos::from_code(try!(reader.read_u8())) {
+                    Some(pos) => res.push(pos),
+                    None => return Err(Error::new(ErrorKind::InvalidData, "Invalid k-mer mask code.")),
+                }
             }
             Some(res)
         } else {
@@ -80,12 +129,27 @@ impl<R: Read + Seek> SeqTable<R> {
         
         //println!("blen: {}, offset: {}, bufsize: {}", blen, offset, bufsize);
         
+        // the sizes and offsets are checked before allocating, in case the header is corrupt
+        let wide_indexes = version != super::TBL_VERSION_V5 && params.wide_indexes();
+        if bufsize as u64 > max_encoded_size(blen, wide_indexes) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid buffer size {}, a full block has {} bytes.", bufsize, max_encoded_size(blen, wide_indexes))));
+        }
+        let file_length = try!(reader.seek(SeekFrom::End(0)));
+        if offset > file_length || counts_offset > file_length {
+            return Err(Error::new(ErrorKind::InvalidData, "Table offsets beyond the end of the file."));
+        }
+
         // load info table
         try!(reader.seek(SeekFrom::Start(offset)));
-        let infotable = (decode_from(&mut reader, bincode::SizeLimit::Infinite)).unwrap(); // TODO: fix
+        let infotable = try!(decode_from(&mut reader, bincode::SizeLimit::Bounded(file_length - offset))
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Invalid sequence info table: {}", err))));
         
         Ok(SeqTable {
+            version: version,
+            wide_indexes: wide_indexes,
+            file_length: file_length,
             params: params,
+            softmask: softmask,
             block_length: blen,
             infotable: infotable,
             reader: reader,
@@ -106,6 +170,7 @@ impl<R: Read + Seek> SeqTable<R> {
                             reader: &mut self.reader,
                             block_length: self.block_length,
                             info: &iref,
+                            wide_indexes: self.wide_indexes,
                             block: None,
                             block_idx: 0,
                             dec_buffer: &mut self.dec_buffer,
@@ -131,6 +196,7 @@ impl<R: Read + Seek> SeqTable<R> {
                         reader: &mut self.reader,
                         block_length: self.block_length,
                         info: &self.infotable[idx],
+                        wide_indexes: self.wide_indexes,
                         block: None,
                         block_idx: 0,
                         dec_buffer: &mut self.dec_buffer,
@@ -142,15 +208,60 @@ impl<R: Read + Seek> SeqTable<R> {
         }
     }
     
+    /// Number of table values per compressed block
+    pub fn block_length(&self) -> u32 {
+        self.block_length
+    }
+
+    /// Largest decompressed block size, in bytes
+    pub fn max_block_size(&self) -> u64 {
+        self.dec_buffer.len() as u64
+    }
+
+    /// Sequence information, including the location of the compressed blocks
+    pub fn infotable(&self) -> &Vec<SeqInfo> {
+        &self.infotable
+    }
+
+    /// Compressed content of `block`, as stored in the file
+    pub fn compressed_block(&mut self, block: &SeqBlock) -> Result<Vec<u8>> {
+        // a corrupt info table could otherwise request a huge allocation
+        if block.offset > self.file_length || block.comp_size > self.file_length - block.offset {
+            return Err(Error::new(ErrorKind::InvalidData, format!("compressed block of {} bytes at offset {} extends beyond the end of the file", block.comp_size, block.offset)));
+        }
+        let mut data = vec![0u8; block.comp_size as usize];
+        try!(self.reader.seek(SeekFrom::Start(block.offset)));
+        try!(self.reader.read_exact(&mut data));
+        Ok(data)
+    }
+
+    fn max_encoded_size(&self) -> u64 {
+        max_encoded_size(self.block_length, self.wide_indexes)
+    }
+
+    /// Values of `block`, with errors instead of panics for blocks that fail to decompress or decode
+    pub fn decode_block(&mut self, block: &SeqBlock) -> Result<Vec<(u64, u64)>> {
+        // the sizes come from the info table, which may be corrupt
+        if block.enc_size > self.max_encoded_size() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("recorded block size {} exceeds the {} bytes of a full block", block.enc_size, self.max_encoded_size())));
+        }
+        let data = try!(self.compressed_block(block));
+        let mut decoded = vec![0u8; block.enc_size as usize];
+        let mut decompressor = Decompress::new(false);
+        match decompressor.decompress(&data, &mut decoded, Flush::Finish) {
+            Ok(Status::StreamEnd) if decompressor.total_out() == block.enc_size => {},
+            _ => return Err(Error::new(ErrorKind::InvalidData, "block failed to decompress to its recorded size")),
+        }
+        decode_values(&decoded, self.wide_indexes).map_err(|err| Error::new(ErrorKind::InvalidData, format!("block failed to decode: {}", err)))
+    }
+
     pub fn params(&self) -> &SeqTableParams {
         &self.params
     }
     
+    /// Whether the table was generated with `params`, including the mask and strand specificity
     pub fn equivalent(&self, _: &str, params: &SeqTableParams) -> bool {
-        self.params.read_length == params.read_length &&
-        self.params.kmer_length == params.kmer_length &&
-        self.params.plus_offset == params.plus_offset &&
-        self.params.minus_offset == params.minus_offset
+        self.params == *params
     }
     
     pub fn sequences(&self) -> Vec<SequenceInfo> {
@@ -159,10 +270,15 @@ impl<R: Read + Seek> SeqTable<R> {
         ).collect()
     }
     
-    pub fn counts(&mut self) -> Result<Vec<(u64, u64, u64, u64)>> {
+    pub fn counts(&mut self) -> Result<NmerCounts> {
         try!(self.reader.seek(SeekFrom::Start(self.counts_offset)));
-        let counts: Vec<(u64, u64, u64, u64)> = (decode_from(&mut self.reader, bincode::SizeLimit::Infinite)).unwrap();
-        Ok(counts)
+        let counts = if self.version == super::TBL_VERSION_V5 {
+            let limit = size_of::<u64>() as u64 + self.params.nmer_count() * size_of::<NmerCount>() as u64;
+            decode_from(&mut self.reader, bincode::SizeLimit::Bounded(limit)).map(NmerCounts::Dense)
+        } else {
+            NmerCounts::decode_from(&mut self.reader)
+        };
+        counts.map_err(|err| Error::new(ErrorKind::InvalidData, format!("Invalid counts table: {}", err)))
     }
     
     pub fn len(&self) -> usize {
@@ -183,7 +299,8 @@ pub struct SeqReader<'a, R: 'a + Read + Seek> {
     reader: &'a mut R,
     block_length: u32,
     info: &'a SeqInfo,
-    block: Option<Vec<(u32, u32)>>,
+    wide_indexes: bool,
+    block: Option<Vec<(u64, u64)>>,
     block_idx: usize,
     dec_buffer: &'a mut Vec<u8>,
     read_buffer: &'a mut Vec<u8>,
@@ -191,7 +308,7 @@ pub struct SeqReader<'a, R: 'a + Read + Seek> {
 
 impl<'a, R: 'a + Read + Seek> SeqReader<'a, R> {
     
-    fn read_block(&mut self, block_info: &SeqBlock) -> Result<Vec<(u32,u32)>> {
+    fn read_block(&mut self, block_info: &SeqBlock) -> Result<Vec<(u64,u64)>> {
         
         // read from disk (compressed)
         let buf = &mut self.read_buffer[0..block_info.comp_size as usize];
@@ -205,17 +322,17 @@ impl<'a, R: 'a + Read + Seek> SeqReader<'a, R> {
         decompressor.decompress(&buf, bufout, Flush::Finish).ok().expect("decompressing block");
 
         // decode back into vector
-        let block: Vec<(u32, u32)> = decode(bufout).ok().expect("decode block");
+        let block = decode_values(bufout, self.wide_indexes).ok().expect("decode block");
         
         return Ok(block);
     }
     
-    pub fn vir_get(&mut self, position: i32) -> Result<(u32, u32)> { 
+    pub fn vir_get(&mut self, position: i32) -> Result<(u64, u64)> { 
         if position < 0 { Ok((0, 0)) }
         else { self.get(position as u32) }
     }
 
-    pub fn get(&mut self, position: u32) -> Result<(u32, u32)> {
+    pub fn get(&mut self, position: u32) -> Result<(u64, u64)> {
         let idx = (position / self.block_length) as usize;
         
         //println!("get: pos: {} idx: {} blen: {} n_blocks: {} length: {}", position, idx, self.block_length, self.info.blocks.len(), self.info.length);
@@ -0,0 +1,134 @@
+//!
+//!	Extract selected sequences or regions of a sequence table into a new table.
+//!
+use std::fs::File;
+use std::io::{Read, Seek};
+use super::{SeqTable, SeqTableWriter, SeqStore, NmerCounts};
+use chroms::ChromAliases;
+use regions::BedRanges;
+
+// genome k-mer counts of one sequence
+fn sequence_counts<R: Read + Seek>(table: &mut SeqTable<R>, idx: usize, length: u32, counts: &mut NmerCounts) -> ::std::io::Result<()> {
+    let mut reader = try!(table.get_sequence_by_idx(idx));
+    for pos in 0..length {
+        let (plus, minus) = try!(reader.get(pos));
+        counts[plus].0 += 1;
+        counts[minus].1 += 1;
+    }
+    Ok(())
+}
+
+/// Write the sequences in `chroms` (all if None) of sequence table `input` to `outfile`
+///
+/// Whole sequences keep their compressed blocks. With `regions` (BED file), only sequences with regions
+/// are kept, at their full length, and positions outside the regions become unmappable. The genome k-mer
+/// counts are recomputed for the selection.
+pub fn subset_seqtable(input: &str, chroms: Option<&Vec<String>>, regions: Option<&str>, aliases: &ChromAliases, outfile: &str) -> Result<(), String> {
+    let file = try!(File::open(input).map_err(|err| format!("Failed to open sequence table file '{}': {}", input, err)));
+    let mut table = try!(SeqTable::open(file).map_err(|err| format!("Failed to read sequence table file '{}': {}", input, err)));
+    let seqinfos = table.sequences();
+
+    // selected sequences, in table order
+    let mut selected: Vec<usize> = match chroms {
+        Some(chroms) => {
+            if let Some(name) = chroms.iter().find(|name| !seqinfos.iter().any(|info| &info.name == *name)) {
+                return Err(format!("Sequence '{}' not found in sequence table '{}'.", name, input));
+            }
+            (0..seqinfos.len()).filter(|&idx| chroms.contains(&seqinfos[idx].name)).collect()
+        },
+        None => (0..seqinfos.len()).collect(),
+    };
+    let ranges = match regions {
+        Some(filename) => {
+            let names = seqinfos.iter().map(|info| info.name.clone()).collect();
+            let ranges = try!(BedRanges::parse(filename, names, &aliases.resolver(&seqinfos))
+                .map_err(|err| format!("Failed to read BED file {}: {}", filename, err)));
+            selected.retain(|&idx| !ranges.ranges(idx).is_empty());
+            Some(ranges)
+        },
+        None => None,
+    };
+    if selected.is_empty() {
+        return Err("No sequences selected.".to_string());
+    }
+
+    let params = table.params.clone();
+    let f_out = try!(File::create(outfile).map_err(|err| format!("Failed to create '{}': {}", outfile, err)));
+    let mut output = try!(SeqTableWriter::new(f_out, &params, table.softmask, table.block_length()).map_err(|err| err.to_string()));
+    let write_error = |err: ::std::io::Error| format!("Failed to copy sequences of '{}': {}", input, err);
+
+    match ranges {
+        Some(ranges) => {
+            // positions outside the regions are written as unmappable
+            for &idx in &selected {
+                let mut reader = try!(table.get_sequence_by_idx(idx).map_err(&write_error));
+                let mut seqwrt = output.create_sequence(seqinfos[idx].name.clone());
+                let mut pos = 0;
+                for &(start, end) in ranges.ranges(idx) {
+                    let end = end.min(seqinfos[idx].length);
+                    while pos < end {
+                        let (plus, minus) = if pos >= start { try!(reader.get(pos).map_err(&write_error)) } else { (0, 0) };
+                        seqwrt.write(plus, minus);
+                        pos += 1;
+                    }
+                }
+                while pos < seqinfos[idx].length {
+                    seqwrt.write(0, 0);
+                    pos += 1;
+                }
+            }
+        },
+        None => {
+            let mut counts = NmerCounts::new(params.nmer_count());
+            let max_block_size = table.max_block_size();
+            for &idx in &selected {
+                try!(sequence_counts(&mut table, idx, seqinfos[idx].length, &mut counts).map_err(&write_error));
+                let info = table.infotable()[idx].clone();
+                try!(output.append_compressed_sequence(&info, max_block_size, |block| table.compressed_block(block)).map_err(&write_error));
+            }
+            output.add_counts(&counts);
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use seqtable::SeqTableParams;
+    use seqtable::tests::{write_table, read_table};
+    use tempdir::TempDir;
+
+    #[test]
+    fn selected_sequences_and_regions() {
+        let dir = TempDir::new("seqoutbias_subset").unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (full, subset, bed) = (path("full.tbl"), path("subset.tbl"), path("regions.bed"));
+        let params = SeqTableParams::new(1, 0, 0, 1, &None, false);
+        write_table(&full, &params, vec![("chr1", vec![(1, 2), (3, 4), (0, 1)]),
+                                         ("chrM", vec![(2, 2)]),
+                                         ("chr2", vec![(4, 4), (1, 0), (2, 3), (3, 0), (1, 1)])]);
+        let aliases = ChromAliases::new(false);
+
+        // whole sequences, in table order
+        let chroms = vec!["chr2".to_string(), "chr1".to_string()];
+        subset_seqtable(&full, Some(&chroms), None, &aliases, &subset).unwrap();
+        let (sequences, counts) = read_table(&subset);
+        assert_eq!(vec!["chr1", "chr2"], sequences.iter().map(|&(ref name, _)| name.as_str()).collect::<Vec<&str>>());
+        assert_eq!(vec![(4, 4), (1, 0), (2, 3), (3, 0), (1, 1)], sequences[1].1);
+        assert_eq!(vec![(1, 2), (3, 2), (1, 1), (2, 1), (1, 2)], counts);
+
+        // regions, other positions are unmappable
+        fs::File::create(&bed).unwrap().write_all(b"chr2\t1\t3\nchr1\t2\t10\n").unwrap();
+        subset_seqtable(&full, None, Some(&bed), &aliases, &subset).unwrap();
+        let (sequences, counts) = read_table(&subset);
+        assert_eq!(vec![("chr1".to_string(), vec![(0, 0), (0, 0), (0, 1)]),
+                        ("chr2".to_string(), vec![(0, 0), (1, 0), (2, 3), (0, 0), (0, 0)])], sequences);
+        assert_eq!(vec![(6, 6), (1, 1), (1, 0), (0, 1), (0, 0)], counts);
+
+        let chroms = vec!["chrY".to_string()];
+        assert!(subset_seqtable(&full, Some(&chroms), None, &aliases, &subset).is_err());
+    }
+}
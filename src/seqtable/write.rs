@@ -13,16 +13,18 @@ use flate2::{Compression,Compress,Flush};
 use std::cmp::max;
 
 use ::seqtable::SeqTableParams;
+use ::seqtable::SoftMask;
 use ::seqtable::SeqStore;
+use ::seqtable::NmerCounts;
 
-#[derive(Debug, RustcEncodable, RustcDecodable, PartialEq)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, PartialEq)]
 pub struct SeqBlock {
     pub enc_size: u64,
     pub comp_size: u64,
     pub offset: u64,
 }
 
-#[derive(Debug, RustcEncodable, RustcDecodable, PartialEq)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, PartialEq)]
 pub struct SeqInfo {
     pub name: String,
     pub length: u32,
@@ -36,11 +38,12 @@ pub struct SeqTableWriter<W: Write + Seek> {
     infotable: Vec<SeqInfo>,
     block_length: u32,
     max_buffer_size: u64,
-    counts: Vec<(u64, u64, u64, u64)>,
+    counts: NmerCounts,
+    wide_indexes: bool,
 }
 
 impl<W: Write + Seek> SeqTableWriter<W> {
-    pub fn new(mut writer: W, params: &SeqTableParams, block_length: u32) -> Result<SeqTableWriter<W>> {
+    pub fn new(mut writer: W, params: &SeqTableParams, softmask: SoftMask, block_length: u32) -> Result<SeqTableWriter<W>> {
         let blen = block_length;
         
         // write version number
@@ -53,15 +56,15 @@ impl<W: Write + Seek> SeqTableWriter<W> {
         try!(writer.write_u8(params.minus_offset));
         try!(writer.write_u16::<LittleEndian>(params.read_length));
         try!(writer.write_u8( if params.strand_specific { 1u8 } else { 0u8 }));
+        try!(writer.write_u8(softmask.code()));
         // write mask if present
         if let Some(ref mask) = params.mask {
-            for &flag in mask.iter() {
-                if flag {
-                    try!(writer.write_u8(1));
-                } else {
-                    try!(writer.write_u8(0));
-                }
+            try!(writer.write_u8(1));
+            for pos in mask.iter() {
+                try!(writer.write_u8(pos.code()));
             }
+        } else {
+            try!(writer.write_u8(0));
         }
 
         // write block size
@@ -75,16 +78,11 @@ impl<W: Write + Seek> SeqTableWriter<W> {
         try!(writer.write_u64::<LittleEndian>(0));
         
         //
-        let hoffset = 6 * size_of::<u8>() + size_of::<u16>() + if params.mask.is_some() { params.kmer_length as usize * size_of::<u8>() } else { 0 } + size_of::<u32>();
+        let hoffset = 8 * size_of::<u8>() + size_of::<u16>() + if params.mask.is_some() { params.kmer_length as usize * size_of::<u8>() } else { 0 } + size_of::<u32>();
         let toffset = hoffset + 3 * size_of::<u64>();
         
         // allocate counts table
-        let mut counts: Vec<(u64, u64, u64, u64)> = Vec::new();
-        let nmer_count = params.nmer_count();
-        
-        for _ in 0..nmer_count {
-            counts.push((0,0,0,0));
-        }
+        let counts = NmerCounts::new(params.nmer_count());
         
         //
         Ok(SeqTableWriter{ 
@@ -95,6 +93,7 @@ impl<W: Write + Seek> SeqTableWriter<W> {
             block_length: blen,
             max_buffer_size: 0,
             counts: counts,
+            wide_indexes: params.wide_indexes(),
         })
     }
     
@@ -109,10 +108,39 @@ impl<W: Write + Seek> SeqTableWriter<W> {
             info: self.infotable.last_mut().unwrap(),
             block: Vec::new(),
             compressor: Compress::new(Compression::Best, false),
-            output: vec![0u8; self.block_length as usize * size_of::<(u32,u32)>()],
+            output: vec![0u8; self.block_length as usize * size_of::<(u64,u64)>()],
             block_length: self.block_length,
             max_buffer_size: &mut self.max_buffer_size,
             counts: &mut self.counts,
+            wide_indexes: self.wide_indexes,
+        }
+    }
+}
+
+impl<W: Write + Seek> SeqTableWriter<W> {
+    /// Append a sequence from its compressed blocks (as returned by `block_data`), copied from a table
+    /// with the same parameters and block length
+    pub fn append_compressed_sequence<F: FnMut(&SeqBlock) -> Result<Vec<u8>>>(&mut self, info: &SeqInfo, max_block_size: u64, mut block_data: F) -> Result<()> {
+        let mut blocks = Vec::with_capacity(info.blocks.len());
+        for block in &info.blocks {
+            let data = try!(block_data(block));
+            try!(self.writer.write_all(&data));
+            blocks.push(SeqBlock { enc_size: block.enc_size, comp_size: block.comp_size, offset: self.tailoffset });
+            self.tailoffset += block.comp_size;
+        }
+        self.infotable.push(SeqInfo { name: info.name.clone(), length: info.length, blocks: blocks });
+        self.max_buffer_size = max(self.max_buffer_size, max_block_size);
+        Ok(())
+    }
+
+    /// Add k-mer counts of sequences appended with `append_compressed_sequence`
+    pub fn add_counts(&mut self, counts: &NmerCounts) {
+        for (idx, (plus, minus, bam_plus, bam_minus)) in counts.iter() {
+            let total = &mut self.counts[idx];
+            total.0 += plus;
+            total.1 += minus;
+            total.2 += bam_plus;
+            total.3 += bam_minus;
         }
     }
 }
@@ -127,7 +155,7 @@ impl<W: Write + Seek> Drop for SeqTableWriter<W> {
         encode_into(&self.infotable, &mut self.writer, bincode::SizeLimit::Infinite).unwrap();
         
         // write counts table
-        encode_into(&self.counts, &mut self.writer, bincode::SizeLimit::Infinite).unwrap();
+        self.counts.encode_into(&mut self.writer).unwrap();
         
         let counts_offset = self.tailoffset + encoded_size(&self.infotable);
         
@@ -143,12 +171,13 @@ pub struct SequenceWriter<'a, W: 'a + Write> {
     offset: &'a mut u64,
     writer: &'a mut W,
     info: &'a mut SeqInfo,
-    block: Vec<(u32, u32)>,
+    block: Vec<(u64, u64)>,
     compressor: Compress,
     output: Vec<u8>,
     block_length: u32,
     max_buffer_size: &'a mut u64,
-    counts: &'a mut Vec<(u64, u64, u64, u64)>,
+    counts: &'a mut NmerCounts,
+    wide_indexes: bool,
 }
 
 impl<'a, W: 'a + Write> SequenceWriter<'a, W> { 
@@ -158,7 +187,12 @@ impl<'a, W: 'a + Write> SequenceWriter<'a, W> {
         }*/
         
         //   compress block and write to disk
-        let binvec: Vec<u8> = encode(&self.block, bincode::SizeLimit::Infinite).unwrap();
+        let binvec: Vec<u8> = if self.wide_indexes {
+            encode(&self.block, bincode::SizeLimit::Infinite).unwrap()
+        } else {
+            let block: Vec<(u32, u32)> = self.block.iter().map(|&(plus, minus)| (plus as u32, minus as u32)).collect();
+            encode(&block, bincode::SizeLimit::Infinite).unwrap()
+        };
         *self.max_buffer_size = max(*self.max_buffer_size, binvec.len() as u64);
 
         self.compressor.reset();
@@ -180,10 +214,10 @@ impl<'a, W: 'a + Write> SequenceWriter<'a, W> {
 }
 
 impl<'a, W: 'a + Write> SeqStore for SequenceWriter<'a, W> {   
-    fn write(&mut self, plus: u32, minus: u32) {
+    fn write(&mut self, plus: u64, minus: u64) {
         // add to counts
-        self.counts[plus as usize].0 += 1;
-        self.counts[minus as usize].1 += 1;
+        self.counts[plus].0 += 1;
+        self.counts[minus].1 += 1;
         
         // add to block
         self.block.push((plus, minus));
@@ -0,0 +1,518 @@
+//!
+//!	Sources of read cut-sites: SAM/BAM/CRAM alignments, BED6 read-end files, 10x-style fragment files
+//! or any sequence of in-memory records.
+//!
+use htslib::htslib as hts;
+use htslib::bam::HeaderView;
+use htslib::bam::record::Record;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::process::exit;
+use filter::{RecordCheck, PairedChecker, SingleChecker, FragmentChecker, FlagFilter, CutSite, CutSites, DEFAULT_EXCLUDE_FLAGS};
+use split::ReadSplit;
+use chroms::{ChromAliases, ChromResolver};
+use seqtable::SequenceInfo;
+use dedup::{UmiDedup, UmiSource};
+use fasta::decompress;
+
+/// Read selection settings shared by the tabulate and scale passes
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    pub min_quality: u8,
+    pub flags: FlagFilter,
+    /// Fragment length range for paired reads
+    pub pair_range: Option<(i32, i32)>,
+    pub only_paired: bool,
+    pub exact_length: bool,
+    pub tail_edge: bool,
+    pub skip_soft_clipped: bool,
+    /// Count both insertion sites of paired fragments, shifted by (plus, minus)
+    pub fragment_shift: Option<(i32, i32)>,
+    /// UMI location and single mismatch tolerance for duplicate removal
+    pub dedup_umi: Option<(UmiSource, bool)>,
+    /// Reference FASTA used to decode CRAM files
+    pub reference: Option<String>,
+    /// Sequence name matching against the sequence table
+    pub chroms: ChromAliases,
+    /// Only warn when the sequence lengths of an input differ from the sequence table
+    pub allow_length_mismatch: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            min_quality: 0,
+            flags: FlagFilter { require: 0, exclude: DEFAULT_EXCLUDE_FLAGS },
+            pair_range: None,
+            only_paired: false,
+            exact_length: false,
+            tail_edge: false,
+            skip_soft_clipped: false,
+            fragment_shift: None,
+            dedup_umi: None,
+            reference: None,
+            chroms: ChromAliases::default(),
+            allow_length_mismatch: false,
+        }
+    }
+}
+
+/// Cut-sites of an accepted read (or fragment)
+pub struct SiteEvent {
+    /// Sequence index within the source, see `AlignmentSource::chrom_name`
+    pub chrom: usize,
+    /// Output group as assigned by the `ReadSplit`
+    pub group: usize,
+    pub sites: CutSites,
+}
+
+/// Stream of cut-sites, grouped by sequence (i.e., sorted by coordinate)
+pub trait AlignmentSource {
+    /// Next accepted read, None when the source is exhausted
+    fn next_event(&mut self, split: &mut ReadSplit) -> Option<SiteEvent>;
+    /// Name of the sequence with index `chrom`
+    fn chrom_name(&self, chrom: usize) -> &str;
+    /// Sequences (and their lengths) declared upfront, e.g. by the @SQ lines of a SAM header
+    fn declared_sequences(&self) -> Vec<SequenceInfo> {
+        Vec::new()
+    }
+}
+
+/// Skip the events on the sequence of `first`, which is unknown to the sequence table, recording them
+/// as skipped reads. Returns the first event on the next sequence.
+pub fn skip_sequence(source: &mut AlignmentSource, split: &mut ReadSplit, chroms: &mut ChromResolver, first: SiteEvent) -> Option<SiteEvent> {
+    let mut count = 1;
+    let next = loop {
+        match source.next_event(split) {
+            Some(event) => if event.chrom == first.chrom { count += 1 } else { break Some(event) },
+            None => break None,
+        }
+    };
+    chroms.skipped(source.chrom_name(first.chrom), count);
+    next
+}
+
+/// Source over aligned records, applying read filters, read splitting and UMI deduplication
+pub struct RecordSource<I: Iterator<Item=Record>, C: RecordCheck> {
+    chroms: Vec<SequenceInfo>,
+    records: I,
+    checker: C,
+    dedup: Option<UmiDedup>,
+    tid: i32,
+}
+
+impl<I: Iterator<Item=Record>, C: RecordCheck> AlignmentSource for RecordSource<I, C> {
+    fn next_event(&mut self, split: &mut ReadSplit) -> Option<SiteEvent> {
+        while let Some(record) = self.records.next() {
+            // skip unmapped reads (tid = -1)
+            if record.tid() < 0 || !self.checker.valid(&record) {
+                continue;
+            }
+            if record.tid() != self.tid {
+                self.tid = record.tid();
+                if let Some(ref mut dedup) = self.dedup {
+                    dedup.clear();
+                }
+            }
+            let group = match split.group(&record) {
+                Some(group) => group,
+                None => continue,
+            };
+            // duplicates are detected within the counts group
+            if let Some(ref mut dedup) = self.dedup {
+                let counts_group = if split.split_counts() { group } else { 0 };
+                if dedup.is_duplicate(&record, counts_group) {
+                    continue;
+                }
+            }
+            return Some(SiteEvent { chrom: self.tid as usize, group: group, sites: self.checker.cut_sites(&record) });
+        }
+        None
+    }
+
+    fn chrom_name(&self, chrom: usize) -> &str {
+        &self.chroms[chrom].name
+    }
+
+    fn declared_sequences(&self) -> Vec<SequenceInfo> {
+        self.chroms.clone()
+    }
+}
+
+/// Source over `records`, whose tid's index into `chroms`, filtered according to `options`
+///
+/// `read_length` is the read length of the sequence table, used by `exact_length`.
+pub fn record_source<I: Iterator<Item=Record> + 'static>(chroms: Vec<SequenceInfo>, records: I, read_length: usize, options: &ReadOptions) -> Box<AlignmentSource> {
+    let dedup = options.dedup_umi.map(|(source, allow_mismatch)| UmiDedup::new(source, allow_mismatch));
+
+    if let Some((plus_shift, minus_shift)) = options.fragment_shift {
+        let (min, max) = options.pair_range.unwrap_or((0, 0));
+        let checker = FragmentChecker {
+            exact_length: options.exact_length,
+            read_length: read_length,
+            min_quality: options.min_quality,
+            flags: options.flags,
+            min_dist: min,
+            max_dist: max,
+            max_distance: options.pair_range.is_some(),
+            plus_shift: plus_shift,
+            minus_shift: minus_shift
+        };
+        Box::new(RecordSource { chroms: chroms, records: records, checker: checker, dedup: dedup, tid: -1 })
+    } else if options.pair_range.is_some() || options.only_paired {
+        let (min, max) = options.pair_range.unwrap_or((0, 0));
+        let checker = PairedChecker {
+            tail_edge: options.tail_edge,
+            exact_length: options.exact_length,
+            read_length: read_length,
+            min_quality: options.min_quality,
+            flags: options.flags,
+            skip_soft_clipped: options.skip_soft_clipped,
+            min_dist: min,
+            max_dist: max,
+            force_paired: options.only_paired,
+            max_distance: options.pair_range.is_some()
+        };
+        Box::new(RecordSource { chroms: chroms, records: records, checker: checker, dedup: dedup, tid: -1 })
+    } else {
+        let checker = SingleChecker {
+            tail_edge: options.tail_edge,
+            exact_length: options.exact_length,
+            read_length: read_length,
+            min_quality: options.min_quality,
+            flags: options.flags,
+            skip_soft_clipped: options.skip_soft_clipped
+        };
+        Box::new(RecordSource { chroms: chroms, records: records, checker: checker, dedup: dedup, tid: -1 })
+    }
+}
+
+/// Reader for SAM, BAM and CRAM files (format detected by htslib)
+pub struct HtsReader {
+    filename: String,
+    fp: *mut hts::htsFile,
+    hdr: *mut hts::bam_hdr_t,
+    // owns (and frees) hdr
+    header: HeaderView,
+}
+
+impl HtsReader {
+    /// Open alignment file, CRAM files are decoded using the `reference` FASTA if supplied
+    pub fn from_path(filename: &str, reference: Option<&str>) -> Result<HtsReader, String> {
+        let path = try!(CString::new(filename).map_err(|_| "invalid filename".to_string()));
+        let fp = unsafe { hts::hts_open(path.as_ptr(), b"r\0".as_ptr() as *const _) };
+        if fp.is_null() {
+            return Err("failed to open file".to_string());
+        }
+        if let Some(fasta) = reference {
+            let fai = try!(CString::new(fasta).map_err(|_| "invalid reference filename".to_string()));
+            if unsafe { hts::hts_set_fai_filename(fp, fai.as_ptr()) } != 0 {
+                unsafe { hts::hts_close(fp); }
+                return Err(format!("failed to use reference '{}'", fasta));
+            }
+        }
+        let header = unsafe { hts::sam_hdr_read(fp) };
+        if header.is_null() {
+            unsafe { hts::hts_close(fp); }
+            return Err("failed to read header".to_string());
+        }
+        Ok(HtsReader { filename: filename.to_string(), fp: fp, hdr: header, header: HeaderView::new(header) })
+    }
+
+    /// Sequences listed in the header
+    pub fn targets(&self) -> Vec<SequenceInfo> {
+        self.header.target_names().iter().enumerate().map(|(tid, name)| SequenceInfo {
+            name: String::from_utf8_lossy(name).into_owned(),
+            length: self.header.target_len(tid as u32).unwrap_or(0),
+        }).collect()
+    }
+}
+
+impl Iterator for HtsReader {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let record = Record::new();
+        match unsafe { hts::sam_read1(self.fp, self.hdr, record.inner) } {
+            -1 => None,
+            res if res < -1 => {
+                println!("Error: Failed to read record from '{}'", self.filename);
+                exit(1);
+            },
+            _ => Some(record),
+        }
+    }
+}
+
+impl Drop for HtsReader {
+    fn drop(&mut self) {
+        unsafe { hts::hts_close(self.fp); }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum TextFormat {
+    /// BED6 read ends: chrom, start, end, name, score, strand
+    Bed,
+    /// 10x fragments: chrom, start, end, barcode, count
+    Fragments,
+}
+
+/// Source over plain text (optionally gzip/bgzip compressed) BED6 or fragments files
+///
+/// BED records count the 5' end of the read (3' end with `tail_edge`). Fragment coordinates are
+/// already adjusted for the Tn5 insertion, so both fragment ends are counted as is, once per line
+/// regardless of the duplicate count column. Fragments outside `pair_range` (--pdist) are skipped.
+pub struct TextSource {
+    filename: String,
+    format: TextFormat,
+    reader: Box<BufRead>,
+    line: String,
+    line_no: usize,
+    chroms: Vec<String>,
+    chrom_index: HashMap<String, usize>,
+    tail_edge: bool,
+    fragment_range: Option<(i32, i32)>,
+}
+
+// read options that need alignment records, --pdist also applies to fragment lengths
+fn alignment_options(format: TextFormat, options: &ReadOptions) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    if options.min_quality > 0 { names.push("--qual"); }
+    if options.flags.require != 0 || options.flags.exclude != DEFAULT_EXCLUDE_FLAGS { names.push("--require-flags/--exclude-flags/--exclude-duplicates"); }
+    if options.pair_range.is_some() && format == TextFormat::Bed { names.push("--pdist"); }
+    if options.only_paired { names.push("--only-paired"); }
+    if options.exact_length { names.push("--exact-length"); }
+    if options.skip_soft_clipped { names.push("--skip-soft-clipped"); }
+    if options.fragment_shift.is_some() { names.push("--fragment-mode"); }
+    if options.dedup_umi.is_some() { names.push("--dedup-umi"); }
+    names
+}
+
+impl TextSource {
+    fn new(filename: &str, format: TextFormat, options: &ReadOptions) -> Result<TextSource, String> {
+        let unsupported = alignment_options(format, options);
+        if !unsupported.is_empty() {
+            return Err(format!("options need SAM/BAM/CRAM read files: {}", unsupported.join(", ")));
+        }
+        let file = try!(File::open(filename).map_err(|err| err.to_string()));
+        let reader: Box<BufRead> = Box::new(BufReader::new(try!(decompress(file).map_err(|err| err.to_string()))));
+
+        Ok(TextSource {
+            filename: filename.to_string(),
+            format: format,
+            reader: reader,
+            line: String::new(),
+            line_no: 0,
+            chroms: Vec::new(),
+            chrom_index: HashMap::new(),
+            tail_edge: options.tail_edge,
+            fragment_range: options.pair_range,
+        })
+    }
+
+    fn invalid_line(&self) -> ! {
+        println!("Error: Invalid line {} in '{}': {}", self.line_no, self.filename, self.line.trim());
+        exit(1);
+    }
+
+    fn chrom(&mut self, name: &str) -> usize {
+        if let Some(&idx) = self.chrom_index.get(name) {
+            return idx;
+        }
+        self.chroms.push(name.to_string());
+        self.chrom_index.insert(name.to_string(), self.chroms.len() - 1);
+        self.chroms.len() - 1
+    }
+}
+
+impl AlignmentSource for TextSource {
+    fn next_event(&mut self, split: &mut ReadSplit) -> Option<SiteEvent> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => self.line_no += 1,
+                Err(err) => {
+                    println!("Error: Failed to read '{}': {}", self.filename, err);
+                    exit(1);
+                },
+            }
+            let line = self.line.trim().to_string();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue;
+            }
+            let parts: Vec<&str> = line.split('\t').collect();
+            let min_fields = if self.format == TextFormat::Bed { 6 } else { 4 };
+            if parts.len() < min_fields {
+                self.invalid_line();
+            }
+            let (start, end): (i32, i32) = match (parts[1].parse(), parts[2].parse()) {
+                (Ok(start), Ok(end)) if start < end => (start, end),
+                _ => self.invalid_line(),
+            };
+
+            let (group, sites) = match self.format {
+                TextFormat::Bed => {
+                    let reverse = match parts[5] {
+                        "+" => false,
+                        "-" => true,
+                        _ => self.invalid_line(),
+                    };
+                    let pos = if reverse == self.tail_edge { start } else { end - 1 };
+                    (split.group_by_value(None), CutSites::one(CutSite { pos: pos, reverse: reverse }))
+                },
+                TextFormat::Fragments => {
+                    if let Some((min, max)) = self.fragment_range {
+                        if end - start < min || end - start > max {
+                            continue;
+                        }
+                    }
+                    (split.group_by_value(Some(parts[3].as_bytes())),
+                     CutSites::two(CutSite { pos: start, reverse: false }, CutSite { pos: end - 1, reverse: true }))
+                },
+            };
+            let group = match group {
+                Some(group) => group,
+                None => continue,
+            };
+            let chrom = self.chrom(parts[0]);
+            return Some(SiteEvent { chrom: chrom, group: group, sites: sites });
+        }
+    }
+
+    fn chrom_name(&self, chrom: usize) -> &str {
+        &self.chroms[chrom]
+    }
+}
+
+fn text_format(filename: &str) -> Option<TextFormat> {
+    if filename.ends_with(".bed") || filename.ends_with(".bed.gz") {
+        Some(TextFormat::Bed)
+    } else if filename.ends_with(".tsv") || filename.ends_with(".tsv.gz") {
+        Some(TextFormat::Fragments)
+    } else {
+        None
+    }
+}
+
+/// Check if the file is read as SAM/BAM/CRAM, i.e., if read flags and tags are available
+pub fn is_alignment_file(filename: &str) -> bool {
+    text_format(filename).is_none()
+}
+
+/// Open a source of cut-sites, the format is selected by filename extension:
+/// `.bed[.gz]` for BED6 read ends, `.tsv[.gz]` for fragments and SAM/BAM/CRAM otherwise
+pub fn open(filename: &str, read_length: usize, options: &ReadOptions) -> Box<AlignmentSource> {
+    if let Some(format) = text_format(filename) {
+        match TextSource::new(filename, format, options) {
+            Ok(source) => Box::new(source),
+            Err(err) => {
+                println!("Error: Failed to open '{}': {}", filename, err);
+                exit(1);
+            },
+        }
+    } else {
+        let reader = match HtsReader::from_path(filename, options.reference.as_ref().map(|s| s.as_str())) {
+            Ok(value) => value,
+            Err(err) => {
+                println!("Error: Failed to open alignment file '{}': {}", filename, err);
+                exit(1);
+            },
+        };
+        let chroms = reader.targets();
+        record_source(chroms, reader, read_length, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tempdir::TempDir;
+    use htslib::bam::record::{Aux, Cigar, CigarString};
+
+    fn record(tid: i32, pos: i32, reverse: bool, umi: &[u8]) -> Record {
+        let mut rec = Record::new();
+        rec.set(b"r01", &CigarString(vec![Cigar::Match(10)]), &[b'A'; 10], &[30u8; 10]);
+        rec.set_tid(tid);
+        rec.set_pos(pos);
+        rec.set_mtid(-1);
+        rec.set_mpos(-1);
+        rec.set_mapq(30);
+        if reverse { rec.set_reverse(); }
+        rec.push_aux(b"RX", &Aux::String(umi));
+        rec
+    }
+
+    fn events(source: &mut AlignmentSource, split: &mut ReadSplit) -> Vec<(String, usize, Vec<CutSite>)> {
+        let mut result = Vec::new();
+        while let Some(event) = source.next_event(split) {
+            result.push((source.chrom_name(event.chrom).to_string(), event.group, event.sites.collect()));
+        }
+        result
+    }
+
+    #[test]
+    fn records_are_filtered_and_deduplicated() {
+        let records = vec![
+            record(-1, -1, false, b"AAAA"),
+            record(0, 10, false, b"AAAA"),
+            record(0, 10, false, b"AAAA"),
+            record(0, 12, true, b"AAAA"),
+            record(1, 10, false, b"AAAA"),
+        ];
+        let options = ReadOptions { dedup_umi: Some((UmiSource::Tag(*b"RX"), false)), ..ReadOptions::default() };
+        let chroms = vec![SequenceInfo { name: "chr1".to_string(), length: 100 }, SequenceInfo { name: "chr2".to_string(), length: 100 }];
+        let mut source = record_source(chroms, records.into_iter(), 10, &options);
+
+        let result = events(&mut *source, &mut ReadSplit::None);
+        assert_eq!(vec![
+            ("chr1".to_string(), 0, vec![CutSite { pos: 10, reverse: false }]),
+            ("chr1".to_string(), 0, vec![CutSite { pos: 21, reverse: true }]),
+            ("chr2".to_string(), 0, vec![CutSite { pos: 10, reverse: false }]),
+        ], result);
+    }
+
+    #[test]
+    fn bed_and_fragment_files() {
+        let dir = TempDir::new("seqoutbias_source").unwrap();
+        let bedfile = dir.path().join("reads.bed.gz");
+        let fragfile = dir.path().join("fragments.tsv");
+        let mut encoder = GzEncoder::new(File::create(&bedfile).unwrap(), Compression::Default);
+        write!(encoder, "track name=reads\nchr1\t10\t20\tr1\t0\t+\nchr1\t10\t20\tr2\t0\t-\n").unwrap();
+        encoder.finish().unwrap();
+        write!(File::create(&fragfile).unwrap(), "# comment\nchr2\t10\t20\tAAAC-1\t3\nchr2\t30\t50\tGGTA-1\t1\n").unwrap();
+
+        let mut bed = open(bedfile.to_str().unwrap(), 10, &ReadOptions::default());
+        assert_eq!(vec![
+            ("chr1".to_string(), 0, vec![CutSite { pos: 10, reverse: false }]),
+            ("chr1".to_string(), 0, vec![CutSite { pos: 19, reverse: true }]),
+        ], events(&mut *bed, &mut ReadSplit::None));
+
+        let mut fragments = open(fragfile.to_str().unwrap(), 10, &ReadOptions::default());
+        let mut split = ReadSplit::by_tag("CB", Some(vec!["GGTA-1".to_string()]));
+        assert_eq!(vec![
+            ("chr2".to_string(), 0, vec![CutSite { pos: 10, reverse: false }, CutSite { pos: 19, reverse: true }]),
+            ("chr2".to_string(), 1, vec![CutSite { pos: 30, reverse: false }, CutSite { pos: 49, reverse: true }]),
+        ], events(&mut *fragments, &mut split));
+
+        // fragment length range, alignment options are rejected
+        let options = ReadOptions { pair_range: Some((15, 100)), ..ReadOptions::default() };
+        let mut fragments = open(fragfile.to_str().unwrap(), 10, &options);
+        assert_eq!(vec![
+            ("chr2".to_string(), 0, vec![CutSite { pos: 30, reverse: false }, CutSite { pos: 49, reverse: true }]),
+        ], events(&mut *fragments, &mut ReadSplit::None));
+        assert!(TextSource::new(bedfile.to_str().unwrap(), TextFormat::Bed, &options).is_err());
+        let options = ReadOptions { min_quality: 10, exact_length: true, fragment_shift: Some((4, -5)), ..ReadOptions::default() };
+        match TextSource::new(fragfile.to_str().unwrap(), TextFormat::Fragments, &options) {
+            Err(err) => assert_eq!("options need SAM/BAM/CRAM read files: --qual, --exact-length, --fragment-mode", err),
+            Ok(_) => panic!("alignment options accepted for a fragments file"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//!
+//!	Assignment of aligned reads to separate output groups (pair ends, cell barcodes, read groups, ...).
+//!
+use htslib::bam::record::{Record, Aux};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+
+/// Reads grouped by the value of a SAM aux tag
+pub struct TagSplit {
+    tag: [u8; 2],
+    values: Vec<String>,
+    index: HashMap<Vec<u8>, usize>,
+    grow: bool,
+}
+
+impl TagSplit {
+    fn group(&mut self, rec: &Record) -> usize {
+        let value = match rec.aux(&self.tag) {
+            Some(Aux::String(value)) => value.to_vec(),
+            Some(Aux::Char(value)) => vec![value],
+            Some(Aux::Integer(value)) => value.to_string().into_bytes(),
+            _ => return 0,
+        };
+        self.lookup(&value)
+    }
+
+    fn lookup(&mut self, value: &[u8]) -> usize {
+        if let Some(&idx) = self.index.get(value) {
+            return idx;
+        }
+        if !self.grow {
+            return 0;
+        }
+        self.values.push(String::from_utf8_lossy(value).into_owned());
+        self.index.insert(value.to_vec(), self.values.len());
+        self.values.len()
+    }
+}
+
+/// How reads are split into separate count tables and pile-ups
+///
+/// Group 0 always exists: all reads, the first read of pairs or the pool of reads whose tag value
+/// is missing or not in the whitelist.
+pub enum ReadSplit {
+    /// Single group with all reads
+    None,
+    /// First and last reads of pairs in separate pile-ups, sharing the same scale factors
+    PairEnd,
+    /// One group per tag value
+    Tag(TagSplit),
+}
+
+impl ReadSplit {
+    /// Split reads by the value of `tag`, using only the values in `whitelist` if supplied
+    pub fn by_tag(tag: &str, whitelist: Option<Vec<String>>) -> ReadSplit {
+        let bytes = tag.as_bytes();
+        let mut split = TagSplit { tag: [bytes[0], bytes[1]], values: Vec::new(), index: HashMap::new(), grow: whitelist.is_none() };
+
+        if let Some(values) = whitelist {
+            for value in values {
+                if !split.index.contains_key(value.as_bytes()) {
+                    split.values.push(value.clone());
+                    split.index.insert(value.into_bytes(), split.values.len());
+                }
+            }
+        }
+        ReadSplit::Tag(split)
+    }
+
+    /// Group index for a record, None if the record does not belong to any group
+    pub fn group(&mut self, rec: &Record) -> Option<usize> {
+        match *self {
+            ReadSplit::None => Some(0),
+            ReadSplit::PairEnd => {
+                if rec.is_first_in_template() {
+                    Some(0)
+                } else if rec.is_last_in_template() {
+                    Some(1)
+                } else {
+                    None
+                }
+            },
+            ReadSplit::Tag(ref mut split) => Some(split.group(rec)),
+        }
+    }
+
+    /// Group index for a read known only by its tag value (e.g. the barcode of a fragments file)
+    ///
+    /// Pair end splits need the read flags, so such reads do not belong to any group.
+    pub fn group_by_value(&mut self, value: Option<&[u8]>) -> Option<usize> {
+        match *self {
+            ReadSplit::None => Some(0),
+            ReadSplit::PairEnd => None,
+            ReadSplit::Tag(ref mut split) => Some(value.map_or(0, |value| split.lookup(value))),
+        }
+    }
+
+    /// Number of groups (for tags without a whitelist: number of values seen so far plus pool)
+    pub fn len(&self) -> usize {
+        match *self {
+            ReadSplit::None => 1,
+            ReadSplit::PairEnd => 2,
+            ReadSplit::Tag(ref split) => split.values.len() + 1,
+        }
+    }
+
+    /// Whether each group gets its own k-mer counts (and hence its own scale factors)
+    pub fn split_counts(&self) -> bool {
+        match *self {
+            ReadSplit::Tag(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Group name, empty for a single group
+    pub fn name(&self, group: usize) -> String {
+        match *self {
+            ReadSplit::None => String::new(),
+            ReadSplit::PairEnd => format!("PE{}", group + 1),
+            ReadSplit::Tag(ref split) => if group == 0 { "other".to_string() } else { split.values[group - 1].clone() },
+        }
+    }
+
+    /// Filename suffix for group outputs
+    pub fn suffix(&self, group: usize) -> String {
+        match *self {
+            ReadSplit::None => String::new(),
+            _ => {
+                let name: String = self.name(group).chars()
+                    .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+                    .collect();
+                format!("_{}", name)
+            }
+        }
+    }
+}
+
+/// Read tag value whitelist, one value per line
+pub fn read_whitelist(filename: &str) -> Result<Vec<String>> {
+    let file = try!(File::open(filename));
+    let reader = BufReader::new(file);
+    let mut values = Vec::new();
+
+    for res in reader.lines() {
+        let line = try!(res);
+        let value = line.trim();
+        if !value.is_empty() {
+            values.push(value.to_string());
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use htslib::bam::record::CigarString;
+
+    fn record(barcode: Option<&[u8]>) -> Record {
+        let mut rec = Record::new();
+        rec.set(b"r01", &CigarString(vec![]), b"", b"");
+        if let Some(value) = barcode {
+            rec.push_aux(b"CB", &Aux::String(value));
+        }
+        rec
+    }
+
+    #[test]
+    fn tag_values_are_added_as_seen() {
+        let mut split = ReadSplit::by_tag("CB", None);
+        assert_eq!(Some(1), split.group(&record(Some(b"AAAC-1"))));
+        assert_eq!(Some(2), split.group(&record(Some(b"GGTA-1"))));
+        assert_eq!(Some(1), split.group(&record(Some(b"AAAC-1"))));
+        assert_eq!(Some(0), split.group(&record(None)));
+        assert_eq!(3, split.len());
+        assert_eq!("_AAAC-1", split.suffix(1));
+        assert_eq!("_other", split.suffix(0));
+    }
+
+    #[test]
+    fn whitelist_pools_other_values() {
+        let mut split = ReadSplit::by_tag("CB", Some(vec!["GGTA-1".to_string(), "AAAC-1".to_string()]));
+        assert_eq!(Some(2), split.group(&record(Some(b"AAAC-1"))));
+        assert_eq!(Some(0), split.group(&record(Some(b"TTTT-1"))));
+        assert_eq!(3, split.len());
+        assert_eq!("GGTA-1", split.name(1));
+    }
+}
@@ -140,6 +140,21 @@ impl<R: BufRead> UnMap<R> {
         Ok(())
     }
     
+    /// Skip ahead to sequence number `seqnumber` (as numbered in the tallymer file, i.e., FASTA file order)
+    pub fn seek_sequence(&mut self, seqnumber: u32) -> Result<()> {
+        while self.seqnumber < seqnumber {
+            if self.seqnumber == self.next_seqnumber {
+                // there is no more data to read
+                self.data.clear();
+                self.seqnumber = seqnumber;
+                self.next_seqnumber = seqnumber;
+                break;
+            }
+            try!(self.read_next_sequence());
+        }
+        Ok(())
+    }
+
     /// Query position
     ///
     /// Returns a pair of boolean values, which are true if the plus or minus strand read, respectively, is mappable at that position.
@@ -3,6 +3,7 @@
 //!	if no such file is present.
 //!
 extern crate flate2;
+extern crate libc;
 
 use std::env;
 use std::process::Command;
@@ -11,16 +12,22 @@ use std::process::Stdio;
 use std::process::Child;
 use std::path::Path;
 use std::path::PathBuf;
-use std::ffi::OsString;
-use std::ffi::OsStr;
+use std::ffi::{CString, OsString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
 use std::fs;
 use std::fs::File;
 use std::io::Result;
 use std::io::ErrorKind;
 use std::io::copy;
 use std::io::BufWriter;
+use std::io::BufReader;
+use std::thread;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use twobit::{TwoBitFile, TwoBitFasta, is_twobit_file};
+use randfile::random_file;
 
 
 /// Get basename for file after excluding a .gz suffix if it exists
@@ -72,29 +79,43 @@ fn file_exists(filename: &OsStr) -> bool {
 	}
 }
 
-fn wait_or_exit(spawned: Result<Child>, name: &str) {
+/// Wait for the process to finish, exit on failure after removing the `temporary` file
+fn wait_or_exit(spawned: Result<Child>, name: &str, temporary: Option<&Path>) {
+	let cleanup = || if let Some(path) = temporary {
+		let _ = fs::remove_file(path);
+	};
 	match spawned {
 		Ok(mut child) => {
 			let ecode = child.wait().unwrap_or_else(|e| {
 				println!("failed to wait on '{}': {}", name, e);
+				cleanup();
 				exit(-1)
 			});
 			if !ecode.success() {
 				println!("'{}' failed!\n", name);
+				cleanup();
 				exit(-1);
 			}
 		},
 		Err(e) => {
 			println!("failed to execute '{}': {}", name, e);
+			cleanup();
 			exit(1)
 		}
 	}
 }
 
 
-fn pipe_to_gzipped_file_or_exit(proc1: Result<Child>, output: &OsStr) {
+fn pipe_to_gzipped_file_or_exit(proc1: Result<Child>, output: &OsStr, twobit: Option<&str>) {
 	let mut proc1 = proc1.ok().expect("spawn gt tallymer");
 	
+	// .2bit genomes are streamed to the query input as FASTA
+	let feeder = twobit.map(|twobit| {
+		let mut reader = open_twobit_fasta(twobit);
+		let mut stdin = proc1.stdin.take().unwrap();
+		thread::spawn(move || copy(&mut reader, &mut stdin))
+	});
+	
 	let file = File::create(output).ok().expect("create file");
 	let writer = BufWriter::new(file);
 	let mut encoder = GzEncoder::new(writer, Compression::Best);
@@ -110,6 +131,84 @@ fn pipe_to_gzipped_file_or_exit(proc1: Result<Child>, output: &OsStr) {
 			println!("gt tallymer!\n");
 			exit(1);
 	}
+	if let Some(feeder) = feeder {
+		if let Ok(Err(err)) = feeder.join() {
+			println!("Error: Failed to convert .2bit file '{}': {}", twobit.unwrap(), err);
+			exit(1);
+		}
+	}
+}
+
+fn open_twobit_fasta(twobit: &str) -> TwoBitFasta<BufReader<File>> {
+	match TwoBitFile::open(twobit) {
+		Ok(file) => file.fasta(),
+		Err(err) => { println!("Error: Failed to read .2bit file '{}': {}", twobit, err); exit(1) },
+	}
+}
+
+// path of the temporary file to remove if the process is interrupted
+static INTERRUPT_CLEANUP: AtomicPtr<libc::c_char> = AtomicPtr::new(ptr::null_mut());
+
+const CLEANUP_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+extern "C" fn remove_on_signal(signum: libc::c_int) {
+	let path = INTERRUPT_CLEANUP.swap(ptr::null_mut(), Ordering::SeqCst);
+	unsafe {
+		if !path.is_null() {
+			libc::unlink(path);
+		}
+		// terminate as the default handler would
+		libc::signal(signum, libc::SIG_DFL);
+		libc::raise(signum);
+	}
+}
+
+/// File removed when dropped or when the process is interrupted (SIGINT, SIGTERM or SIGHUP)
+struct TemporaryFile {
+	path: PathBuf,
+}
+
+impl TemporaryFile {
+	fn new(path: PathBuf) -> TemporaryFile {
+		let cpath = CString::new(path.as_os_str().as_bytes()).expect("temporary file path");
+		let previous = INTERRUPT_CLEANUP.swap(cpath.into_raw(), Ordering::SeqCst);
+		assert!(previous.is_null(), "only one temporary file is removed on interrupt");
+		for &signum in CLEANUP_SIGNALS.iter() {
+			unsafe { libc::signal(signum, remove_on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+		}
+		TemporaryFile { path: path }
+	}
+
+	fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for TemporaryFile {
+	fn drop(&mut self) {
+		for &signum in CLEANUP_SIGNALS.iter() {
+			unsafe { libc::signal(signum, libc::SIG_DFL) };
+		}
+		let cpath = INTERRUPT_CLEANUP.swap(ptr::null_mut(), Ordering::SeqCst);
+		if !cpath.is_null() {
+			drop(unsafe { CString::from_raw(cpath) });
+		}
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+/// Write .2bit genome to a temporary FASTA file in `workdir`, as Genome Tools only reads FASTA
+fn twobit_to_fasta(twobit: &str, workdir: &Path) -> TemporaryFile {
+	let path = env::current_dir().expect("Current directory is invalid!").join(workdir).join(random_file("twobit", ".fa", 5));
+	let temporary = TemporaryFile::new(path);
+	let mut reader = open_twobit_fasta(twobit);
+	let mut writer = BufWriter::new(File::create(temporary.path()).ok().expect("create temporary FASTA file"));
+	if let Err(err) = copy(&mut reader, &mut writer) {
+		println!("Error: Failed to convert .2bit file '{}': {}", twobit, err);
+		let _ = fs::remove_file(temporary.path());
+		exit(1);
+	}
+	temporary
 }
 
 /// Create tallymer based mapabillity file 
@@ -117,10 +216,18 @@ fn tallymer_create(workdir: PathBuf, fasta: &str, readlen: u16, parts: u8, outfi
 	let sft_filename = create_suffixtree_filename(fasta);
 	let tidx_filename = create_tallymerindex_filename(fasta, readlen);
 	
+	let twobit = is_twobit_file(fasta);
+	
 	// create suffix-tree index if it does not exist
 	let mut tmp = sft_filename.clone();
 	tmp.push(".suf"); // actually there are a bunch of files that must exist ...
-	if !file_exists(&tmp) {
+	if !file_exists(workdir.join(&tmp).as_os_str()) {
+		// sequence input for Genome Tools, index file names still follow the .2bit file name
+		let twobit_fasta = if twobit { Some(twobit_to_fasta(fasta, &workdir)) } else { None };
+		let db = match twobit_fasta {
+			Some(ref temporary) => temporary.path().as_os_str(),
+			None => OsStr::new(fasta),
+		};
 		let gt_proc = Command::new("gt")
 		    .current_dir(workdir.as_path())
 			.arg("suffixerator")
@@ -133,12 +240,12 @@ fn tallymer_create(workdir: PathBuf, fasta: &str, readlen: u16, parts: u8, outfi
 			.arg("-parts")
 			.arg(format!("{}", parts)) // reduce in-memory requirements to 1/nth
 			.arg("-db")
-			.arg(fasta)
+			.arg(db)
 			.arg("-indexname")
 			.arg(&sft_filename)
 			.spawn();
 		
-		wait_or_exit(gt_proc, "gt suffixerator");
+		wait_or_exit(gt_proc, "gt suffixerator", twobit_fasta.as_ref().map(|temporary| temporary.path()));
 	}
 	
 	// create genome index
@@ -158,7 +265,7 @@ fn tallymer_create(workdir: PathBuf, fasta: &str, readlen: u16, parts: u8, outfi
 		.arg(&sft_filename)
 		.spawn();
 	
-	wait_or_exit(gt_proc, "gt tallymer mkindex");
+	wait_or_exit(gt_proc, "gt tallymer mkindex", None);
 	
 	// generate final file
 	let gt_proc = Command::new("gt")
@@ -173,11 +280,12 @@ fn tallymer_create(workdir: PathBuf, fasta: &str, readlen: u16, parts: u8, outfi
 		.arg("-tyr")
 		.arg(&tidx_filename)
 		.arg("-q")
-		.arg(fasta)
+		.arg(if twobit { "/dev/stdin" } else { fasta })
+		.stdin(if twobit { Stdio::piped() } else { Stdio::inherit() })
 		.stdout(Stdio::piped())
 		.spawn();
 	
-	pipe_to_gzipped_file_or_exit(gt_proc, outfile);
+	pipe_to_gzipped_file_or_exit(gt_proc, outfile, if twobit { Some(fasta) } else { None });
 }
 
 /// Get tallymer mappability file's path
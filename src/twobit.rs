@@ -0,0 +1,263 @@
+//!
+//!	Reader for UCSC .2bit genome files, including N and soft-mask (lowercase) blocks.
+//!
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Cursor, Result, Error, ErrorKind, SeekFrom};
+use byteorder::{ReadBytesExt, LittleEndian, BigEndian};
+use fasta::GenomeSequences;
+
+const TWOBIT_SIGNATURE: u32 = 0x1A412743;
+const TWOBIT_BASES: [u8; 4] = [b'T', b'C', b'A', b'G'];
+/// Bases per line of the FASTA formatted output
+const FASTA_LINE_LENGTH: usize = 60;
+
+/// Check if the file is read as .2bit (by filename extension)
+pub fn is_twobit_file(filename: &str) -> bool {
+    filename.ends_with(".2bit")
+}
+
+/// .2bit file, with its sequence index
+pub struct TwoBitFile<R: Read + Seek> {
+    reader: R,
+    big_endian: bool,
+    sequences: Vec<(String, u64)>,
+}
+
+impl TwoBitFile<BufReader<File>> {
+    pub fn open(filename: &str) -> Result<TwoBitFile<BufReader<File>>> {
+        let file = try!(File::open(filename));
+        TwoBitFile::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> TwoBitFile<R> {
+    /// Read the file header and sequence index, version 1 files have 64-bit sequence offsets
+    pub fn new(mut reader: R) -> Result<TwoBitFile<R>> {
+        let signature = try!(reader.read_u32::<LittleEndian>());
+        let big_endian = if signature == TWOBIT_SIGNATURE {
+            false
+        } else if signature.swap_bytes() == TWOBIT_SIGNATURE {
+            true
+        } else {
+            return Err(Error::new(ErrorKind::InvalidData, "not a .2bit file (invalid signature)"));
+        };
+        let mut result = TwoBitFile { reader: reader, big_endian: big_endian, sequences: Vec::new() };
+
+        let version = try!(result.read_u32());
+        if version > 1 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("unsupported .2bit version {} (versions 0 and 1 are supported)", version)));
+        }
+        let count = try!(result.read_u32());
+        try!(result.read_u32()); // reserved
+
+        for _ in 0..count {
+            let name_size = try!(result.reader.read_u8());
+            let mut name = vec![0u8; name_size as usize];
+            try!(result.reader.read_exact(&mut name));
+            let offset = if version == 1 { try!(result.read_u64()) } else { try!(result.read_u32()) as u64 };
+            result.sequences.push((String::from_utf8_lossy(&name).into_owned(), offset));
+        }
+        Ok(result)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        if self.big_endian {
+            self.reader.read_u32::<BigEndian>()
+        } else {
+            self.reader.read_u32::<LittleEndian>()
+        }
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        if self.big_endian {
+            self.reader.read_u64::<BigEndian>()
+        } else {
+            self.reader.read_u64::<LittleEndian>()
+        }
+    }
+
+    // (start, size) blocks
+    fn read_blocks(&mut self) -> Result<Vec<(u32, u32)>> {
+        let count = try!(self.read_u32());
+        let mut starts = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            starts.push(try!(self.read_u32()));
+        }
+        let mut blocks = Vec::with_capacity(count as usize);
+        for start in starts {
+            blocks.push((start, try!(self.read_u32())));
+        }
+        Ok(blocks)
+    }
+
+    /// Sequence names, in file order
+    pub fn sequence_names(&self) -> Vec<String> {
+        self.sequences.iter().map(|&(ref name, _)| name.clone()).collect()
+    }
+
+    /// Number of bases of sequence `idx`
+    pub fn sequence_length(&mut self, idx: usize) -> Result<u32> {
+        let offset = self.sequences[idx].1;
+        try!(self.reader.seek(SeekFrom::Start(offset)));
+        self.read_u32()
+    }
+
+    /// Bases of sequence `idx` as ASCII letters, N blocks as N and soft-masked blocks in lowercase
+    pub fn sequence(&mut self, idx: usize) -> Result<Vec<u8>> {
+        let offset = self.sequences[idx].1;
+        try!(self.reader.seek(SeekFrom::Start(offset)));
+        let length = try!(self.read_u32()) as usize;
+        let n_blocks = try!(self.read_blocks());
+        let mask_blocks = try!(self.read_blocks());
+        try!(self.read_u32()); // reserved
+
+        let mut packed = vec![0u8; (length + 3) / 4];
+        try!(self.reader.read_exact(&mut packed));
+        let mut bases = Vec::with_capacity(length);
+        for pos in 0..length {
+            let code = (packed[pos / 4] >> (6 - 2 * (pos % 4))) & 3;
+            bases.push(TWOBIT_BASES[code as usize]);
+        }
+
+        for (start, size) in n_blocks {
+            for base in try!(block_range(&mut bases, start, size)) {
+                *base = b'N';
+            }
+        }
+        for (start, size) in mask_blocks {
+            for base in try!(block_range(&mut bases, start, size)) {
+                *base = base.to_ascii_lowercase();
+            }
+        }
+        Ok(bases)
+    }
+
+    /// FASTA formatted stream of all sequences
+    pub fn fasta(self) -> TwoBitFasta<R> {
+        TwoBitFasta { file: self, next_idx: 0, buffer: Cursor::new(Vec::new()) }
+    }
+}
+
+impl<R: Read + Seek> GenomeSequences for TwoBitFile<R> {
+    fn sequence_names(&self) -> Vec<String> {
+        TwoBitFile::sequence_names(self)
+    }
+
+    fn sequence_length(&mut self, name: &str) -> ::std::result::Result<u32, String> {
+        match self.sequences.iter().position(|&(ref seqname, _)| seqname == name) {
+            Some(idx) => TwoBitFile::sequence_length(self, idx).map_err(|err| format!("Failed to read sequence '{}' from .2bit file: {}", name, err)),
+            None => Err(format!("Sequence '{}' not found in .2bit file", name)),
+        }
+    }
+
+    fn fetch(&mut self, name: &str) -> ::std::result::Result<Vec<u8>, String> {
+        match self.sequences.iter().position(|&(ref seqname, _)| seqname == name) {
+            Some(idx) => self.sequence(idx).map_err(|err| format!("Failed to read sequence '{}' from .2bit file: {}", name, err)),
+            None => Err(format!("Sequence '{}' not found in .2bit file", name)),
+        }
+    }
+}
+
+fn block_range(bases: &mut Vec<u8>, start: u32, size: u32) -> Result<&mut [u8]> {
+    let (start, end) = (start as usize, start as usize + size as usize);
+    if end > bases.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid .2bit block (beyond the sequence end)"));
+    }
+    Ok(&mut bases[start..end])
+}
+
+/// .2bit file read as FASTA, one sequence is held in memory at a time
+pub struct TwoBitFasta<R: Read + Seek> {
+    file: TwoBitFile<R>,
+    next_idx: usize,
+    buffer: Cursor<Vec<u8>>,
+}
+
+impl<R: Read + Seek> Read for TwoBitFasta<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let count = try!(self.buffer.read(buf));
+            if count > 0 || buf.is_empty() || self.next_idx >= self.file.sequences.len() {
+                return Ok(count);
+            }
+
+            // format next sequence
+            let bases = try!(self.file.sequence(self.next_idx));
+            let mut record = format!(">{}\n", self.file.sequences[self.next_idx].0).into_bytes();
+            for line in bases.chunks(FASTA_LINE_LENGTH) {
+                record.extend_from_slice(line);
+                record.push(b'\n');
+            }
+            self.buffer = Cursor::new(record);
+            self.next_idx += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    // .2bit file with the sequences ACGTNNgtaC and GG, version 1 has 64-bit offsets
+    fn twobit_data(version: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in &[TWOBIT_SIGNATURE, version, 2, 0] {
+            data.write_u32::<LittleEndian>(*value).unwrap();
+        }
+        // index: header (16) + 2 entries (1 + 2 + 4 or 8 bytes each)
+        let offset_size = if version == 1 { 8 } else { 4 };
+        let offset1 = 16 + 2 * (3 + offset_size);
+        let offset2 = offset1 + 4 + (4 + 4 + 4) + (4 + 4 + 4) + 4 + 3;
+        for &(name, offset) in &[("s1", offset1), ("s2", offset2)] {
+            data.push(2);
+            data.extend_from_slice(name.as_bytes());
+            if version == 1 {
+                data.write_u64::<LittleEndian>(offset as u64).unwrap();
+            } else {
+                data.write_u32::<LittleEndian>(offset).unwrap();
+            }
+        }
+        // s1: 10 bases, N block at 4 (2 bp), mask block at 6 (3 bp)
+        for value in &[10, 1, 4, 2, 1, 6, 3, 0] {
+            data.write_u32::<LittleEndian>(*value).unwrap();
+        }
+        // ACGT ACGT AC (T = 0, C = 1, A = 2, G = 3)
+        data.extend_from_slice(&[0b10_01_11_00, 0b10_01_11_00, 0b10_01_00_00]);
+        // s2: 2 bases, no blocks
+        for value in &[2, 0, 0, 0] {
+            data.write_u32::<LittleEndian>(*value).unwrap();
+        }
+        data.push(0b11_11_00_00);
+        data
+    }
+
+    #[test]
+    fn sequences_with_n_and_mask_blocks() {
+        let mut file = TwoBitFile::new(Cursor::new(twobit_data(0))).unwrap();
+        assert_eq!(vec!["s1".to_string(), "s2".to_string()], file.sequence_names());
+        assert_eq!(b"ACGTNNgtaC".to_vec(), file.sequence(0).unwrap());
+        assert_eq!(b"GG".to_vec(), file.sequence(1).unwrap());
+    }
+
+    #[test]
+    fn version_1_offsets() {
+        let mut file = TwoBitFile::new(Cursor::new(twobit_data(1))).unwrap();
+        assert_eq!(b"ACGTNNgtaC".to_vec(), file.sequence(0).unwrap());
+        assert_eq!(b"GG".to_vec(), file.sequence(1).unwrap());
+        assert!(TwoBitFile::new(Cursor::new(twobit_data(2))).is_err());
+    }
+
+    #[test]
+    fn fasta_stream() {
+        let mut text = String::new();
+        TwoBitFile::new(Cursor::new(twobit_data(0))).unwrap().fasta().read_to_string(&mut text).unwrap();
+        assert_eq!(">s1\nACGTNNgtaC\n>s2\nGG\n", text);
+    }
+
+    #[test]
+    fn invalid_signature() {
+        assert!(TwoBitFile::new(Cursor::new(b">s1\nACGT\n".to_vec())).is_err());
+    }
+}
@@ -1,6 +1,15 @@
 extern crate seqoutbiaslib;
+extern crate tempdir;
+extern crate flate2;
 
-use seqoutbiaslib::fasta::reverse_complement;
+use seqoutbiaslib::fasta::{reverse_complement, generate_seqtable, process_fasta, process_fasta_subset, decompress, genome_sequence_names, SoftMask};
+use seqoutbiaslib::seqtable::{SeqTable, SeqTableParams};
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Write};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tempdir::TempDir;
 
 // Missing data is encoded as zero, so it's reverse complement should also be zero
 #[test]
@@ -33,4 +42,117 @@ fn kmer_reverse_complement_kmer_size_4() {
     // TTTT
     let rev_comp = 1 + 4u32.pow( 3 ) * 3 + 4u32.pow( 2 ) * 3 + 4u32.pow( 1 ) * 3 + 3;
     assert_eq!( reverse_complement( kmer, kmer_size), rev_comp );
-}
\ No newline at end of file
+}
+
+// number of positions with a mappable plus strand k-mer, for 2-mers cut between both bases
+fn mappable_plus_count(fasta: &str, softmask: SoftMask) -> usize {
+    let dir = TempDir::new("seqoutbias_fasta").unwrap();
+    let outfile = dir.path().join("softmask.tbl").to_str().unwrap().to_string();
+    let params = SeqTableParams::new(2, 1, 1, 1, &None, false);
+
+    // no tallymer data, all positions are mappable
+    generate_seqtable(Cursor::new(fasta.as_bytes()), Cursor::new(Vec::new()), &params, softmask, &outfile).unwrap();
+
+    let mut table = SeqTable::open(File::open(&outfile).unwrap()).unwrap();
+    assert_eq!(softmask, table.softmask);
+    let length = table.len_by_idx(0).unwrap();
+    let mut reader = table.get_sequence_by_idx(0).unwrap();
+    (0..length).filter(|&pos| reader.get(pos).unwrap().0 > 0).count()
+}
+
+#[test]
+fn softmasked_bases_as_unmappable() {
+    let fasta = ">1\nACGTacgtACGT\n";
+    assert_eq!(11, mappable_plus_count(fasta, SoftMask::Ignore));
+    // 2-mers starting at 3 to 7 include lowercase bases
+    assert_eq!(6, mappable_plus_count(fasta, SoftMask::Unmappable));
+    // plus strand cut-sites (second base) at lowercase bases for 2-mers starting at 3 to 6
+    assert_eq!(7, mappable_plus_count(fasta, SoftMask::CutSite));
+}
+
+// (plus, minus) table values of each sequence
+fn table_values(filename: &str) -> Vec<(String, Vec<(u64, u64)>)> {
+    let mut table = SeqTable::open(File::open(filename).unwrap()).unwrap();
+    let seqinfos = table.sequences();
+    seqinfos.iter().enumerate().map(|(idx, info)| {
+        let mut reader = table.get_sequence_by_idx(idx).unwrap();
+        (info.name.clone(), (0..info.length).map(|pos| reader.get(pos).unwrap()).collect())
+    }).collect()
+}
+
+#[test]
+fn chromosome_subset_matches_full_table() {
+    let dir = TempDir::new("seqoutbias_fasta").unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    File::create(path("genome.fa")).unwrap().write_all(b">chr1\nACGTTGCA\nAC\n>chr2 alt\nGGGCCC\n>chr3\nTTACGATC\n").unwrap();
+    // unmappable positions on chr3 only, tallymer numbers sequences in FASTA order
+    File::create(path("genome.tal")).unwrap().write_all(b"2\t+1\n2\t-4\n").unwrap();
+    let params = SeqTableParams::new(2, 1, 1, 2, &None, false);
+
+    process_fasta(&path("genome.fa"), path("genome.tal").as_ref(), &params, SoftMask::Ignore, &path("full.tbl"));
+    let chroms = vec!["chr3".to_string(), "chr1".to_string()];
+    process_fasta_subset(&path("genome.fa"), path("genome.tal").as_ref(), &params, SoftMask::Ignore, &chroms, &path("subset.tbl"));
+
+    let full = table_values(&path("full.tbl"));
+    let subset = table_values(&path("subset.tbl"));
+    // sequences are kept in FASTA order
+    assert_eq!(vec![full[0].clone(), full[2].clone()], subset);
+
+    // the chr3 mappability information was applied
+    File::create(path("empty.tal")).unwrap();
+    process_fasta(&path("genome.fa"), path("empty.tal").as_ref(), &params, SoftMask::Ignore, &path("mappable.tbl"));
+    assert!(table_values(&path("mappable.tbl"))[2] != subset[1]);
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// generate table from FASTA data, returning the sequence names and lengths
+fn table_sequences(fasta: Vec<u8>) -> io::Result<Vec<(String, u32)>> {
+    let dir = TempDir::new("seqoutbias_fasta").unwrap();
+    let outfile = dir.path().join("input.tbl").to_str().unwrap().to_string();
+    let params = SeqTableParams::new(2, 1, 1, 1, &None, false);
+    try!(generate_seqtable(try!(decompress(Cursor::new(fasta))), Cursor::new(Vec::new()), &params, SoftMask::Ignore, &outfile));
+
+    let table = SeqTable::open(File::open(&outfile).unwrap()).unwrap();
+    Ok(table.sequences().into_iter().map(|info| (info.name, info.length)).collect())
+}
+
+#[test]
+fn multi_member_gzip_fasta() {
+    // BGZF files are a series of gzip members
+    let mut fasta = gzip(b">chr1\nACGTACGT\n");
+    fasta.extend(gzip(b">chr2\nGGCC\n"));
+    assert_eq!(vec![("chr1".to_string(), 8), ("chr2".to_string(), 4)], table_sequences(fasta).unwrap());
+}
+
+#[test]
+fn crlf_and_tab_delimited_headers() {
+    let expected = vec![("chr1".to_string(), 8), ("chr2".to_string(), 4)];
+    assert_eq!(expected, table_sequences(b">chr1\r\nACGT\r\nACGT\r\n>chr2\r\nGGCC\r\n".to_vec()).unwrap());
+    assert_eq!(expected, table_sequences(b">chr1\tprimary assembly\nACGTACGT\n>chr2 alt\nGGCC\n".to_vec()).unwrap());
+}
+
+#[test]
+fn truncated_gzip_fasta_is_an_error() {
+    let fasta = gzip(b">chr1\nACGTACGTACGTACGTACGTACGTACGTACGT\n>chr2\nGGCC\n");
+    assert!(table_sequences(fasta[..fasta.len() - 12].to_vec()).is_err());
+}
+
+#[test]
+fn genome_sequence_names_from_headers_and_index() {
+    let dir = TempDir::new("seqoutbias_fasta").unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    let expected = vec!["chr1".to_string(), "chr2".to_string()];
+
+    File::create(path("genome.fa.gz")).unwrap().write_all(&gzip(b">chr1 primary\r\nACGT\r\n>chr2\talt\r\nGGCC\r\n")).unwrap();
+    assert_eq!(expected, genome_sequence_names(&path("genome.fa.gz")));
+
+    // through the FASTA index
+    File::create(path("genome.fa")).unwrap().write_all(b">chr1\nACGT\n>chr2\nGGCC\n").unwrap();
+    File::create(path("genome.fa.fai")).unwrap().write_all(b"chr1\t4\t6\t4\t5\nchr2\t4\t17\t4\t5\n").unwrap();
+    assert_eq!(expected, genome_sequence_names(&path("genome.fa")));
+}
@@ -0,0 +1,239 @@
+extern crate seqoutbiaslib;
+extern crate rust_htslib;
+extern crate tempdir;
+
+use seqoutbiaslib::seqtable::{SeqTableParams, SequenceInfo, KmerCodec};
+use seqoutbiaslib::fasta;
+use seqoutbiaslib::counts;
+use seqoutbiaslib::counts::GroupCounts;
+use seqoutbiaslib::scale;
+use seqoutbiaslib::scale::PileUp;
+use seqoutbiaslib::source;
+use seqoutbiaslib::source::ReadOptions;
+use seqoutbiaslib::split::ReadSplit;
+use seqoutbiaslib::masksearch;
+use seqoutbiaslib::masksearch::{SiteSet, MaskScorer};
+use seqoutbiaslib::seqtable::SeqTable;
+use rust_htslib::bam;
+use rust_htslib::bam::header::{Header, HeaderRecord};
+use rust_htslib::bam::record::{Record, Cigar, CigarString, Aux};
+use std::ffi::OsStr;
+use std::io::Cursor;
+use std::io::Write;
+use std::fs::File;
+use std::path::PathBuf;
+use tempdir::TempDir;
+
+fn get_resource(folder: &str, filename: &str) -> PathBuf {
+    let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    d.push("tests");
+    d.push("resources");
+    d.push(folder);
+    d.push(filename);
+    return d;
+}
+
+// seqtable for ref2.fa (21 bp), read size 5, 2-mers cut between both bases
+fn create_seqtable(dir: &TempDir) -> String {
+    let outfile = dir.path().join("ref2_5.2.1.1.tbl").to_str().unwrap().to_string();
+    let params = SeqTableParams::new(2, 1, 1, 5, &None, false);
+    let fasta = get_resource("base", "ref2.fa");
+    let tallymer = get_resource("tallymer", "ref2.tal_5.gtTxt.gz");
+
+    fasta::process_fasta(fasta.to_str().unwrap(), OsStr::new(tallymer.to_str().unwrap()), &params, fasta::SoftMask::Ignore, &outfile);
+    outfile
+}
+
+// single-end reads as (start, length, reverse)
+fn create_records(reads: &[(i32, usize, bool)]) -> Vec<Record> {
+    reads.iter().map(|&(start, length, reverse)| {
+        let seq = vec![b'A'; length];
+        let qual = vec![30u8; length];
+        let mut rec = Record::new();
+        rec.set(b"r01", &CigarString(vec![Cigar::Match(length as u32)]), &seq, &qual);
+        rec.set_tid(0);
+        rec.set_pos(start);
+        rec.set_mtid(-1);
+        rec.set_mpos(-1);
+        rec.set_mapq(30);
+        if reverse { rec.set_reverse(); }
+        rec
+    }).collect()
+}
+
+fn create_bam(dir: &TempDir, reads: &[(i32, usize, bool)]) -> String {
+    write_bam(dir, create_records(reads))
+}
+
+fn write_bam(dir: &TempDir, records: Vec<Record>) -> String {
+    let outfile = dir.path().join("reads.bam").to_str().unwrap().to_string();
+    let mut header = Header::new();
+    header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", &"1").push_tag(b"LN", &21));
+
+    let mut writer = bam::Writer::from_path(&outfile, &header).unwrap();
+    for rec in records {
+        writer.write(&rec).unwrap();
+    }
+    outfile
+}
+
+fn pileup(seqtbl: &str, bam: &str, tail_edge: bool) -> (GroupCounts, PileUp) {
+    let bams = vec![bam.to_string()];
+    let mut split = ReadSplit::None;
+    let options = ReadOptions { tail_edge: tail_edge, ..ReadOptions::default() };
+    let counts = counts::tabulate(seqtbl, Some(&bams), None, &options, &mut split);
+    let mut pileups = scale::scale(seqtbl, &counts, &bams, &options, false, &None, true, &mut split);
+    (counts, pileups.remove(0))
+}
+
+#[test]
+fn mixed_length_reverse_reads_share_five_prime_end() {
+    let dir = TempDir::new("seqoutbias_rlen").unwrap();
+    let seqtbl = create_seqtable(&dir);
+    // reverse reads of length 3, 5 and 8 ending at position 14
+    let bam = create_bam(&dir, &[(7, 8, true), (10, 5, true), (12, 3, true)]);
+
+    let (counts, pileup) = pileup(&seqtbl, &bam, false);
+
+    // all reads use the k-mer of the configured 5 bp read ending at 14 (minus strand index 5 in the table)
+    assert_eq!(3, counts.get(0, 5).3);
+    assert_eq!(3, counts.totals(0).3);
+
+    let positions: Vec<(u32, (f64, f64))> = pileup.chrom_iter(0).map(|(&pos, &value)| (pos, value)).collect();
+    assert_eq!(vec![(14, (0f64, 3f64))], positions);
+}
+
+#[test]
+fn mixed_length_reads_tail_edge() {
+    let dir = TempDir::new("seqoutbias_rlen").unwrap();
+    let seqtbl = create_seqtable(&dir);
+    // forward reads of length 3 and 6 ending at 5, reverse reads of length 4 and 8 starting at 10
+    let bam = create_bam(&dir, &[(0, 6, false), (3, 3, false), (10, 4, true), (10, 8, true)]);
+
+    let (counts, pileup) = pileup(&seqtbl, &bam, true);
+
+    assert_eq!(2, counts.get(0, 8).2);
+    assert_eq!(2, counts.get(0, 2).3);
+
+    let positions: Vec<(u32, (f64, f64))> = pileup.chrom_iter(0).map(|(&pos, &value)| (pos, value)).collect();
+    assert_eq!(vec![(5, (2f64, 0f64)), (10, (0f64, 2f64))], positions);
+}
+
+#[test]
+fn in_memory_records() {
+    let dir = TempDir::new("seqoutbias_rlen").unwrap();
+    let seqtbl = create_seqtable(&dir);
+    let reads = [(7, 8, true), (10, 5, true), (12, 3, true)];
+    let options = ReadOptions::default();
+    let mut split = ReadSplit::None;
+
+    let sources = vec![source::record_source(vec![SequenceInfo { name: "1".to_string(), length: 21 }], create_records(&reads).into_iter(), 5, &options)];
+    let counts = counts::tabulate_sources(&seqtbl, Some(sources), None, &options, &mut split);
+    let sources = vec![source::record_source(vec![SequenceInfo { name: "1".to_string(), length: 21 }], create_records(&reads).into_iter(), 5, &options)];
+    let pileups = scale::scale_sources(&seqtbl, &counts, sources, false, &None, true, &options.chroms, &mut split);
+
+    assert_eq!(3, counts.get(0, 5).3);
+    let positions: Vec<(u32, (f64, f64))> = pileups[0].chrom_iter(0).map(|(&pos, &value)| (pos, value)).collect();
+    assert_eq!(vec![(14, (0f64, 3f64))], positions);
+}
+
+#[test]
+fn counts_and_pileups_split_by_tag() {
+    let dir = TempDir::new("seqoutbias_rlen").unwrap();
+    let seqtbl = create_seqtable(&dir);
+    // two whitelisted barcodes, one other barcode and one read without barcode (both pooled)
+    let reads = [(2, 5, false), (4, 5, false), (10, 5, true), (12, 3, true), (7, 8, true)];
+    let barcodes: [Option<&[u8]>; 5] = [Some(b"AAA"), Some(b"AAA"), Some(b"CCC"), Some(b"GGG"), None];
+    let mut records = create_records(&reads);
+    for (rec, barcode) in records.iter_mut().zip(barcodes.iter()) {
+        if let Some(value) = *barcode {
+            rec.push_aux(b"CB", &Aux::String(value));
+        }
+    }
+    let bams = vec![write_bam(&dir, records)];
+
+    let options = ReadOptions::default();
+    let mut split = ReadSplit::by_tag("CB", Some(vec!["AAA".to_string(), "CCC".to_string()]));
+    let counts = counts::tabulate(&seqtbl, Some(&bams), None, &options, &mut split);
+    let pileups = scale::scale(&seqtbl, &counts, &bams, &options, false, &None, true, &mut split);
+
+    // groups: pooled remainder, AAA, CCC; all share the genome counts
+    assert_eq!(3, counts.len());
+    let genome = counts.genome().totals();
+    assert_eq!((genome.0, genome.1, 0, 2), counts.totals(0));
+    assert_eq!((genome.0, genome.1, 2, 0), counts.totals(1));
+    assert_eq!((genome.0, genome.1, 0, 1), counts.totals(2));
+    // reverse reads ending at 14 share the minus strand k-mer
+    assert_eq!(2, counts.get(0, 5).3);
+    assert_eq!(1, counts.get(2, 5).3);
+
+    let positions = |group: usize| pileups[group].chrom_iter(0).map(|(&pos, &value)| (pos, value)).collect::<Vec<(u32, (f64, f64))>>();
+    assert_eq!(3, pileups.len());
+    assert_eq!(vec![(14, (0f64, 2f64))], positions(0));
+    assert_eq!(vec![(2, (1f64, 0f64)), (4, (1f64, 0f64))], positions(1));
+    assert_eq!(vec![(14, (0f64, 1f64))], positions(2));
+}
+
+#[test]
+fn wide_kmer_indexes() {
+    let dir = TempDir::new("seqoutbias_wide").unwrap();
+    let seqtbl = dir.path().join("wide.tbl").to_str().unwrap().to_string();
+    // 20-mers cut in the middle, table indexes need more than 32 bits
+    let params = SeqTableParams::new(20, 10, 10, 5, &None, false);
+    assert!(params.wide_indexes());
+    let sequence = "GATTACAGCGTTAGCCATGACTTGCAGTCCGATAGGCTTACGATCGAT";
+    let fasta_data = format!(">1\n{}\n", sequence);
+    fasta::generate_seqtable(Cursor::new(fasta_data.as_bytes()), Cursor::new(Vec::new()), &params, fasta::SoftMask::Ignore, &seqtbl).unwrap();
+
+    let options = ReadOptions::default();
+    let mut split = ReadSplit::None;
+    let reads = [(15, 5, false), (15, 5, false), (30, 5, true)];
+    let sources = vec![source::record_source(vec![SequenceInfo { name: "1".to_string(), length: sequence.len() as u32 }], create_records(&reads).into_iter(), 5, &options)];
+    let counts = counts::tabulate_sources(&seqtbl, Some(sources), None, &options, &mut split);
+
+    let codec = KmerCodec::new(&params);
+    let observed: Vec<(String, u64, u64)> = counts.iter(0).filter(|&(_, count)| count.2 + count.3 > 0)
+        .map(|(idx, count)| (codec.decode(idx).unwrap(), count.2, count.3)).collect();
+    assert_eq!(vec![("AGTCCGATAGGCTTACGATC".to_string(), 0, 1), ("CAGCGTTAGCCATGACTTGC".to_string(), 2, 0)], observed);
+    assert!(counts.iter(0).any(|(idx, count)| idx > u32::max_value() as u64 && count.2 > 0));
+    assert_eq!((sequence.len() - 19) as u64, counts.totals(0).0);
+}
+
+// bias score of `mask` through a sequence table on disk
+fn table_bias_score(dir: &TempDir, mask: &str, bam: &str, sites: &str, shift: bool) -> f64 {
+    let outfile = dir.path().join("mask.tbl").to_str().unwrap().to_string();
+    let params = SeqTableParams::new(0, 0, 0, 5, &Some(mask.to_string()), false);
+    let fasta = get_resource("base", "ref2.fa");
+    let tallymer = get_resource("tallymer", "ref2.tal_5.gtTxt.gz");
+    fasta::process_fasta(fasta.to_str().unwrap(), OsStr::new(tallymer.to_str().unwrap()), &params, fasta::SoftMask::Ignore, &outfile);
+
+    let bams = vec![bam.to_string()];
+    let options = ReadOptions::default();
+    let mut split = ReadSplit::None;
+    let counts = counts::tabulate(&outfile, Some(&bams), None, &options, &mut split);
+    let pileups = scale::scale(&outfile, &counts, &bams, &options, shift, &None, false, &mut split);
+    let seqinfos = SeqTable::open(File::open(&outfile).unwrap()).unwrap().sequences();
+    let site_sets = vec![SiteSet::from_bed(sites).unwrap()];
+    masksearch::bias_score(&pileups[0], &site_sets, &options.chroms.resolver(&seqinfos))
+}
+
+#[test]
+fn mask_scores_match_sequence_tables() {
+    let dir = TempDir::new("seqoutbias_masksearch").unwrap();
+    let bam = create_bam(&dir, &[(0, 5, false), (3, 5, false), (5, 5, true), (6, 5, false), (6, 5, false), (9, 5, true), (12, 5, true), (14, 5, false)]);
+    let sites = dir.path().join("sites.bed").to_str().unwrap().to_string();
+    File::create(&sites).unwrap().write_all(b"1\t5\t10\ts1\t0\t+\n1\t9\t14\ts2\t0\t-\n").unwrap();
+
+    let fasta = get_resource("base", "ref2.fa");
+    let tallymer = get_resource("tallymer", "ref2.tal_5.gtTxt.gz");
+    let bams = vec![bam.clone()];
+    for &shift in &[false, true] {
+        let site_sets = vec![SiteSet::from_bed(&sites).unwrap()];
+        let scorer = MaskScorer::new(fasta.to_str().unwrap(), OsStr::new(tallymer.to_str().unwrap()), 5, false, fasta::SoftMask::Ignore, &bams, &ReadOptions::default(), shift, &None, site_sets);
+        for mask in &["XXCXN", "NXCXX", "NXCNN", "NNCNN"] {
+            let score = scorer.score_mask(mask);
+            assert!(score > 0f64);
+            assert_eq!(table_bias_score(&dir, mask, &bam, &sites, shift), score);
+        }
+    }
+}